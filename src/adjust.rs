@@ -0,0 +1,111 @@
+use crate::color::{LinearRgb, LinearImage, OkLab};
+use crate::metrics;
+
+// Tone and color adjustments, applied in linear light before the image is dithered. Since dithering preserves the
+// average color, these directly control how busy the result looks: for example, lowering the contrast of an image that
+// spans most of the palette's gamut avoids large areas of harsh, mixed-up colors.
+pub struct Adjustments {
+    // In stops, so that each step of 1 doubles the amount of light
+    pub exposure: f64,
+    pub gamma: f64,
+    // As a power curve around middle gray (18% reflectance), which stays fixed
+    pub contrast: f64,
+    // A multiplier for the chroma in OKLab
+    pub saturation: f64,
+    // Whether to stretch the luminance range so that the darkest and brightest pixels (ignoring a few outliers) go
+    // all the way to black and white
+    pub auto_levels: bool,
+    pub unsharp_amount: f64,
+    pub unsharp_sigma: f64,
+}
+
+// The fraction of pixels at each end ignored by auto-levels, so that a few stray pixels don't stop it from working
+const LEVELS_CLIP: f64 = 0.005;
+const MIDDLE_GRAY: f64 = 0.18;
+
+impl Adjustments {
+    pub fn from_matches(arg_matches: &clap::ArgMatches) -> Adjustments {
+        let number = |name: &str, default: f64| -> f64 {
+            if arg_matches.is_present(name) { arg_matches.value_of_t_or_exit(name) } else { default }
+        };
+        let adjustments = Adjustments {
+            exposure: number("EXPOSURE", 0.0),
+            gamma: number("GAMMA", 1.0),
+            contrast: number("CONTRAST", 1.0),
+            saturation: number("SATURATION", 1.0),
+            auto_levels: arg_matches.is_present("auto-levels"),
+            unsharp_amount: number("UNSHARP", 0.0),
+            unsharp_sigma: number("UNSHARP_SIGMA", 1.0),
+        };
+        if adjustments.gamma <= 0.0 || adjustments.contrast <= 0.0 || adjustments.saturation < 0.0 || adjustments.unsharp_sigma <= 0.0 {
+            panic!("The gamma, contrast and unsharp mask radius must be positive, and the saturation can't be negative!");
+        }
+        adjustments
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.exposure == 0.0 && self.gamma == 1.0 && self.contrast == 1.0 && self.saturation == 1.0 && !self.auto_levels && self.unsharp_amount == 0.0
+    }
+
+    // Adjusts several images together (e.g. the frames of an animation), so that auto-levels treats them all the same
+    // instead of making the brightness flicker.
+    pub fn apply(&self, images: &mut [LinearImage]) {
+        if self.is_identity() {
+            clip(images);
+            return;
+        }
+
+        let (black, white) = if self.auto_levels {
+            let mut luminances: Vec<f64> = images.iter().flat_map(|img| &img.pixels).map(|pixel| pixel.luminance()).collect();
+            luminances.sort_unstable_by(|y1, y2| y1.partial_cmp(y2).unwrap());
+            let percentile = |fraction: f64| luminances[((luminances.len() - 1) as f64 * fraction).round() as usize];
+            let (black, white) = (percentile(LEVELS_CLIP), percentile(1.0 - LEVELS_CLIP));
+            if white > black { (black, white) } else { (0.0, 1.0) }
+        } else {
+            (0.0, 1.0)
+        };
+
+        let exposure = 2.0f64.powf(self.exposure);
+        for img in images.iter_mut() {
+            for pixel in &mut img.pixels {
+                for value in &mut pixel.data {
+                    let leveled = ((*value - black) / (white - black)).max(0.0);
+                    let exposed = leveled * exposure;
+                    let gamma_corrected = exposed.powf(1.0 / self.gamma);
+                    *value = MIDDLE_GRAY * (gamma_corrected / MIDDLE_GRAY).powf(self.contrast);
+                }
+
+                if self.saturation != 1.0 {
+                    let mut oklab = OkLab::from(*pixel);
+                    oklab.data[1] *= self.saturation;
+                    oklab.data[2] *= self.saturation;
+                    *pixel = LinearRgb::from(oklab);
+                }
+            }
+        }
+
+        if self.unsharp_amount != 0.0 {
+            for img in images.iter_mut() {
+                let blurred = metrics::gaussian_blur(&img.pixels, img.width, img.height, self.unsharp_sigma);
+                for (pixel, blurred) in img.pixels.iter_mut().zip(&blurred) {
+                    for (value, blurred) in pixel.data.iter_mut().zip(&blurred.data) {
+                        *value += self.unsharp_amount * (*value - blurred);
+                    }
+                }
+            }
+        }
+
+        clip(images);
+    }
+}
+
+// Clips images to the displayable range. Floating point images can be brighter than white to begin with, and the
+// adjustments can overshoot (like the halos of an unsharp mask); either way, those colors shouldn't become out-of-gamut
+// targets for the dither to chase.
+fn clip(images: &mut [LinearImage]) {
+    for img in images {
+        for pixel in &mut img.pixels {
+            *pixel = pixel.clamp();
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use crate::find_bias;
+use crate::bias;
+use crate::color::{Srgb8, LinearRgb};
+use crate::preview;
+
+// Measurements of a bias pattern, for choosing between them with more than just our eyes. Ordered dithering only keeps
+// the average color right if the bias values are uniform over [0, 1), so the histogram is checked against that. The
+// power spectrum shows how the pattern looks: white noise has the same power at every frequency, while blue noise (what
+// we want) has little power at low frequencies, which are the ones that show up as visible blotches.
+
+const HISTOGRAM_BINS: usize = 64;
+const PLOT_WIDTH: u32 = 512;
+const PLOT_HEIGHT: u32 = 256;
+const PLOT_MARGIN: u32 = 16;
+
+pub fn run(arg_matches: &clap::ArgMatches) {
+    let bias_name = arg_matches.value_of("BIAS").unwrap();
+    let bias_func = &find_bias(bias_name).build(&bias::ScreenGeometry::from_matches(arg_matches));
+    let size: usize = arg_matches.value_of_t_or_exit("SIZE");
+    if !size.is_power_of_two() || size < 2 {
+        panic!("The size must be a power of two, for the FFT!");
+    }
+    let prefix = arg_matches.value_of("OUTPUT").unwrap();
+
+    let values: Vec<f64> = (0..size).flat_map(|y| (0..size).map(move |x| bias_func(x as u32, y as u32))).collect();
+    let count = values.len() as f64;
+
+    // The same rendering as the visualize-bias algorithm
+    let pattern = image::RgbImage::from_fn(size as u32, size as u32, |x, y| {
+        let bias = values[y as usize * size + x as usize];
+        Srgb8::from(LinearRgb { data: [bias, bias, bias] })
+    });
+    pattern.save(format!("{}_pattern.png", prefix)).unwrap();
+
+    println!("Bias {:?}, sampled over {}x{} pixels", bias_name, size, size);
+
+    // Distribution
+    let out_of_range = values.iter().filter(|&&value| !(0.0..1.0).contains(&value)).count();
+    let mean = values.iter().sum::<f64>() / count;
+    let mut sorted = values.clone();
+    sorted.sort_unstable_by(|v1, v2| v1.partial_cmp(v2).unwrap());
+    // The Kolmogorov-Smirnov statistic against the uniform distribution: the largest gap between the fraction of values
+    // below some point and where that point lies
+    let ks = sorted.iter().enumerate().map(|(i, &value)| {
+        (value - i as f64 / count).abs().max(((i + 1) as f64 / count - value).abs())
+    }).fold(0.0, f64::max);
+    let mut histogram = [0usize; HISTOGRAM_BINS];
+    for &value in &values {
+        histogram[((value * HISTOGRAM_BINS as f64) as usize).min(HISTOGRAM_BINS - 1)] += 1;
+    }
+    let expected = count / HISTOGRAM_BINS as f64;
+    let worst_bin = histogram.iter().map(|&bin_count| (bin_count as f64 - expected).abs() / expected).fold(0.0, f64::max);
+    println!("Values outside [0, 1): {}", out_of_range);
+    println!("Mean: {:.5} (should be 0.5)", mean);
+    println!("Kolmogorov-Smirnov distance from uniform: {:.5}", ks);
+    println!("Largest histogram bin deviation: {:.2}% of the expected count", 100.0 * worst_bin);
+    render_histogram(&histogram, expected).save(format!("{}_histogram.png", prefix)).unwrap();
+
+    // Period
+    let period = |offset: (u32, u32)| (1..=size as u32).find(|&step| {
+        (0..size as u32).all(|y| (0..size as u32).all(|x| {
+            bias_func(x + step * offset.0, y + step * offset.1) == values[y as usize * size + x as usize]
+        }))
+    });
+    let describe = |period: Option<u32>| period.map_or(format!("none within {}", size), |period| period.to_string());
+    println!("Horizontal period: {}", describe(period((1, 0))));
+    println!("Vertical period: {}", describe(period((0, 1))));
+
+    // Spectrum, leaving out the mean, which would otherwise dwarf everything else
+    let mut spectrum: Vec<(f64, f64)> = values.iter().map(|&value| (value - mean, 0.0)).collect();
+    fft_2d(&mut spectrum, size);
+    let power: Vec<f64> = spectrum.iter().map(|&(re, im)| (re * re + im * im) / count).collect();
+    let frequency = |index: usize| if index < size / 2 { index as f64 } else { index as f64 - size as f64 };
+
+    let mut radial_sums = vec![0.0; size / 2 + 1];
+    let mut radial_counts = vec![0usize; size / 2 + 1];
+    let (mut low_power, mut low_bins) = (0.0, 0);
+    for v in 0..size {
+        for u in 0..size {
+            if u == 0 && v == 0 {
+                continue;
+            }
+            let radius = frequency(u).hypot(frequency(v));
+            let bin = radius.round() as usize;
+            if bin < radial_sums.len() {
+                radial_sums[bin] += power[v * size + u];
+                radial_counts[bin] += 1;
+            }
+            if radius < size as f64 / 8.0 {
+                low_power += power[v * size + u];
+                low_bins += 1;
+            }
+        }
+    }
+    let radial: Vec<f64> = radial_sums.iter().zip(&radial_counts).map(|(&sum, &bins)| if bins > 0 { sum / bins as f64 } else { 0.0 }).collect();
+    let total_power: f64 = power.iter().sum();
+    if total_power > 0.0 {
+        println!("Power below 1/8 cycles per pixel: {:.2}% (white noise would have {:.2}%)",
+            100.0 * low_power / total_power, 100.0 * low_bins as f64 / (count - 1.0));
+    }
+    render_spectrum(&power, size).save(format!("{}_spectrum.png", prefix)).unwrap();
+    render_radial(&radial[1..]).save(format!("{}_radial.png", prefix)).unwrap();
+}
+
+// An in-place radix-2 FFT of `data[start], data[start + stride], ...`, with `length` (a power of two) elements
+fn fft(data: &mut [(f64, f64)], start: usize, stride: usize, length: usize) {
+    let index = |i: usize| start + i * stride;
+    let mut j = 0;
+    for i in 1..length {
+        let mut bit = length >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j {
+            data.swap(index(i), index(j));
+        }
+    }
+
+    let mut span = 2;
+    while span <= length {
+        let angle = -2.0 * std::f64::consts::PI / span as f64;
+        for block in (0..length).step_by(span) {
+            for k in 0..span / 2 {
+                let (sin, cos) = (angle * k as f64).sin_cos();
+                let (a, b) = (data[index(block + k)], data[index(block + k + span / 2)]);
+                let twiddled = (b.0 * cos - b.1 * sin, b.0 * sin + b.1 * cos);
+                data[index(block + k)] = (a.0 + twiddled.0, a.1 + twiddled.1);
+                data[index(block + k + span / 2)] = (a.0 - twiddled.0, a.1 - twiddled.1);
+            }
+        }
+        span *= 2;
+    }
+}
+
+fn fft_2d(data: &mut [(f64, f64)], size: usize) {
+    for row in 0..size {
+        fft(data, row * size, 1, size);
+    }
+    for column in 0..size {
+        fft(data, column, size, size);
+    }
+}
+
+fn gray(value: f64) -> Srgb8 {
+    let level = (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+    image::Rgb([level, level, level])
+}
+
+// Bars for each bin, with a line marking the count a uniform distribution would give
+fn render_histogram(histogram: &[usize], expected: f64) -> image::RgbImage {
+    let mut img = image::RgbImage::from_pixel(PLOT_WIDTH, PLOT_HEIGHT, gray(1.0));
+    let scale = (PLOT_HEIGHT - 2 * PLOT_MARGIN) as f64 / (2.0 * expected).max(*histogram.iter().max().unwrap() as f64);
+    let edge = |i: usize| PLOT_MARGIN + i as u32 * (PLOT_WIDTH - 2 * PLOT_MARGIN) / histogram.len() as u32;
+    let bottom = PLOT_HEIGHT - PLOT_MARGIN;
+    for (i, &bin_count) in histogram.iter().enumerate() {
+        let height = (bin_count as f64 * scale).round() as u32;
+        preview::fill_rect(&mut img, edge(i), bottom - height, (edge(i + 1) - edge(i)).saturating_sub(1).max(1), height, gray(0.4));
+    }
+    let expected_y = bottom as f64 - expected * scale;
+    preview::draw_line(&mut img, [PLOT_MARGIN as f64, expected_y], [(PLOT_WIDTH - PLOT_MARGIN) as f64, expected_y], image::Rgb([220, 0, 0]));
+    preview::draw_text(&mut img, PLOT_MARGIN, 4, "histogram", 1, gray(0.0));
+    img
+}
+
+// The 2D power spectrum on a log scale relative to the mean power, with zero frequency in the center
+fn render_spectrum(power: &[f64], size: usize) -> image::RgbImage {
+    let mean = (power.iter().sum::<f64>() / power.len() as f64).max(f64::MIN_POSITIVE);
+    let max = power.iter().cloned().fold(0.0, f64::max);
+    let scale = (1.0 + max / mean).ln().max(f64::MIN_POSITIVE);
+    image::RgbImage::from_fn(size as u32, size as u32, |x, y| {
+        let (u, v) = ((x as usize + size / 2) % size, (y as usize + size / 2) % size);
+        gray((1.0 + power[v * size + u] / mean).ln() / scale)
+    })
+}
+
+// The radially averaged power, from the lowest frequency on the left to 1/2 cycle per pixel on the right
+fn render_radial(radial: &[f64]) -> image::RgbImage {
+    let mut img = image::RgbImage::from_pixel(PLOT_WIDTH, PLOT_HEIGHT, gray(1.0));
+    let max = radial.iter().cloned().fold(0.0, f64::max).max(f64::MIN_POSITIVE);
+    let (width, height) = ((PLOT_WIDTH - 2 * PLOT_MARGIN) as f64, (PLOT_HEIGHT - 2 * PLOT_MARGIN) as f64);
+    let point = |i: usize| [
+        PLOT_MARGIN as f64 + width * i as f64 / (radial.len() - 1).max(1) as f64,
+        (PLOT_HEIGHT - PLOT_MARGIN) as f64 - height * radial[i] / max,
+    ];
+    let axis = (PLOT_HEIGHT - PLOT_MARGIN) as f64;
+    preview::draw_line(&mut img, [PLOT_MARGIN as f64, axis], [(PLOT_WIDTH - PLOT_MARGIN) as f64, axis], gray(0.6));
+    for i in 1..radial.len() {
+        preview::draw_line(&mut img, point(i - 1), point(i), gray(0.0));
+    }
+    preview::draw_text(&mut img, PLOT_MARGIN, 4, "radial power spectrum", 1, gray(0.0));
+    img
+}
@@ -0,0 +1,201 @@
+use crate::color::{self, Srgb8, LinearRgb, LinearImage, ColorSpace};
+use crate::profiles;
+use crate::resize;
+
+use image::AnimationDecoder;
+
+use std::path::Path;
+
+// An animation, stored as fully composited frames. The decoders in `image` apply each source frame's offset, blending
+// and disposal for us, so every frame here is exactly what should be on screen at that point. When writing, every frame
+// covers the whole canvas and the previous one is cleared first, which reproduces the same sequence of images no matter
+// what disposal the source used.
+pub struct Animation {
+    pub width: u32,
+    pub height: u32,
+    pub frames: Vec<image::RgbImage>,
+    // Which pixels of each frame are (mostly) transparent. These are left transparent in the output.
+    pub transparent: Vec<Vec<bool>>,
+    // How long each frame is shown for, in milliseconds
+    pub delays: Vec<u32>,
+    // The color space the decoded frames are in
+    pub space: ColorSpace,
+}
+
+// The colors of a frame's non-transparent pixels, for building a palette from
+pub fn opaque_pixels<'a>(frame: &'a LinearImage, transparent: &'a [bool]) -> impl Iterator<Item=LinearRgb> + 'a {
+    frame.pixels.iter().zip(transparent).filter(|&(_, &is_transparent)| !is_transparent).map(|(&pixel, _)| pixel)
+}
+
+impl Animation {
+    // Loads an animated GIF or APNG. Returns None if the file is some other format or only has a single frame, in which
+    // case it should be treated as an ordinary image.
+    pub fn open(path: &Path) -> Option<Animation> {
+        let format = image::ImageFormat::from_path(path).ok()?;
+        Animation::decode(&std::fs::read(path).unwrap(), format)
+    }
+
+    // The same, for a file that has already been read into memory
+    pub fn decode(data: &[u8], format: image::ImageFormat) -> Option<Animation> {
+        let mut space = ColorSpace::SRGB;
+        let frames = match format {
+            image::ImageFormat::Gif => image::codecs::gif::GifDecoder::new(data).unwrap().into_frames().collect_frames().unwrap(),
+            image::ImageFormat::Png => {
+                let decoder = image::codecs::png::PngDecoder::new(data).unwrap();
+                if !decoder.is_apng() {
+                    return None;
+                }
+                space = profiles::detect_png(data);
+                decoder.apng().into_frames().collect_frames().unwrap()
+            },
+            _ => return None,
+        };
+        if frames.len() < 2 {
+            return None;
+        }
+
+        let (width, height) = frames[0].buffer().dimensions();
+        Some(Animation {
+            width,
+            height,
+            transparent: frames.iter().map(|frame| frame.buffer().pixels().map(|pixel| pixel.0[3] < 128).collect()).collect(),
+            delays: frames.iter().map(|frame| {
+                let (numerator, denominator) = frame.delay().numer_denom_ms();
+                (numerator as f64 / denominator as f64).round() as u32
+            }).collect(),
+            frames: frames.into_iter().map(|frame| image::DynamicImage::ImageRgba8(frame.into_buffer()).into_rgb8()).collect(),
+            space,
+        })
+    }
+
+    // The frames in linear light, which is what gets resized, adjusted and dithered
+    pub fn sources(&self) -> Vec<LinearImage> {
+        self.frames.iter().map(|frame| LinearImage::from_dynamic(image::DynamicImage::ImageRgb8(frame.clone()), &self.space)).collect()
+    }
+
+    // The same, keeping colors outside the working space's gamut for the adjustments to bring back into range
+    pub fn unclipped_sources(&self) -> Vec<LinearImage> {
+        self.frames.iter().map(|frame| LinearImage::from_dynamic_unclipped(image::DynamicImage::ImageRgb8(frame.clone()), &self.space)).collect()
+    }
+
+    // Changes the size of the animation to match sources that have been resized. The frames themselves are replaced
+    // once the sources are dithered, so only the transparency needs resizing here.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        for transparent in &mut self.transparent {
+            *transparent = resize::resize_mask(transparent, self.width, self.height, width, height);
+        }
+        self.width = width;
+        self.height = height;
+    }
+
+    pub fn upscale(&mut self, factor: u32) {
+        for frame in &mut self.frames {
+            *frame = resize::upscale(frame, factor);
+        }
+        for transparent in &mut self.transparent {
+            *transparent = resize::upscale_mask(transparent, self.width, self.height, factor);
+        }
+        self.width *= factor;
+        self.height *= factor;
+    }
+
+    // Writes the animation as a GIF or APNG, depending on the file extension. Since GIF frames can only hold 256 colors
+    // (one of which is needed for transparency), larger palettes only work as long as each frame uses few enough colors.
+    // Otherwise, this returns why the animation couldn't be written.
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        // Encoded in memory first, so that nothing is left behind if the animation can't be written
+        let mut data = Vec::new();
+        self.write(&mut data, image::ImageFormat::from_path(path).ok())?;
+        std::fs::write(path, data).unwrap();
+        Ok(())
+    }
+
+    pub fn write(&self, writer: impl std::io::Write, format: Option<image::ImageFormat>) -> Result<(), String> {
+        match format {
+            Some(image::ImageFormat::Gif) => self.save_gif(writer),
+            Some(image::ImageFormat::Png) => {
+                self.save_apng(writer);
+                Ok(())
+            },
+            _ => Err("Animations can only be written as GIF or PNG files!".to_string()),
+        }
+    }
+
+    fn save_gif(&self, file: impl std::io::Write) -> Result<(), String> {
+        if color::working_space() != ColorSpace::SRGB {
+            eprintln!("Warning: GIF files can't record the color space, so viewers will assume the output is sRGB");
+        }
+        let mut encoder = gif::Encoder::new(file, self.width as u16, self.height as u16, &[]).unwrap();
+        encoder.set_repeat(gif::Repeat::Infinite).unwrap();
+
+        for ((frame, transparent), &delay) in self.frames.iter().zip(&self.transparent).zip(&self.delays) {
+            let mut colors: Vec<Srgb8> = Vec::new();
+            let mut color_indices = std::collections::HashMap::new();
+            let mut indices = Vec::with_capacity(frame.len() / 3);
+            let has_transparency = transparent.contains(&true);
+            for (&pixel, &is_transparent) in frame.pixels().zip(transparent) {
+                if is_transparent {
+                    indices.push(0);
+                } else {
+                    let index = *color_indices.entry(pixel).or_insert_with(|| {
+                        colors.push(pixel);
+                        colors.len() - 1
+                    });
+                    // Reserve index 0 for transparency
+                    indices.push((index + has_transparency as usize) as u8);
+                }
+            }
+            if colors.len() + has_transparency as usize > 256 {
+                return Err(format!(
+                    "A frame uses {} colors{}, but GIF frames can only hold 256! Use fewer colors, or write an animated PNG instead.",
+                    colors.len(), if has_transparency { " plus transparency" } else { "" },
+                ));
+            }
+
+            let mut palette = if has_transparency { vec![0, 0, 0] } else { Vec::new() };
+            palette.extend(colors.iter().flat_map(|color| color.0));
+
+            encoder.write_frame(&gif::Frame {
+                width: self.width as u16,
+                height: self.height as u16,
+                delay: ((delay + 5) / 10) as u16,
+                dispose: gif::DisposalMethod::Background,
+                transparent: if has_transparency { Some(0) } else { None },
+                palette: Some(palette),
+                buffer: std::borrow::Cow::Owned(indices),
+                ..gif::Frame::default()
+            }).unwrap();
+        }
+        Ok(())
+    }
+
+    fn save_apng(&self, file: impl std::io::Write) {
+        let has_transparency = self.transparent.iter().any(|transparent| transparent.contains(&true));
+
+        let mut encoder = png::Encoder::new(file, self.width, self.height);
+        encoder.set_color(if has_transparency { png::ColorType::Rgba } else { png::ColorType::Rgb });
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_animated(self.frames.len() as u32, 0).unwrap();
+        encoder.set_dispose_op(png::DisposeOp::Background).unwrap();
+        let space = color::working_space();
+        if space != ColorSpace::SRGB {
+            profiles::tag_png_header(&mut encoder, &space);
+        }
+        let mut writer = encoder.write_header().unwrap();
+        if space != ColorSpace::SRGB {
+            profiles::write_cicp(&mut writer, &space);
+        }
+
+        for ((frame, transparent), &delay) in self.frames.iter().zip(&self.transparent).zip(&self.delays) {
+            writer.set_frame_delay(delay.min(u16::MAX as u32) as u16, 1000).unwrap();
+            if has_transparency {
+                let data: Vec<u8> = frame.pixels().zip(transparent).flat_map(|(pixel, &is_transparent)| {
+                    [pixel.0[0], pixel.0[1], pixel.0[2], if is_transparent { 0 } else { 255 }]
+                }).collect();
+                writer.write_image_data(&data).unwrap();
+            } else {
+                writer.write_image_data(frame).unwrap();
+            }
+        }
+    }
+}
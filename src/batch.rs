@@ -0,0 +1,261 @@
+use crate::{PaletteChoice, Settings};
+use crate::animation::{self, Animation};
+use crate::color::{Srgb8, LinearRgb, LinearImage, Lab};
+use crate::files;
+use crate::palettes;
+
+use rayon::iter::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
+
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+// Expands the glob patterns given on the command line into a list of files. Anything that names an existing file is
+// taken literally, so that file names containing brackets and the like don't need escaping.
+fn expand_inputs<'a>(patterns: impl Iterator<Item=&'a str>) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for pattern in patterns {
+        if Path::new(pattern).is_file() {
+            paths.push(PathBuf::from(pattern));
+            continue;
+        }
+
+        let matches: Vec<PathBuf> = match glob::glob(pattern) {
+            Ok(matches) => matches.filter_map(Result::ok).filter(|path| path.is_file()).collect(),
+            Err(err) => panic!("Invalid pattern {:?}: {}", pattern, err),
+        };
+        if matches.is_empty() {
+            panic!("No files match {:?}!", pattern);
+        }
+        paths.extend(matches);
+    }
+    paths
+}
+
+// A single input file, which may be animated. Still images are kept in linear light, at full precision, until they are
+// dithered.
+enum Input {
+    Still(LinearImage),
+    Animated(Animation),
+}
+
+// The same file after dithering
+enum Output {
+    Still(image::RgbImage),
+    Animated(Animation),
+}
+
+impl Input {
+    fn open(path: &Path) -> Input {
+        match Animation::open(path) {
+            Some(animation) => Input::Animated(animation),
+            None => Input::Still(files::InputFile::read(path.as_os_str()).image()),
+        }
+    }
+
+    // The colors of every non-transparent pixel, keeping only every `subsample`th one
+    fn pixels(&self, subsample: usize) -> Vec<LinearRgb> {
+        match *self {
+            Input::Still(ref img) => img.pixels.iter().cloned().step_by(subsample).collect(),
+            Input::Animated(ref animation) => {
+                animation.sources().iter().zip(&animation.transparent)
+                    .flat_map(|(source, transparent)| animation::opaque_pixels(source, transparent).collect::<Vec<_>>())
+                    .step_by(subsample)
+                    .collect()
+            },
+        }
+    }
+
+    fn dither(self, palette: &[Srgb8], settings: &Settings) -> Output {
+        let linear_palette: Vec<_> = palette.iter().cloned().map(LinearRgb::from).collect();
+        let lab_palette: Vec<_> = linear_palette.iter().cloned().map(Lab::from).collect();
+        let palette_output = settings.palette_output(palette);
+        let dither = |source: &LinearImage| {
+            let img = settings.dither(source, palette, &linear_palette, &lab_palette);
+            palette_output.apply(&img, palette)
+        };
+        match self {
+            Input::Still(img) => Output::Still(dither(&img)),
+            Input::Animated(mut animation) => {
+                animation.frames = animation.sources().iter().map(dither).collect();
+                Output::Animated(animation)
+            },
+        }
+    }
+}
+
+impl Output {
+    fn save(&self, path: &Path) -> Result<(), String> {
+        match *self {
+            Output::Still(ref img) => {
+                files::save_image(img, path.as_os_str(), None);
+                Ok(())
+            },
+            Output::Animated(ref animation) => animation.save(path),
+        }
+    }
+
+    // How many distinct colors the image uses
+    fn colors_used(&self) -> usize {
+        let frames = match *self {
+            Output::Still(ref img) => std::slice::from_ref(img),
+            Output::Animated(ref animation) => &animation.frames[..],
+        };
+        frames.iter().flat_map(|frame| frame.pixels()).collect::<HashSet<_>>().len()
+    }
+}
+
+// Works out where to write the result for an input by filling in the --name template. Placeholders are `{stem}` (the
+// input's file name without its extension), `{ext}` (`gif` for GIFs and `png` for everything else), and the values of
+// the `{algorithm}`, `{palette}`, `{bias}`, `{distance}` and `{colors}` options. A palette file stands in as its file
+// name without the extension. Results go next to their inputs unless an output directory is given.
+fn output_path(path: &Path, template: &str, out_dir: Option<&Path>, arg_matches: &clap::ArgMatches) -> PathBuf {
+    // Values are pasted into a single file name, so they mustn't lead into other directories (or, on Windows, drives)
+    let value = |value: &std::ffi::OsStr| value.to_string_lossy().replace(|c: char| std::path::is_separator(c) || c == ':', "_");
+    let option = |id: &str| value(arg_matches.value_of_os(id).unwrap());
+    let palette = arg_matches.value_of("PALETTE").unwrap();
+    let palette_name = if palettes::lookup(palette).is_none() && Path::new(palette).is_file() {
+        value(Path::new(palette).file_stem().unwrap())
+    } else {
+        value(palette.as_ref())
+    };
+
+    let mut name = std::ffi::OsString::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        name.push(&rest[..start]);
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => panic!("Unclosed placeholder in output name template {:?}!", template),
+        };
+        match &rest[start + 1..end] {
+            "stem" => name.push(value(path.file_stem().unwrap())),
+            "ext" => name.push(if image::ImageFormat::from_path(path).ok() == Some(image::ImageFormat::Gif) { "gif" } else { "png" }),
+            "algorithm" => name.push(option("ALGORITHM")),
+            "palette" => name.push(&palette_name),
+            "bias" => name.push(option("BIAS")),
+            "distance" => name.push(option("DISTANCE")),
+            "colors" => name.push(option("PALETTE_SIZE")),
+            placeholder => panic!("Unknown placeholder {{{}}} in output name template! The options are {{stem}}, {{ext}}, {{algorithm}}, {{palette}}, {{bias}}, {{distance}} and {{colors}}.", placeholder),
+        }
+        rest = &rest[end + 1..];
+    }
+    name.push(rest);
+
+    match out_dir {
+        Some(out_dir) => out_dir.join(name),
+        None => path.with_file_name(name),
+    }
+}
+
+// Whether an output was written after its input last changed, so there's no need to make it again
+fn up_to_date(path: &Path, out_path: &Path) -> bool {
+    let modified = |path: &Path| std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok();
+    match (modified(path), modified(out_path)) {
+        (Some(input_time), Some(output_time)) => output_time > input_time,
+        _ => false,
+    }
+}
+
+// Dithers many files with the same settings, in parallel. With --shared-palette, a procedural palette is built once
+// from the pixels of every input together (which keeps sprite sheets, tile sets and the like consistent with each
+// other) and written out alongside the results. Otherwise, every file gets its own palette.
+//
+// Outputs that are newer than their inputs are skipped, unless --force is given. Since a shared palette depends on
+// every input, changing any of them means redoing the whole batch.
+pub fn run(arg_matches: &clap::ArgMatches) {
+    let settings = Settings::from_matches(arg_matches);
+    let inputs = expand_inputs(arg_matches.values_of("INPUTS").unwrap());
+    let subsample: usize = arg_matches.value_of_t_or_exit("SUBSAMPLE");
+    if subsample == 0 {
+        panic!("The subsampling rate must be at least 1!");
+    }
+    let procedural = matches!(settings.palette, PaletteChoice::Procedural(_) | PaletteChoice::Bounded(_));
+    let shared = arg_matches.is_present("shared-palette");
+
+    let out_dir = arg_matches.value_of_os("OUT_DIR").map(Path::new);
+    if let Some(out_dir) = out_dir {
+        std::fs::create_dir_all(out_dir).unwrap();
+    }
+    let template = arg_matches.value_of("NAME").unwrap();
+    let out_paths: Vec<PathBuf> = inputs.iter().map(|path| output_path(path, template, out_dir, arg_matches)).collect();
+    let input_set: HashSet<&PathBuf> = inputs.iter().collect();
+    let mut written_by: HashMap<&PathBuf, &PathBuf> = HashMap::new();
+    for (path, out_path) in inputs.iter().zip(&out_paths) {
+        if input_set.contains(out_path) {
+            panic!("The output for {} would overwrite an input!", path.display());
+        }
+        if let Some(other) = written_by.insert(out_path, path) {
+            panic!("{} and {} would both be written to {}! Try adding {{stem}} to the --name template or using a different --out-dir.",
+                   other.display(), path.display(), out_path.display());
+        }
+    }
+
+    let mut stale: Vec<bool> = inputs.iter().zip(&out_paths).map(|(path, out_path)| {
+        arg_matches.is_present("force") || !up_to_date(path, out_path)
+    }).collect();
+    if shared && stale.contains(&true) {
+        stale = vec![true; inputs.len()];
+    }
+
+    let shared_palette = if shared && stale.contains(&true) {
+        // Loading every file twice is slower than keeping them all around, but large batches would not fit in memory
+        let pixels: Vec<LinearRgb> = if procedural {
+            inputs.par_iter().flat_map_iter(|path| {
+                eprintln!("Reading {}", path.display());
+                Input::open(path).pixels(subsample)
+            }).collect()
+        } else {
+            Vec::new()
+        };
+        let palette = settings.build_palette(&pixels);
+
+        let palette_file_name = arg_matches.value_of_os("PALETTE_OUTPUT").unwrap_or_else(|| "palette.hex".as_ref());
+        std::fs::write(palette_file_name, palettes::to_hex(&palette)).unwrap();
+        eprintln!("Generated a shared palette of {} colors from {} files", palette.len(), inputs.len());
+        Some(palette)
+    } else {
+        None
+    };
+
+    let results: Vec<_> = inputs.par_iter().zip(&out_paths).zip(&stale).map(|((path, out_path), &stale)| {
+        if !stale {
+            return None;
+        }
+
+        let start = std::time::Instant::now();
+        let input = Input::open(path);
+        let palette = match shared_palette {
+            Some(ref palette) => palette.clone(),
+            None => {
+                let pixels = if procedural { input.pixels(subsample) } else { Vec::new() };
+                settings.build_palette(&pixels)
+            },
+        };
+
+        eprintln!("Dithering {}", path.display());
+        let output = input.dither(&palette, &settings);
+        // A file that can't be written is reported with the rest, rather than stopping the whole batch
+        Some(output.save(out_path).map(|()| (output.colors_used(), palette.len(), start.elapsed().as_secs_f64())))
+    }).collect();
+
+    let width = inputs.iter().map(|path| path.display().to_string().len()).max().unwrap_or(0);
+    let mut dithered = 0;
+    let mut failed = 0;
+    for ((path, out_path), result) in inputs.iter().zip(&out_paths).zip(&results) {
+        match *result {
+            Some(Ok((used, palette_size, seconds))) => {
+                dithered += 1;
+                println!("{:width$}  -> {}  ({} of {} colors, {:.2}s)", path.display(), out_path.display(), used, palette_size, seconds, width = width);
+            },
+            Some(Err(ref message)) => {
+                failed += 1;
+                println!("{:width$}  -> {}  (failed: {})", path.display(), out_path.display(), message, width = width);
+            },
+            None => println!("{:width$}  -> {}  (up to date, skipped)", path.display(), out_path.display(), width = width),
+        }
+    }
+    println!("Dithered {} of {} files", dithered, inputs.len());
+    if failed > 0 {
+        panic!("{} of {} files couldn't be written!", failed, inputs.len());
+    }
+}
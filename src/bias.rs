@@ -0,0 +1,203 @@
+use rand::Rng as _;
+
+// A bias pattern assigns each pixel a value in [0, 1) that ordered dithering algorithms use to choose between the
+// candidate palette colors for that pixel. For the result to be accurate on average, the values should be uniformly
+// distributed; the different patterns just arrange those values differently in space.
+pub struct NamedBias {
+    pub names: &'static [&'static str],
+    pub description: &'static str,
+    pub source: BiasSource,
+}
+
+pub enum BiasSource {
+    // A fixed pattern
+    Pattern(fn(u32, u32) -> f64),
+    // A halftone screen built from the given spot function, at whatever angle and frequency were asked for
+    Screen(fn(f64, f64) -> f64),
+}
+
+pub type BiasFunc = Box<dyn Fn(u32, u32) -> f64 + Send + Sync>;
+
+impl NamedBias {
+    pub fn build(&self, geometry: &ScreenGeometry) -> BiasFunc {
+        match self.source {
+            BiasSource::Pattern(func) => Box::new(func),
+            BiasSource::Screen(spot) => {
+                let screen = Screen::new(spot, geometry);
+                Box::new(move |x, y| screen.bias(x, y))
+            },
+        }
+    }
+}
+
+pub const BIASES: &[NamedBias] = &[
+    NamedBias { names: &["interleavedgradient"], description: "Jorge Jimenez's interleaved gradient noise", source: BiasSource::Pattern(interleaved_gradient) },
+    NamedBias { names: &["plastic"], description: "Quasirandom sequence based on the plastic number", source: BiasSource::Pattern(plastic) },
+    NamedBias { names: &["plastic+triangle"], description: "The plastic sequence composed with a triangle wave, for an even fabric-like pattern", source: BiasSource::Pattern(plastic_triangle) },
+    NamedBias { names: &["bayer2"], description: "2x2 Bayer matrix", source: BiasSource::Pattern(|x, y| bayer(1, x, y)) },
+    NamedBias { names: &["bayer4"], description: "4x4 Bayer matrix", source: BiasSource::Pattern(|x, y| bayer(2, x, y)) },
+    NamedBias { names: &["bayer8"], description: "8x8 Bayer matrix", source: BiasSource::Pattern(|x, y| bayer(3, x, y)) },
+    NamedBias { names: &["bayer16"], description: "16x16 Bayer matrix", source: BiasSource::Pattern(|x, y| bayer(4, x, y)) },
+    NamedBias { names: &["bayer256"], description: "256x256 Bayer matrix, for an old-school look", source: BiasSource::Pattern(|x, y| bayer(8, x, y)) },
+    NamedBias { names: &["block8"], description: "8x8 blocks filled in row-major order (for debugging)", source: BiasSource::Pattern(block8) },
+    NamedBias { names: &["zblock8"], description: "8x8 blocks filled in Z-order (for debugging)", source: BiasSource::Pattern(zblock8) },
+    NamedBias { names: &["dot8"], description: "8x8 clustered dot, approximating halftoning", source: BiasSource::Pattern(dot8) },
+    NamedBias { names: &["round-screen"], description: "Halftone screen of round dots", source: BiasSource::Screen(round_spot) },
+    NamedBias { names: &["elliptical-screen"], description: "Halftone screen of elliptical dots, which join up into chains in the midtones", source: BiasSource::Screen(elliptical_spot) },
+    NamedBias { names: &["line-screen"], description: "Halftone screen of parallel lines", source: BiasSource::Screen(line_spot) },
+    NamedBias { names: &["square-screen"], description: "Halftone screen of square dots", source: BiasSource::Screen(square_spot) },
+    NamedBias { names: &["random"], description: "Actual pseudorandomness, for a mottled look", source: BiasSource::Pattern(random) },
+];
+
+pub fn lookup(name: &str) -> Option<&'static NamedBias> {
+    BIASES.iter().find(|bias| bias.names.contains(&name))
+}
+
+pub fn spread(x: u8) -> u16 {
+    let mut ret = x as u16;
+    ret = (ret ^ (ret << 4)) & 0x0f0f;
+    ret = (ret ^ (ret << 2)) & 0x3333;
+    ret = (ret ^ (ret << 1)) & 0x5555;
+    ret
+}
+
+pub fn bayer(bits: u32, x: u32, y: u32) -> f64 {
+    let size = 1 << bits;
+    let grid_x = spread((x%size) as u8);
+    let grid_y = spread((y%size) as u8);
+    let mixed = (grid_x ^ (grid_x << 1) ^ grid_y).reverse_bits() >> (16 - 2*bits);
+    (0.5 + mixed as f64) / (size*size) as f64
+}
+
+fn interleaved_gradient(x: u32, y: u32) -> f64 {
+    (52.9829189 * (0.06711056 * x as f64 + 0.00583715 * y as f64).fract()).fract()
+}
+
+fn plastic(x: u32, y: u32) -> f64 {
+    let plastic = 1.32471795724474602596;
+    (x as f64 / plastic + y as f64 / plastic.powi(2)).fract()
+}
+
+fn plastic_triangle(x: u32, y: u32) -> f64 {
+    // As suggested in The Unreasonable Effectiveness of Quasirandom Sequences (Martin Roberts),
+    // using a simple linear function based on the plastic number, composed with a triangle wave,
+    // gives good results for bias.
+    let r_bias = plastic(x, y);
+    if r_bias < 0.5 {
+        2.0 * r_bias
+    } else {
+        2.0 - 2.0 * r_bias
+    }
+}
+
+// Mostly useful for debugging
+fn block8(x: u32, y: u32) -> f64 {
+    let mixed = (x%8) + 8 * (y%8);
+    (0.5 + mixed as f64) / 64.0
+}
+
+fn zblock8(x: u32, y: u32) -> f64 {
+    let mixed = spread((x%8) as u8) | (spread((y%8) as u8) << 1);
+    (0.5 + mixed as f64) / 64.0
+}
+
+// And for fun
+fn dot8(x: u32, y: u32) -> f64 {
+    let arr = [[60, 52, 51, 37, 36, 50, 59, 63],
+               [53, 43, 28, 19, 18, 27, 42, 58],
+               [44, 29, 13,  5,  4, 12, 26, 49],
+               [38, 20,  6,  0,  3, 11, 17, 35],
+               [39, 21,  7,  1,  2, 10, 16, 34],
+               [45, 30, 14,  8,  9, 15, 25, 48],
+               [54, 40, 31, 22, 23, 24, 41, 57],
+               [61, 55, 46, 32, 33, 47, 56, 62]];
+    let mixed: u8 = arr[(y%8) as usize][(x%8) as usize];
+    (0.5 + mixed as f64) / 64.0
+}
+
+fn random(_: u32, _: u32) -> f64 {
+    rand::thread_rng().gen::<f64>()
+}
+
+//////// Halftone screens ////////
+
+// The angle and spacing of a halftone screen, given the way printers do: as lines (rows of dots) per inch at a certain
+// output resolution
+pub struct ScreenGeometry {
+    pub angle: f64,
+    pub frequency: f64,
+    pub dpi: f64,
+}
+
+impl ScreenGeometry {
+    pub fn from_matches(arg_matches: &clap::ArgMatches) -> ScreenGeometry {
+        let geometry = ScreenGeometry {
+            angle: arg_matches.value_of_t_or_exit("SCREEN_ANGLE"),
+            frequency: arg_matches.value_of_t_or_exit("SCREEN_FREQUENCY"),
+            dpi: arg_matches.value_of_t_or_exit("DPI"),
+        };
+        if !(geometry.frequency > 0.0 && geometry.dpi >= geometry.frequency) {
+            panic!("The screen frequency must be positive, and no more than the resolution!");
+        }
+        geometry
+    }
+
+    // The side length of each cell of the screen, in pixels
+    pub fn cell_size(&self) -> f64 {
+        self.dpi / self.frequency
+    }
+}
+
+// How finely to sample a spot function to find how its values are distributed
+const SPOT_SAMPLES: usize = 128;
+
+// A spot function gives each point of a cell (with coordinates from -1 to 1) a value, and as the tone darkens, the dot
+// grows to cover the points with the highest values first. These values are far from uniform, so the bias is instead
+// the fraction of the cell whose values are higher: the area the dot covers by the time it reaches that point. This keeps
+// the average color right, whatever the shape of the dot.
+struct Screen {
+    spot: fn(f64, f64) -> f64,
+    cos: f64,
+    sin: f64,
+    cell_size: f64,
+    // The spot function's values over a fine grid covering one cell, in increasing order
+    levels: Vec<f64>,
+}
+
+impl Screen {
+    fn new(spot: fn(f64, f64) -> f64, geometry: &ScreenGeometry) -> Screen {
+        let coordinate = |i: usize| (i as f64 + 0.5) / SPOT_SAMPLES as f64 * 2.0 - 1.0;
+        let mut levels: Vec<f64> = (0..SPOT_SAMPLES).flat_map(|i| (0..SPOT_SAMPLES).map(move |j| spot(coordinate(i), coordinate(j)))).collect();
+        levels.sort_unstable_by(|l1, l2| l1.partial_cmp(l2).unwrap());
+        let (sin, cos) = geometry.angle.to_radians().sin_cos();
+        Screen { spot, cos, sin, cell_size: geometry.cell_size(), levels }
+    }
+
+    fn bias(&self, x: u32, y: u32) -> f64 {
+        let (px, py) = (x as f64 + 0.5, y as f64 + 0.5);
+        let u = (self.cos * px + self.sin * py) / self.cell_size;
+        let v = (self.cos * py - self.sin * px) / self.cell_size;
+        let value = (self.spot)(u.rem_euclid(1.0) * 2.0 - 1.0, v.rem_euclid(1.0) * 2.0 - 1.0);
+        // Ties (as on the lines of a line screen) share the middle of the range of ranks they cover
+        let below = self.levels.partition_point(|&level| level < value);
+        let not_above = self.levels.partition_point(|&level| level <= value);
+        let count = self.levels.len() as f64;
+        (0.5 + count - (below + not_above) as f64 / 2.0) / (count + 1.0)
+    }
+}
+
+fn round_spot(x: f64, y: f64) -> f64 {
+    1.0 - (x * x + y * y)
+}
+
+fn elliptical_spot(x: f64, y: f64) -> f64 {
+    1.0 - (x * x + (y / 0.7).powi(2))
+}
+
+fn line_spot(_x: f64, y: f64) -> f64 {
+    1.0 - y.abs()
+}
+
+fn square_spot(x: f64, y: f64) -> f64 {
+    1.0 - x.abs().max(y.abs())
+}
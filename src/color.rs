@@ -1,7 +1,9 @@
-use crate::geom::Affine3;
+use crate::geom::{self, Affine3, Matrix3};
 
 use image::Pixel;
 
+use std::sync::OnceLock;
+
 //////// Color spaces ////////
 
 pub type Srgb8 = image::Rgb<u8>;
@@ -27,6 +29,11 @@ impl LinearRgb {
         }
         self
     }
+
+    // The relative luminance, Y in CIEXYZ
+    pub fn luminance(self) -> f64 {
+        Xyz::from(self).data[1]
+    }
 }
 
 // CIEXYZ: we only use this as an intermediate conversion step
@@ -58,10 +65,236 @@ pub struct Lab {
     c: f64 // For optimized color comparisons, we cache this: c = sqrt(a^2 + b^2)
 }
 
+impl Lab {
+    pub fn chroma(self) -> f64 {
+        self.c
+    }
+
+    // The hue angle in radians, in (-pi, pi]
+    pub fn hue(self) -> f64 {
+        self.b.atan2(self.a)
+    }
+}
+
+// OKLab (https://bottosson.github.io/posts/oklab/), which unlike CIELAB is easy to convert back to RGB, and keeps hues
+// steadier when changing saturation
+#[derive(Copy, Clone)]
+pub struct OkLab {
+    pub data: [f64; 3]
+}
+
+//////// White points and primaries ////////
+
+// A white point, as a CIE 1931 xy chromaticity
+#[derive(Copy, Clone, PartialEq)]
+pub struct WhitePoint {
+    pub x: f64,
+    pub y: f64,
+}
+
+impl WhitePoint {
+    pub const D65: WhitePoint = WhitePoint { x: 0.3127, y: 0.3290 };
+    pub const D50: WhitePoint = WhitePoint { x: 0.3457, y: 0.3585 };
+
+    // The white's tristimulus values, scaled so that Y is 1
+    pub fn to_xyz(self) -> [f64; 3] {
+        [self.x / self.y, 1.0, (1.0 - self.x - self.y) / self.y]
+    }
+}
+
+pub struct NamedWhitePoint {
+    pub names: &'static [&'static str],
+    pub description: &'static str,
+    pub white_point: WhitePoint,
+}
+
+pub const WHITE_POINTS: &[NamedWhitePoint] = &[
+    NamedWhitePoint { names: &["d65"], description: "Average daylight, the white of sRGB and most screens", white_point: WhitePoint::D65 },
+    NamedWhitePoint { names: &["d50"], description: "Horizon light, the standard for print and ICC profiles", white_point: WhitePoint::D50 },
+    NamedWhitePoint { names: &["d55"], description: "Mid-morning daylight", white_point: WhitePoint { x: 0.3324, y: 0.3474 } },
+    NamedWhitePoint { names: &["d75"], description: "North sky daylight", white_point: WhitePoint { x: 0.2990, y: 0.3149 } },
+    NamedWhitePoint { names: &["a"], description: "Incandescent (tungsten) light", white_point: WhitePoint { x: 0.44757, y: 0.40745 } },
+    NamedWhitePoint { names: &["e"], description: "Equal energy", white_point: WhitePoint { x: 1.0 / 3.0, y: 1.0 / 3.0 } },
+];
+
+// Finds a white point by name, or parses a custom one given as its chromaticity, like `0.3127,0.3290`
+pub fn lookup_white_point(name: &str) -> Option<WhitePoint> {
+    if let Some(named) = WHITE_POINTS.iter().find(|white_point| white_point.names.contains(&name.to_ascii_lowercase().as_str())) {
+        return Some(named.white_point);
+    }
+    let (x, y) = name.split_once(',')?;
+    let (x, y): (f64, f64) = (x.trim().parse().ok()?, y.trim().parse().ok()?);
+    if x > 0.0 && y > 0.0 && x + y < 1.0 { Some(WhitePoint { x, y }) } else { None }
+}
+
+// Bradford chromatic adaptation, which maps colors seen under one white point to the colors that look the same under
+// another. This works in a sharpened cone response space, scaling each response by the ratio between the whites.
+pub fn bradford(from: WhitePoint, to: WhitePoint) -> Matrix3 {
+    const BRADFORD: Matrix3 = [
+        [ 0.8951,  0.2664, -0.1614],
+        [-0.7502,  1.7135,  0.0367],
+        [ 0.0389, -0.0685,  1.0296],
+    ];
+    let from_cone = geom::transform(&BRADFORD, from.to_xyz());
+    let to_cone = geom::transform(&BRADFORD, to.to_xyz());
+    let scale = geom::diagonal([to_cone[0] / from_cone[0], to_cone[1] / from_cone[1], to_cone[2] / from_cone[2]]);
+    geom::multiply(&geom::invert(&BRADFORD), &geom::multiply(&scale, &BRADFORD))
+}
+
+// An RGB color space's primaries (as xy chromaticities) and white point
+#[derive(Copy, Clone, PartialEq)]
+pub struct RgbPrimaries {
+    pub red: [f64; 2],
+    pub green: [f64; 2],
+    pub blue: [f64; 2],
+    pub white: WhitePoint,
+}
+
+impl RgbPrimaries {
+    pub const SRGB: RgbPrimaries = RgbPrimaries { red: [0.64, 0.33], green: [0.30, 0.60], blue: [0.15, 0.06], white: WhitePoint::D65 };
+    pub const DISPLAY_P3: RgbPrimaries = RgbPrimaries { red: [0.680, 0.320], green: [0.265, 0.690], blue: [0.150, 0.060], white: WhitePoint::D65 };
+    pub const ADOBE_RGB: RgbPrimaries = RgbPrimaries { red: [0.64, 0.33], green: [0.21, 0.71], blue: [0.15, 0.06], white: WhitePoint::D65 };
+    pub const REC2020: RgbPrimaries = RgbPrimaries { red: [0.708, 0.292], green: [0.170, 0.797], blue: [0.131, 0.046], white: WhitePoint::D65 };
+
+    // The matrix from linear RGB to XYZ. Each primary's chromaticity fixes the direction of its column, and their
+    // intensities are chosen so that full red, green and blue together make the white point with a luminance of 1.
+    pub fn to_xyz_matrix(self) -> Matrix3 {
+        let column = |[x, y]: [f64; 2]| [x / y, 1.0, (1.0 - x - y) / y];
+        let (r, g, b) = (column(self.red), column(self.green), column(self.blue));
+        let unscaled = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+        let scale = geom::transform(&geom::invert(&unscaled), self.white.to_xyz());
+        geom::multiply(&unscaled, &geom::diagonal(scale))
+    }
+
+    // The matrix from linear RGB with these primaries to linear RGB with others, adapting between their white points
+    pub fn conversion_to(&self, other: &RgbPrimaries) -> Matrix3 {
+        let to_xyz = geom::multiply(&bradford(self.white, other.white), &self.to_xyz_matrix());
+        geom::multiply(&geom::invert(&other.to_xyz_matrix()), &to_xyz)
+    }
+
+    // Whether two sets of primaries agree to the precision that file formats store them with
+    pub fn approx_eq(&self, other: &RgbPrimaries) -> bool {
+        let close = |[x1, y1]: [f64; 2], [x2, y2]: [f64; 2]| (x1 - x2).abs() < 1e-4 && (y1 - y2).abs() < 1e-4;
+        close(self.red, other.red) && close(self.green, other.green) && close(self.blue, other.blue)
+            && close([self.white.x, self.white.y], [other.white.x, other.white.y])
+    }
+}
+
+// A transfer function, relating the stored (encoded) values of a color space to linear light
+#[derive(Copy, Clone, PartialEq)]
+pub enum Transfer {
+    Srgb,
+    // A pure power law with the given exponent for decoding, like Adobe RGB's 563/256
+    Gamma(f64),
+    // The curve from Rec.709, which Rec.2020 shares
+    Rec709,
+}
+
+impl Transfer {
+    pub fn decode(self, value: f64) -> f64 {
+        match self {
+            Transfer::Srgb => srgb_decode(value),
+            Transfer::Gamma(gamma) => value.max(0.0).powf(gamma),
+            Transfer::Rec709 => if value < 0.081 { value / 4.5 } else { ((value + 0.099) / 1.099).powf(1.0 / 0.45) },
+        }
+    }
+
+    pub fn encode(self, value: f64) -> f64 {
+        match self {
+            Transfer::Srgb => srgb_encode(value),
+            Transfer::Gamma(gamma) => value.max(0.0).powf(1.0 / gamma),
+            Transfer::Rec709 => if value < 0.018 { value * 4.5 } else { 1.099 * value.powf(0.45) - 0.099 },
+        }
+    }
+}
+
+// An RGB color space, which is what gives the values stored in an image file (or in a palette) an actual color
+#[derive(Copy, Clone, PartialEq)]
+pub struct ColorSpace {
+    pub primaries: RgbPrimaries,
+    pub transfer: Transfer,
+}
+
+impl ColorSpace {
+    pub const SRGB: ColorSpace = ColorSpace { primaries: RgbPrimaries::SRGB, transfer: Transfer::Srgb };
+    pub const DISPLAY_P3: ColorSpace = ColorSpace { primaries: RgbPrimaries::DISPLAY_P3, transfer: Transfer::Srgb };
+    pub const ADOBE_RGB: ColorSpace = ColorSpace { primaries: RgbPrimaries::ADOBE_RGB, transfer: Transfer::Gamma(563.0 / 256.0) };
+    pub const REC2020: ColorSpace = ColorSpace { primaries: RgbPrimaries::REC2020, transfer: Transfer::Rec709 };
+}
+
+pub struct NamedColorSpace {
+    pub names: &'static [&'static str],
+    pub description: &'static str,
+    pub space: ColorSpace,
+}
+
+pub const COLOR_SPACES: &[NamedColorSpace] = &[
+    NamedColorSpace { names: &["srgb"], description: "The standard for the web and most images", space: ColorSpace::SRGB },
+    NamedColorSpace { names: &["display-p3", "p3"], description: "Apple's wide gamut space, used by most recent phones and laptops", space: ColorSpace::DISPLAY_P3 },
+    NamedColorSpace { names: &["adobe-rgb", "adobe98"], description: "Adobe RGB (1998), with more saturated greens and cyans for print work", space: ColorSpace::ADOBE_RGB },
+    NamedColorSpace { names: &["rec2020", "bt2020"], description: "The very wide gamut of UHD video", space: ColorSpace::REC2020 },
+];
+
+pub fn lookup_color_space(name: &str) -> Option<&'static NamedColorSpace> {
+    COLOR_SPACES.iter().find(|space| space.names.contains(&name.to_ascii_lowercase().as_str()))
+}
+
+// The color spaces and matrices used by the conversions below. Linear RGB and 8-bit RGB colors are in the working
+// space (sRGB unless asked otherwise), which is the space dithered images are written in. CIELAB (and PseudoLab) can be
+// relative to a different white than the working space, in which case colors are chromatically adapted to it first.
+// These are chosen once, at startup, by `configure`.
+struct Conversions {
+    working_space: ColorSpace,
+    rgb_to_xyz: Matrix3,
+    xyz_to_rgb: Matrix3,
+    // From XYZ to XYZ adapted to the Lab white point and divided by it, which is what the Lab formulas start from
+    xyz_to_lab_xyz: Matrix3,
+    lab_xyz_to_xyz: Matrix3,
+    // To and from linear sRGB, which OKLab is defined in terms of
+    rgb_to_srgb: Matrix3,
+    srgb_to_rgb: Matrix3,
+}
+
+impl Conversions {
+    fn new(working_space: ColorSpace, lab_white: WhitePoint) -> Conversions {
+        let primaries = &working_space.primaries;
+        let rgb_to_xyz = primaries.to_xyz_matrix();
+        let white = lab_white.to_xyz();
+        let normalize = geom::diagonal([1.0 / white[0], 1.0 / white[1], 1.0 / white[2]]);
+        let xyz_to_lab_xyz = geom::multiply(&normalize, &bradford(primaries.white, lab_white));
+        Conversions {
+            working_space,
+            rgb_to_xyz,
+            xyz_to_rgb: geom::invert(&rgb_to_xyz),
+            xyz_to_lab_xyz,
+            lab_xyz_to_xyz: geom::invert(&xyz_to_lab_xyz),
+            rgb_to_srgb: primaries.conversion_to(&RgbPrimaries::SRGB),
+            srgb_to_rgb: RgbPrimaries::SRGB.conversion_to(primaries),
+        }
+    }
+}
+
+static CONVERSIONS: OnceLock<Conversions> = OnceLock::new();
+
+fn conversions() -> &'static Conversions {
+    CONVERSIONS.get_or_init(|| Conversions::new(ColorSpace::SRGB, WhitePoint::D65))
+}
+
+// Chooses the working space and the reference white for CIELAB. This must happen before any colors are converted.
+pub fn configure(working_space: ColorSpace, lab_white: WhitePoint) {
+    if CONVERSIONS.set(Conversions::new(working_space, lab_white)).is_err() {
+        panic!("The color settings must be chosen before any colors are converted!");
+    }
+}
+
+pub fn working_space() -> ColorSpace {
+    conversions().working_space
+}
+
 //////// Conversions ////////
 
-pub fn srgb_decode_channel(value: u8) -> f64 {
-    let normalized = value as f64 / 255.0;
+// The sRGB transfer function and its inverse, for values from 0 to 1
+pub fn srgb_decode(normalized: f64) -> f64 {
     if normalized < 0.04045 {
         normalized / 12.92
     } else {
@@ -69,21 +302,37 @@ pub fn srgb_decode_channel(value: u8) -> f64 {
     }
 }
 
-pub fn srgb_encode_channel(value: f64) -> u8 {
-    let normalized = if value <= 0.04045 / 12.92 {
+pub fn srgb_encode(value: f64) -> f64 {
+    if value <= 0.04045 / 12.92 {
         value * 12.92
     } else {
         value.powf(1.0 / 2.4) * 1.055 - 0.055
-    };
-    (normalized * 255.0).round() as u8
+    }
+}
+
+// The working space's transfer function and its inverse, for values from 0 to 1
+pub fn decode(normalized: f64) -> f64 {
+    conversions().working_space.transfer.decode(normalized)
+}
+
+pub fn encode(value: f64) -> f64 {
+    conversions().working_space.transfer.encode(value)
+}
+
+pub fn decode_channel(value: u8) -> f64 {
+    decode(value as f64 / 255.0)
+}
+
+pub fn encode_channel(value: f64) -> u8 {
+    (encode(value) * 255.0).round() as u8
 }
 
 impl From<Srgb8> for LinearRgb {
     fn from(srgb: Srgb8) -> LinearRgb {
         LinearRgb { data: [
-            srgb_decode_channel(srgb.channels()[0]),
-            srgb_decode_channel(srgb.channels()[1]),
-            srgb_decode_channel(srgb.channels()[2]),
+            decode_channel(srgb.channels()[0]),
+            decode_channel(srgb.channels()[1]),
+            decode_channel(srgb.channels()[2]),
         ] }
     }
 }
@@ -91,30 +340,22 @@ impl From<Srgb8> for LinearRgb {
 impl From<LinearRgb> for Srgb8 {
     fn from(rgb: LinearRgb) -> Srgb8 {
         image::Rgb([
-            srgb_encode_channel(rgb.data[0]),
-            srgb_encode_channel(rgb.data[1]),
-            srgb_encode_channel(rgb.data[2]),
+            encode_channel(rgb.data[0]),
+            encode_channel(rgb.data[1]),
+            encode_channel(rgb.data[2]),
         ])
     }
 }
 
 impl From<LinearRgb> for Xyz {
     fn from(rgb: LinearRgb) -> Xyz {
-        Xyz { data: [
-            0.4124*rgb.data[0] + 0.3576*rgb.data[1] + 0.1805*rgb.data[2],
-            0.2126*rgb.data[0] + 0.7152*rgb.data[1] + 0.0722*rgb.data[2],
-            0.0193*rgb.data[0] + 0.1192*rgb.data[1] + 0.9505*rgb.data[2]
-        ] }
+        Xyz { data: geom::transform(&conversions().rgb_to_xyz, rgb.data) }
     }
 }
 
 impl From<Xyz> for LinearRgb {
     fn from(xyz: Xyz) -> LinearRgb {
-        LinearRgb { data: [
-             3.2406 * xyz.data[0] - 1.5372 * xyz.data[1] - 0.4986 * xyz.data[2],
-            -0.9689 * xyz.data[0] + 1.8758 * xyz.data[1] + 0.0415 * xyz.data[2],
-             0.0557 * xyz.data[0] - 0.2040 * xyz.data[1] + 1.0570 * xyz.data[2]
-        ] }
+        LinearRgb { data: geom::transform(&conversions().xyz_to_rgb, xyz.data) }
     }
 }
 
@@ -129,9 +370,10 @@ impl From<Xyz> for Lab {
             }
         }
 
-        let fx = f(xyz.data[0] / 0.9505);
-        let fy = f(xyz.data[1]);
-        let fz = f(xyz.data[2] / 1.089);
+        let [x, y, z] = geom::transform(&conversions().xyz_to_lab_xyz, xyz.data);
+        let fx = f(x);
+        let fy = f(y);
+        let fz = f(z);
 
         let l = 116.0 * fy - 16.0;
         let a = 500.0 * (fx - fy);
@@ -148,9 +390,10 @@ impl From<Xyz> for PseudoLab {
             value * 0.78 + 0.325
         }
         
-        let fx = f(xyz.data[0] / 0.9505);
-        let fy = f(xyz.data[1]);
-        let fz = f(xyz.data[2] / 1.089);
+        let [x, y, z] = geom::transform(&conversions().xyz_to_lab_xyz, xyz.data);
+        let fx = f(x);
+        let fy = f(y);
+        let fz = f(z);
 
         let l = 1.16 * fy - 0.16;
         let a = 5.0 * (fx - fy);
@@ -170,7 +413,7 @@ impl From<PseudoLab> for Xyz {
         let fx = plab.data[1] / 5.0 + fy;
         let fz = fy - plab.data[2] / 2.0;
 
-        Xyz { data: [invf(fx) * 0.9505, invf(fy), invf(fz) * 1.089] }
+        Xyz { data: geom::transform(&conversions().lab_xyz_to_xyz, [invf(fx), invf(fy), invf(fz)]) }
     }
 }
 
@@ -198,6 +441,113 @@ impl From<Srgb8> for Lab {
     }
 }
 
+impl From<LinearRgb> for OkLab {
+    fn from(rgb: LinearRgb) -> OkLab {
+        let [r, g, b] = geom::transform(&conversions().rgb_to_srgb, rgb.data);
+        let l = (0.4122214708 * r + 0.5363325363 * g + 0.0514459929 * b).cbrt();
+        let m = (0.2119034982 * r + 0.6806995451 * g + 0.1073969566 * b).cbrt();
+        let s = (0.0883024619 * r + 0.2817188376 * g + 0.6299787005 * b).cbrt();
+        OkLab { data: [
+            0.2104542553 * l + 0.7936177850 * m - 0.0040720468 * s,
+            1.9779984951 * l - 2.4285922050 * m + 0.4505937099 * s,
+            0.0259040371 * l + 0.7827717662 * m - 0.8086757660 * s,
+        ] }
+    }
+}
+
+impl From<OkLab> for LinearRgb {
+    fn from(oklab: OkLab) -> LinearRgb {
+        let [l, a, b] = oklab.data;
+        let l_ = (l + 0.3963377774 * a + 0.2158037573 * b).powi(3);
+        let m_ = (l - 0.1055613458 * a - 0.0638541728 * b).powi(3);
+        let s_ = (l - 0.0894841775 * a - 1.2914855480 * b).powi(3);
+        LinearRgb { data: geom::transform(&conversions().srgb_to_rgb, [
+             4.0767416621 * l_ - 3.3077115913 * m_ + 0.2309699292 * s_,
+            -1.2684380046 * l_ + 2.6097574011 * m_ - 0.3413193965 * s_,
+            -0.0041960863 * l_ - 0.7034186147 * m_ + 1.7076147010 * s_,
+        ]) }
+    }
+}
+
+//////// Images ////////
+
+// An image stored in linear RGB. This is how we keep input images, so that 16-bit and floating point files keep all of
+// their precision instead of being rounded to 8 bits before dithering.
+#[derive(Clone)]
+pub struct LinearImage {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<LinearRgb>,
+}
+
+impl LinearImage {
+    // An 8-bit image whose values are already in the working space, like a dithered one
+    pub fn from_rgb8(img: &image::RgbImage) -> LinearImage {
+        LinearImage {
+            width: img.width(),
+            height: img.height(),
+            pixels: img.pixels().cloned().map(LinearRgb::from).collect(),
+        }
+    }
+
+    // Decodes an image stored in the given color space, converting it to the working space. Colors outside the working
+    // space's gamut (from a wider space, or brighter than white) are clipped, since the dither can't reach them anyway.
+    pub fn from_dynamic(img: image::DynamicImage, space: &ColorSpace) -> LinearImage {
+        let mut decoded = LinearImage::from_dynamic_unclipped(img, space);
+        for pixel in &mut decoded.pixels {
+            *pixel = pixel.clamp();
+        }
+        decoded
+    }
+
+    // The same without clipping, for adjusting before anything out of range is clipped
+    pub fn from_dynamic_unclipped(img: image::DynamicImage, space: &ColorSpace) -> LinearImage {
+        let (width, height) = (img.width(), img.height());
+        let decode = |value: f64| space.transfer.decode(value);
+        let mut pixels: Vec<LinearRgb> = match img {
+            // Floating point formats (OpenEXR and Radiance HDR) store linear light already. Values above 1 are kept, but
+            // negative ones (which some renderers produce) have no meaning for us and would break the Lab conversion.
+            image::DynamicImage::ImageRgb32F(_) | image::DynamicImage::ImageRgba32F(_) => {
+                let value = |value: f32| (value as f64).max(0.0);
+                img.into_rgb32f().pixels().map(|pixel| LinearRgb { data: [value(pixel.0[0]), value(pixel.0[1]), value(pixel.0[2])] }).collect()
+            },
+            image::DynamicImage::ImageLuma16(_) | image::DynamicImage::ImageLumaA16(_) |
+            image::DynamicImage::ImageRgb16(_) | image::DynamicImage::ImageRgba16(_) => {
+                let decode = |value: u16| decode(value as f64 / 65535.0);
+                img.into_rgb16().pixels().map(|pixel| LinearRgb { data: [decode(pixel.0[0]), decode(pixel.0[1]), decode(pixel.0[2])] }).collect()
+            },
+            _ => {
+                // Decoding each channel is slow enough to be worth a lookup table
+                let table: Vec<f64> = (0..=255).map(|value| decode(value as f64 / 255.0)).collect();
+                img.into_rgb8().pixels().map(|pixel| LinearRgb { data: [table[pixel.0[0] as usize], table[pixel.0[1] as usize], table[pixel.0[2] as usize]] }).collect()
+            },
+        };
+
+        let working_space = working_space();
+        if space.primaries != working_space.primaries {
+            let matrix = space.primaries.conversion_to(&working_space.primaries);
+            for pixel in &mut pixels {
+                pixel.data = geom::transform(&matrix, pixel.data);
+            }
+        }
+        LinearImage { width, height, pixels }
+    }
+
+    pub fn get(&self, x: u32, y: u32) -> LinearRgb {
+        self.pixels[(y * self.width + x) as usize]
+    }
+
+    pub fn crop(&self, x: u32, y: u32, width: u32, height: u32) -> LinearImage {
+        let pixels = (y..y + height).flat_map(|y| (x..x + width).map(move |x| (x, y))).map(|(x, y)| self.get(x, y)).collect();
+        LinearImage { width, height, pixels }
+    }
+
+    // Rounds to 8-bit sRGB, clipping anything out of range
+    pub fn to_srgb8(&self) -> image::RgbImage {
+        image::RgbImage::from_fn(self.width, self.height, |x, y| Srgb8::from(self.get(x, y).clamp()))
+    }
+}
+
 //////// Color difference ////////
 
 impl Lab {
@@ -368,3 +718,21 @@ impl Lab {
         (diff_l_adj / sl).powi(2) + (diff_c_adj / sc).powi(2) + (diff_H_adj / sh).powi(2) + rt_repair * (diff_c_adj / sc) * (diff_H_adj / sh)
     }
 }
+
+pub struct NamedDistance {
+    pub names: &'static [&'static str],
+    pub description: &'static str,
+    pub func: fn(Lab, Lab) -> f64,
+}
+
+pub const DISTANCES: &[NamedDistance] = &[
+    NamedDistance { names: &["CIE1994", "CIE94"], description: "CIE 1994 color difference, using the graphic arts constants", func: Lab::cie1994_distance2 },
+    NamedDistance { names: &["symCIE1994"], description: "CIE 1994 made symmetric by averaging the chromas", func: Lab::sym_cie1994_distance2 },
+    NamedDistance { names: &["wdsCIE1994"], description: "Warren D. Smith's rescaled symmetric CIE 1994", func: Lab::wds_cie1994_distance2 },
+    NamedDistance { names: &["CIEDE2000"], description: "CIEDE2000 color difference", func: Lab::ciede2000_distance2 },
+    NamedDistance { names: &["contCIEDE2000"], description: "Warren D. Smith's continuous variant of CIEDE2000", func: Lab::cont_ciede2000_distance2 },
+];
+
+pub fn lookup_distance(name: &str) -> Option<&'static NamedDistance> {
+    DISTANCES.iter().find(|distance| distance.names.contains(&name))
+}
@@ -0,0 +1,107 @@
+use crate::Settings;
+use crate::color::{Srgb8, LinearRgb, LinearImage, Lab};
+use crate::dither::{self, Algorithm};
+
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use std::collections::HashMap;
+
+// Attribute clash: dithering for hardware that can only show a few colors in each block of pixels, like the ZX Spectrum
+// (2 colors in each 8x8 cell), C64 multicolor mode (3 colors in each 4x8 cell, plus a background shared by the whole
+// screen) or NES backgrounds (3 colors in each 16x16 area, plus a shared backdrop).
+//
+// The whole image is first dithered without any limits, which says how much each color is wanted, both overall and in
+// each block. The colors used most overall are shared by every block. Each block then picks the rest of its colors
+// from the ones its own pixels used, one at a time, taking whichever leaves its pixels closest to the gamut of the
+// colors picked so far, and is dithered again with only those.
+
+pub struct Blocks {
+    pub width: u32,
+    pub height: u32,
+    // How many colors each block can use, counting the shared ones
+    pub colors: usize,
+    pub shared: usize,
+}
+
+impl Blocks {
+    pub fn from_matches(arg_matches: &clap::ArgMatches) -> Option<Blocks> {
+        let spec = arg_matches.value_of("BLOCK")?;
+        let (width, height) = match spec.split_once('x').map(|(width, height)| (width.parse(), height.parse())) {
+            Some((Ok(width), Ok(height))) if width > 0 && height > 0 => (width, height),
+            _ => panic!("Invalid block size {:?}! Use something like 8x8.", spec),
+        };
+        let colors: usize = arg_matches.value_of_t_or_exit("BLOCK_COLORS");
+        let shared: usize = arg_matches.value_of_t_or_exit("SHARED_COLORS");
+        if colors == 0 {
+            panic!("Each block needs at least one color!");
+        }
+        if shared > colors {
+            panic!("There can't be more shared colors than colors in each block!");
+        }
+        Some(Blocks { width, height, colors, shared })
+    }
+}
+
+// Positions in the palette of the colors used in part of a dithered image, from most used to least, with ties in
+// palette order
+fn colors_by_usage(img: &image::RgbImage, positions: &HashMap<Srgb8, usize>, x: u32, y: u32, width: u32, height: u32) -> Vec<usize> {
+    let mut counts = vec![0usize; positions.values().max().unwrap() + 1];
+    for y in y..y + height {
+        for x in x..x + width {
+            counts[positions[img.get_pixel(x, y)]] += 1;
+        }
+    }
+    let mut used: Vec<usize> = (0..counts.len()).filter(|&i| counts[i] > 0).collect();
+    used.sort_by_key(|&i| std::cmp::Reverse(counts[i]));
+    used
+}
+
+pub fn dither(blocks: &Blocks, source: &LinearImage, palette: &[Srgb8], linear_palette: &[LinearRgb], lab_palette: &[Lab], settings: &Settings) -> image::RgbImage {
+    if let Algorithm::PerChannel = *settings.algorithm {
+        panic!("The per-channel algorithm can't keep to a few colors per block! Choose an ordered or error diffusion algorithm.");
+    }
+    let distance2 = settings.distance2_func;
+    let unconstrained = dither::dither_image(source, settings.algorithm, palette, linear_palette, lab_palette, &settings.bias_func, distance2);
+    // Going backwards, so that a color listed twice counts as its first position
+    let positions: HashMap<Srgb8, usize> = palette.iter().enumerate().rev().map(|(i, &color)| (color, i)).collect();
+    let shared: Vec<usize> = colors_by_usage(&unconstrained, &positions, 0, 0, source.width, source.height).into_iter().take(blocks.shared).collect();
+
+    let columns = source.width.div_ceil(blocks.width);
+    let rows = source.height.div_ceil(blocks.height);
+    let dithered_blocks: Vec<(u32, u32, image::RgbImage)> = (0..columns * rows).into_par_iter().map(|i| {
+        let (x, y) = ((i % columns) * blocks.width, (i / columns) * blocks.height);
+        let (width, height) = (blocks.width.min(source.width - x), blocks.height.min(source.height - y));
+        let block = source.crop(x, y, width, height);
+        let sub_palettes = |positions: &[usize]| {
+            let sub_palette: Vec<Srgb8> = positions.iter().map(|&i| palette[i]).collect();
+            let sub_linear: Vec<LinearRgb> = positions.iter().map(|&i| linear_palette[i]).collect();
+            let sub_lab: Vec<Lab> = positions.iter().map(|&i| lab_palette[i]).collect();
+            (sub_palette, sub_linear, sub_lab)
+        };
+
+        let mut chosen = shared.clone();
+        let mut candidates: Vec<usize> = colors_by_usage(&unconstrained, &positions, x, y, width, height).into_iter().filter(|i| !chosen.contains(i)).collect();
+        while chosen.len() < blocks.colors && !candidates.is_empty() {
+            let errors: Vec<f64> = candidates.iter().map(|&candidate| {
+                let mut trial = chosen.clone();
+                trial.push(candidate);
+                let (sub_palette, sub_linear, sub_lab) = sub_palettes(&trial);
+                dither::gamut_errors(&block, &sub_palette, &sub_linear, &sub_lab, distance2).iter().sum()
+            }).collect();
+            // The first of equally good candidates is the most used one
+            let best = (0..candidates.len()).min_by(|&c1, &c2| errors[c1].partial_cmp(&errors[c2]).unwrap()).unwrap();
+            chosen.push(candidates.remove(best));
+        }
+        chosen.sort_unstable();
+
+        let (sub_palette, sub_linear, sub_lab) = sub_palettes(&chosen);
+        let bias_func = |bx, by| (settings.bias_func)(x + bx, y + by);
+        (x, y, dither::dither_image(&block, settings.algorithm, &sub_palette, &sub_linear, &sub_lab, &bias_func, distance2))
+    }).collect();
+
+    let mut img = image::RgbImage::new(source.width, source.height);
+    for (x, y, block) in dithered_blocks {
+        image::imageops::replace(&mut img, &block, x as i64, y as i64);
+    }
+    img
+}
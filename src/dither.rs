@@ -0,0 +1,628 @@
+use crate::color::{self, Srgb8, LinearRgb, LinearImage, Lab, PseudoLab};
+use crate::geom::{Vec3, determinant, subtract};
+
+use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator, ParallelBridge};
+
+// The signature shared by all the per-pixel (ordered) dithering algorithms: given a pixel, the palette in several
+// representations, and that pixel's bias value, choose a palette color.
+pub type PixelAlgorithm = fn(LinearRgb, &[Srgb8], &[LinearRgb], &[Lab], f64, fn(Lab, Lab) -> f64) -> Srgb8;
+
+pub enum Algorithm {
+    // Each pixel is dithered independently, using the bias pattern to choose between colors
+    Ordered(PixelAlgorithm),
+    // The whole image is processed at once, in order, so the bias pattern is ignored
+    FloydSteinberg { serpentine: bool, clamp: bool },
+    // Each channel is dithered on its own, which only works for grid palettes
+    PerChannel,
+}
+
+pub struct NamedAlgorithm {
+    pub names: &'static [&'static str],
+    pub description: &'static str,
+    pub algorithm: Algorithm,
+}
+
+pub const ALGORITHMS: &[NamedAlgorithm] = &[
+    NamedAlgorithm { names: &["nearest"], description: "Map every pixel to its nearest palette color", algorithm: Algorithm::Ordered(nearest) },
+    NamedAlgorithm { names: &["nearest2:d^-2"], description: "Choose between the two nearest colors, weighted by inverse squared distance", algorithm: Algorithm::Ordered(nearest2_inv2_dist) },
+    NamedAlgorithm { names: &["nearest2:project"], description: "Choose between the two nearest colors by projecting onto the segment between them", algorithm: Algorithm::Ordered(nearest2_project) },
+    NamedAlgorithm { names: &["simplex"], description: "Mix up to 4 palette colors to exactly reproduce the average color", algorithm: Algorithm::Ordered(tight_simplex) },
+    NamedAlgorithm { names: &["per-channel"], description: "Dither each channel separately, for grid palettes like websafe or those from --channels", algorithm: Algorithm::PerChannel },
+    NamedAlgorithm { names: &["floyd-steinberg"], description: "Floyd-Steinberg error diffusion", algorithm: Algorithm::FloydSteinberg { serpentine: false, clamp: false } },
+    NamedAlgorithm { names: &["floyd-steinberg+serpentine"], description: "Floyd-Steinberg, alternating direction every row", algorithm: Algorithm::FloydSteinberg { serpentine: true, clamp: false } },
+    NamedAlgorithm { names: &["floyd-steinberg+clamp"], description: "Floyd-Steinberg, clamping the accumulated error", algorithm: Algorithm::FloydSteinberg { serpentine: false, clamp: true } },
+    NamedAlgorithm { names: &["floyd-steinberg+clamp+serpentine"], description: "Floyd-Steinberg, both clamped and serpentine", algorithm: Algorithm::FloydSteinberg { serpentine: true, clamp: true } },
+    // For debugging
+    NamedAlgorithm { names: &["visualize-bias"], description: "Output the bias pattern itself as a grayscale image (for debugging)", algorithm: Algorithm::Ordered(visualize_bias) },
+];
+
+pub fn lookup(name: &str) -> Option<&'static NamedAlgorithm> {
+    ALGORITHMS.iter().find(|algorithm| algorithm.names.contains(&name))
+}
+
+// Dithers a whole image, using whichever kind of algorithm was chosen
+pub fn dither_image(source: &LinearImage, algorithm: &Algorithm, palette: &[Srgb8], linear_palette: &[LinearRgb], lab_palette: &[Lab], bias_func: &(dyn Fn(u32, u32) -> f64 + Sync), distance2: fn(Lab, Lab) -> f64) -> image::RgbImage {
+    match *algorithm {
+        Algorithm::FloydSteinberg { serpentine, clamp } => {
+            floyd_steinberg(source, palette, linear_palette, lab_palette, serpentine, clamp, distance2)
+        },
+        Algorithm::PerChannel => match grid_levels(palette) {
+            Some(levels) => per_channel(source, &levels, bias_func),
+            None => panic!("The per-channel algorithm needs a palette with every combination of its channel values, like websafe or one from --channels!"),
+        },
+        Algorithm::Ordered(pixel_algorithm) => {
+            let mut img = image::RgbImage::new(source.width, source.height);
+            img.enumerate_pixels_mut().par_bridge().for_each(|(x, y, pixel)| {
+                let bias = bias_func(x, y);
+
+                if x == 0 && y % 10 == 0 {
+                    eprintln!("Processing line {}", y);
+                }
+
+                //eprintln!();
+                //eprintln!("Pixel at ({}, {})", x, y);
+                *pixel = pixel_algorithm(source.get(x, y), palette, linear_palette, lab_palette, bias, distance2);
+            });
+            img
+        },
+    }
+}
+
+// The position in the palette of the color the dither chose for each pixel, in row-major order. Where several entries
+// are shown as the same color, the dither can't tell them apart, so the first is taken.
+pub fn palette_positions(img: &image::RgbImage, palette: &[Srgb8]) -> Vec<usize> {
+    let mut positions = std::collections::HashMap::new();
+    for (position, &color) in palette.iter().enumerate().rev() {
+        positions.insert(color, position);
+    }
+    img.pixels().map(|pixel| match positions.get(pixel) {
+        Some(&position) => position,
+        None => panic!("The image has a color that isn't in the palette!"),
+    }).collect()
+}
+
+// The values each channel takes in a palette that contains every combination of them, like those from `palettes::grid`,
+// or None if the palette isn't like that
+pub fn grid_levels(palette: &[Srgb8]) -> Option<[Vec<u8>; 3]> {
+    let channel_levels = |channel: usize| -> Vec<u8> {
+        let mut levels: Vec<u8> = palette.iter().map(|color| color.0[channel]).collect();
+        levels.sort_unstable();
+        levels.dedup();
+        levels
+    };
+    let levels = [channel_levels(0), channel_levels(1), channel_levels(2)];
+    let distinct: std::collections::HashSet<Srgb8> = palette.iter().cloned().collect();
+    if distinct.len() == levels.iter().map(Vec::len).product() {
+        Some(levels)
+    } else {
+        None
+    }
+}
+
+// Where each channel reads the bias pattern from, relative to the pixel. If every channel used the same bias, they would
+// all round up in the same places, giving gray speckles instead of fine color noise; these offsets don't line up on any
+// of the small power-of-two patterns.
+const CHANNEL_OFFSETS: [(u32, u32); 3] = [(0, 0), (3, 5), (5, 2)];
+
+// Dithers each channel independently, choosing between the two levels around its value in linear light. This keeps each
+// channel's average exact without searching the palette at all, so it stays fast for palettes like RGB565's 65536
+// colors.
+fn per_channel(source: &LinearImage, levels: &[Vec<u8>; 3], bias_func: &(dyn Fn(u32, u32) -> f64 + Sync)) -> image::RgbImage {
+    let linear_levels: Vec<Vec<f64>> = levels.iter().map(|levels| levels.iter().map(|&value| color::decode_channel(value)).collect()).collect();
+    let mut img = image::RgbImage::new(source.width, source.height);
+    img.enumerate_pixels_mut().par_bridge().for_each(|(x, y, pixel)| {
+        let value = source.get(x, y);
+        for channel in 0..3 {
+            let (linear, levels) = (&linear_levels[channel], &levels[channel]);
+            if levels.len() == 1 {
+                pixel.0[channel] = levels[0];
+                continue;
+            }
+            let upper = linear.partition_point(|&level| level <= value.data[channel]).clamp(1, levels.len() - 1);
+            let fraction = (value.data[channel] - linear[upper - 1]) / (linear[upper] - linear[upper - 1]);
+            let (dx, dy) = CHANNEL_OFFSETS[channel];
+            pixel.0[channel] = if fraction > bias_func(x + dx, y + dy) { levels[upper] } else { levels[upper - 1] };
+        }
+    });
+    img
+}
+
+// Traditional Floyd-Steinberg dithering. Or it would be, except that everything is gamma-correct and using CIEDE2000, which seems to
+// result in some pretty drastic changes, or at least occasional failures that look significanty worse than any other implementation I've
+// seen. This may be the result of errors being done in floating point, which allows them to grow arbitrarily large and excessive, combined
+// with CIEDE2000 continuing to choose a value that doesn't correct for the error. Therefore, there is also a manual clamping that can be
+// enabled to force errors within a reasonable range. TODO: make color comparison configuable, and see if that fixes things
+pub fn floyd_steinberg(source: &LinearImage, palette: &[Srgb8], linear_palette: &[LinearRgb], lab_palette: &[Lab], serpentine: bool, clamp: bool, distance2: fn(Lab, Lab) -> f64) -> image::RgbImage {
+    let mut img = image::RgbImage::new(source.width, source.height);
+    let select_color = |rgb: LinearRgb| -> usize {
+        let lab = Lab::from(rgb);
+        // Since error diffusion is mostly inherently serial, we parallelize the search for the nearest color
+        if lab_palette.len() < 250 { // TODO: don't just do an ad-hoc switch
+            (0..lab_palette.len()).map(|i| (i, distance2(lab, lab_palette[i])))
+                                  .fold((!0, core::f64::INFINITY), |(i1, d1), (i2, d2)| {
+                                      if d1 < d2 {
+                                          (i1, d1)
+                                      } else {
+                                          (i2, d2)
+                                      }
+                                  }).0
+        } else {
+            (0..lab_palette.len()).into_par_iter()
+                                  .map(|i| (i, distance2(lab, lab_palette[i])))
+                                  .reduce(|| (!0, core::f64::INFINITY), |(i1, d1), (i2, d2)| {
+                                      if d1 < d2 {
+                                          (i1, d1)
+                                      } else {
+                                          (i2, d2)
+                                      }
+                                  }).0
+        }
+    };
+
+    let do_clamp = |rgb: LinearRgb| if clamp { rgb.clamp() } else { rgb };
+
+    // At any point in the loop, this contains the error for the next row in positions 0..=x+1 and the error
+    // for the current row in the remaining positions
+    let mut row_error: Vec<Vec3<LinearRgb>> = core::iter::repeat(Vec3::zero()).take(img.width() as usize).collect();
+    for y in 0..img.height()-1 {
+        if y % 10 == 0 {
+            eprintln!("Processing line {}", y);
+        }
+
+        // If serpentine is set, then we use the same algorithm, but traverse the arrays in the opposite order
+        // every other row.
+        let widthm1 = img.width() - 1;
+        let flip = |x| if serpentine && (y & 1) != 0 { widthm1 - x } else { x };
+
+        // Because row_error[x + 1] holds the next row's error, we need to save the current row's
+        // error so that it doesn't get overwritten.
+        let mut next_error = row_error[flip(0) as usize];
+
+        // Separate the left side: because we cannot send error off the image, send it downwards.
+        let first_target = do_clamp(next_error + source.get(flip(0), y));
+        next_error = row_error[flip(1) as usize];
+        let first_selection = select_color(first_target);
+        let first_error = subtract(first_target, linear_palette[first_selection]);
+        *img.get_pixel_mut(flip(0), y) = palette[first_selection];
+        row_error[flip(0) as usize] = first_error * 0.5;
+        row_error[flip(1) as usize] = first_error * 0.0625;
+        next_error += first_error * 0.4375;
+
+        for x in 1..img.width()-1 {
+            let target = do_clamp(next_error + source.get(flip(x), y));
+            next_error = row_error[flip(x + 1) as usize];
+            let selection = select_color(target);
+            let error = subtract(target, linear_palette[selection]);
+            *img.get_pixel_mut(flip(x), y) = palette[selection];
+            row_error[flip(x - 1) as usize] += error * 0.1875;
+            row_error[flip(x) as usize] += error * 0.3125;
+            row_error[flip(x + 1) as usize] = error * 0.0625;
+            next_error += error * 0.4325;
+        }
+
+        // Separate the righ side: to avoid sending error over the edge, distribute it proportionally downwards.
+        let last_target = do_clamp(next_error + source.get(flip(img.width() - 1), y));
+        let last_selection = select_color(last_target);
+        let last_error = subtract(last_target, linear_palette[last_selection]);
+        *img.get_pixel_mut(flip(img.width() - 1), y) = palette[last_selection];
+        row_error[flip(img.width() - 2) as usize] += last_error * 0.375;
+        row_error[flip(img.width() - 1) as usize] += last_error * 0.625;
+    }
+
+    // Handle the last row by pushing error entirely rightwards.
+    // TODO: flip for serpentine?
+    let mut error = Vec3::zero();
+    for x in 0..img.width() {
+        let target = do_clamp(error + row_error[x as usize] + source.get(x, img.height() - 1));
+        let selection = select_color(target);
+        error = subtract(target, linear_palette[selection]);
+        *img.get_pixel_mut(x, img.height() - 1) = palette[selection];
+    }
+
+    img
+}
+
+// Simple quantization: map each pixel to the nearest palette color.
+pub fn nearest(pixel: LinearRgb, palette: &[Srgb8], _linear_palette: &[LinearRgb], lab_palette: &[Lab], _bias: f64, distance2: fn(Lab, Lab) -> f64) -> Srgb8 {
+    let lab_pixel = Lab::from(pixel);
+    let mut best_dist = std::f64::INFINITY;
+    let mut best = None;
+    for (&opt, &lab_opt) in palette.iter().zip(lab_palette.iter()) {
+        let dist = distance2(lab_opt, lab_pixel);
+        if dist < best_dist {
+            best_dist = dist;
+            best = Some(opt);
+        }
+    }
+
+    best.unwrap()
+}
+
+// A simple dithering scheme in the style of Yliluoma or simplex dithering: it chooses two nearby palette colors,
+// then quasirandomly chooses between them. This is really just a toy and should probably be removed. It doesn't
+// work very well.
+pub fn nearest2_inv2_dist(pixel: LinearRgb, palette: &[Srgb8], _linear_palette: &[LinearRgb], lab_palette: &[Lab], bias: f64, distance2: fn(Lab, Lab) -> f64) -> image::Rgb<u8> {
+    let lab_pixel = Lab::from(pixel);
+    let mut best_dist = std::f64::INFINITY;
+    let mut best2_dist = std::f64::INFINITY;
+    let mut best = None;
+    let mut best2 = None;
+    for (&opt, &lab_opt) in palette.iter().zip(lab_palette.iter()) {
+        let dist = distance2(lab_opt, lab_pixel);
+        if dist < best_dist {
+            best2_dist = best_dist;
+            best2 = best;
+            best_dist = dist;
+            best = Some(opt);
+        } else if dist < best2_dist {
+            best2_dist = dist;
+            best2 = Some(opt);
+        }
+    }
+
+    let inv2_best = 1.0 / best_dist;
+    let inv2_best2 = 1.0 / best2_dist;
+    let total = inv2_best + inv2_best2;
+    if bias * total <= inv2_best {
+        best.unwrap()
+    } else {
+        best2.unwrap()
+    }
+}
+
+pub fn nearest2_project(linear_pixel: LinearRgb, palette: &[Srgb8], linear_palette: &[LinearRgb], lab_palette: &[Lab], bias: f64, distance2: fn(Lab, Lab) -> f64) -> image::Rgb<u8> {
+    let lab_pixel = Lab::from(linear_pixel);
+
+    let mut trans_palette: Vec<_> = palette.iter().cloned().enumerate().map(|(i, rgb8)| {
+        let linear = subtract(linear_palette[i], linear_pixel); // Shift to our pixel being at the origin, since this simplifies a good chunk of the math.
+        let lab = lab_palette[i];
+        let dist2 = distance2(lab_pixel, lab);
+        (rgb8, linear, dist2, lab.l)
+    }).collect();
+    trans_palette.sort_unstable_by(|&(_, _, d1, _), &(_, _, d2, _)| d1.partial_cmp(&d2).unwrap());
+
+    // Fast path that also avoids some of the most annoying edge cases: if we're on a palette color, just return that.
+    if trans_palette[0].2 < 1e-20 {
+        return trans_palette[0].0;
+    }
+
+    let plab_pixel = PseudoLab::from(linear_pixel);
+
+    for index2 in 1..trans_palette.len() {
+        for index1 in 0..index2 {
+            // TODO: just scale directly
+            let point_1 = PseudoLab::from(trans_palette[index1].1 + linear_pixel);
+            let point_2 = PseudoLab::from(trans_palette[index2].1 + linear_pixel);
+
+            // Project our point onto the line segment
+            let vec_12 = subtract(point_2, point_1);
+            let vec_1p = subtract(plab_pixel, point_1);
+            let mag2_12 = vec_12.dot(vec_12);
+            let proj_1p = vec_12 * (vec_1p.dot(vec_12) / mag2_12);
+            let projected = proj_1p + point_1;
+
+            // Test whether we are actually in the segment, not elsewhere on the line
+            let offset_mag = proj_1p.dot(vec_12);
+            //eprintln!("Line: {}", offset_mag / mag2_12);
+            if offset_mag >= 0.0 && offset_mag <= mag2_12 {
+                // Ensure our palette is sorted
+                let bias_shifted = if trans_palette[index1].3 < trans_palette[index2].3 {
+                    1.0 - bias
+                } else {
+                    bias
+                };
+
+                // Select between the two points
+                if bias_shifted * mag2_12 <= offset_mag {
+                    return trans_palette[index2].0;
+                } else {
+                    return trans_palette[index1].0;
+                }
+            }
+        }
+    }
+
+    return trans_palette[0].0;
+}
+
+// The simplex ordered dithering algorithm. The majority of the actual code here is dealing with edge cases.
+// The basic idea is to look for a simplex in linear space that contains the actual color value of the pixel
+// we wish to represent. Then, we represent that pixel in barycentric coordinates within the simplex so that
+// it can be seen as a weighted average of simplex values. Finally, we use the bias value to quasirandomly
+// pick between the corners of the simplex, weighted by the barycentric coordinates: this has an expected
+// value equal to the actual pixel value.
+//
+// When choosing a simplex, we wish to minimize the number of jarring transitions that are supposed to average
+// out, so we want a simplex that is psychovisually small and close to the desired color. As such, we sort all
+// palette points by their distance to the desired color and choose the simplex that fits inside the smallest
+// ball around the desired color, i.e. we minimize that maximum deviation from our desired color. Ties are broken
+// by considering the closeness of successively closer corners of the simplex.
+//
+// Once we've chosen a simplex, we need to "randomly" choose between the vertices. However, since our bias is
+// quasirandom, it's important that we don't add more randomness, which would decrease the quality of the dither.
+// To ensure consistency, we therefore first sort the vertices by luma. This means that low bias values result
+// in a darker choice and high bias values give rise to a lighter choice, consistently across the image. Without
+// this, the dither ends up very clumpy and not sharp.
+//
+//
+// Note that if P is inside a simplex, then, for any other point C, P must be inside one of the 4 simplices
+// obtained by replacing one of the corners with C. For the purposes of dithering, this implies that if our
+// desired color is inside any simplex at all, then we can replace one of the corners with whatever palette color
+// is closest to our desired color. Moreover, this substitution can only decrease the size of the simplex
+// according to our metric. Therefore, we can safely assume that one of the corners of our simplex will be the
+// nearest palette color, and we only need to search for 3 other corners, not 4. This reduces the worst-case
+// runtime of our naive search from O(n^4) to O(n^3).
+//
+// There are definitely faster ways of performing this search - for example, an online convex hull algorithm
+// on the surface of a sphere could be used to add palette points one by one until a simplex is found, then
+// tear down point by point to determine the remaining corners. However, I haven't put much effort into optimizing
+// yet, and this code is already quite complicated due to all the edge cases. Moreover, due to the complexity
+// of CIEDE2000, much of the runtime seems to be consumed in color distance calculations. Avoiding doing the
+// calculation between every pixel and every palette color, possibly with some sort of spatial data structure,
+// would seem more prudent and effective. TODO: Optimize!
+pub fn tight_simplex(pixel: LinearRgb, palette: &[Srgb8], linear_palette: &[LinearRgb], lab_palette: &[Lab], bias: f64, distance2: fn(Lab, Lab) -> f64) -> Srgb8 {
+    tight_simplex_projected(pixel, palette, linear_palette, lab_palette, bias, distance2).0
+}
+
+// The full simplex dithering algorithm, which also returns the squared distance between the pixel and the color that the
+// chosen palette colors average to. This is zero unless the pixel is outside of the gamut of the palette and had to be
+// projected onto its surface.
+pub fn tight_simplex_projected(linear_pixel: LinearRgb, palette: &[Srgb8], linear_palette: &[LinearRgb], lab_palette: &[Lab], bias: f64, distance2: fn(Lab, Lab) -> f64) -> (Srgb8, f64) {
+    let lab_pixel = Lab::from(linear_pixel);
+
+    // The palette, sorted by distance from the pixel. We cache the distance, linear color, and luma and also store a flag
+    // to indicate when we know for certain that a color will be useless.
+    let mut trans_palette: Vec<_> = palette.iter().cloned().enumerate().map(|(i, rgb8)| {
+        let linear = subtract(linear_palette[i], linear_pixel); // Shift to our pixel being at the origin, since this simplifies a good chunk of the math.
+        let lab = lab_palette[i];
+        let dist2 = distance2(lab_pixel, lab);
+        (rgb8, linear, dist2, false, lab.l)
+    }).collect();
+    trans_palette.sort_unstable_by(|&(_, _, d1, _, _), &(_, _, d2, _, _)| d1.partial_cmp(&d2).unwrap());
+
+    // Fast path that also avoids some of the most annoying edge cases: if we're on a palette color, just return that.
+    if trans_palette[0].2 < 1e-20 {
+        return (trans_palette[0].0, 0.0);
+    }
+
+    'outer_loop:
+    for index3 in 3..trans_palette.len() {
+        for index2 in 2..index3 {
+            if trans_palette[index2].3 { continue; } // Skip known-useless colors.
+            for index1 in 1..index2 {
+                if trans_palette[index1].3 { continue; }
+                let index0 = 0;
+
+                // Calculate the signed volumes of the simplices with our pixel and 3 other points.
+                // If all of these have the same sign, then we are inside the simplex.
+                let points = [
+                    trans_palette[index0].1,
+                    trans_palette[index1].1,
+                    trans_palette[index2].1,
+                    trans_palette[index3].1,
+                ];
+                let d0 = determinant([points[1], points[3], points[2]]);
+                let d1 = determinant([points[0], points[2], points[3]]);
+                let d2 = determinant([points[0], points[3], points[1]]);
+                let d3 = determinant([points[0], points[1], points[2]]);
+                let d_all = d0 + d1 + d2 + d3;
+
+                // Handle degenerate cases separately
+                if d3.abs() < 1e-15 || d2.abs() < 1e-15 || d1.abs() < 1e-15 || d0.abs() < 1e-15 {//d_all.abs() < 1e-15 {
+                    //eprintln!("  Degeneracy ({},{},{},{})! d_all={}, d0={}, d1={}, d2={}, d3={}", index0, index1, index2, index3, d_all, d0, d1, d2, d3);
+                    for &face in &[[0,1,2],[0,1,3],[0,2,3],[1,2,3]] {
+                        let face_points = [points[face[0]], points[face[1]], points[face[2]]];
+                        let det = determinant(face_points);
+                        if det.abs() < 1e-12 {
+                            let vec_01 = face_points[1] - face_points[0];
+                            let vec_02 = face_points[2] - face_points[0];
+                            let normal = vec_01.cross(vec_02);
+                            let size2 = normal.dot(normal);
+                            //eprintln!("    Face ({},{},{}) has determinant {}, size2 {}", face[0], face[1], face[2], det, size2);
+                            if size2 > 1e-18 {
+                                let coeff_0 = normal.dot(face_points[1].cross(face_points[2]));
+                                let coeff_1 = normal.dot(face_points[2].cross(face_points[0]));
+                                let coeff_2 = normal.dot(face_points[0].cross(face_points[1]));
+                                //eprintln!("    Valid triangle: {},{},{}", coeff_0, coeff_1, coeff_2);
+
+                                if coeff_0 >= -1e-15 && coeff_1 >= -1e-15 && coeff_2 >= -1e-15 {
+                                    //eprintln!("      Success!");
+                                    let indexes = [index0, index1, index2, index3];
+                                    let mut simplex = [(coeff_0, face[0]), (coeff_1, face[1]), (coeff_2, face[2])];
+                                    simplex.sort_unstable_by(|&(_, f1), &(_, f2)| trans_palette[indexes[f1]].4.partial_cmp(&trans_palette[indexes[f2]].4).unwrap());
+                                    if bias <= simplex[0].0 / size2 {
+                                        return (trans_palette[indexes[simplex[0].1]].0, 0.0);
+                                    } else if bias - simplex[0].0 / size2 <= simplex[1].0 / size2 {
+                                        return (trans_palette[indexes[simplex[1].1]].0, 0.0);
+                                    } else {
+                                        return (trans_palette[indexes[simplex[2].1]].0, 0.0);
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    for &edge in &[[0,1], [0,2], [0,3], [1,2], [1,3], [2,3]] {
+                        let edge_points = [points[edge[0]], points[edge[1]]];
+                        let vec_01 = edge_points[1] - edge_points[0];
+                        let vec_0p = -edge_points[0];
+                        let normal = vec_01.cross(vec_0p);
+                        if normal.dot(normal) < 1e-15 {
+                            let len2 = vec_01.dot(vec_01);
+                            let partial = vec_0p.dot(vec_01);
+                            if partial >= 0.0 && partial <= len2 {
+                                let indexes = [index0, index1, index2, index3];
+                                let mut simplex = [(partial / len2, edge[0]), (1.0 - partial / len2, edge[1])];
+                                simplex.sort_unstable_by(|&(_, f1), &(_, f2)| trans_palette[indexes[f1]].4.partial_cmp(&trans_palette[indexes[f2]].4).unwrap());
+                                if bias <= simplex[0].0 {
+                                    return (trans_palette[indexes[simplex[0].1]].0, 0.0);
+                                } else {
+                                    return (trans_palette[indexes[simplex[1].1]].0, 0.0);
+                                }
+                            }
+                        }
+                    }
+                } else if d_all.signum() == d0.signum() &&
+                          d_all.signum() == d1.signum() &&
+                          d_all.signum() == d2.signum() &&
+                          d_all.signum() == d3.signum() {
+
+                    // We're inside the simplex! 
+                    let mut simplex = [(d0, index0), (d1, index1), (d2, index2), (d3, index3)];
+                    simplex.sort_unstable_by(|&(_, i1), &(_, i2)| trans_palette[i1].4.partial_cmp(&trans_palette[i2].4).unwrap());
+
+                    let mut bias_left = bias;
+                    if bias_left <= simplex[0].0 / d_all {
+                        return (trans_palette[simplex[0].1].0, 0.0);
+                    } else {
+                        bias_left -= simplex[0].0 / d_all;
+                    }
+                    if bias_left <= simplex[1].0 / d_all {
+                        return (trans_palette[simplex[1].1].0, 0.0);
+                    } else {
+                        bias_left -= simplex[1].0 / d_all;
+                    }
+                    if bias_left <= simplex[2].0 / d_all {
+                        return (trans_palette[simplex[2].1].0, 0.0);
+                    } else {
+                        return (trans_palette[simplex[3].1].0, 0.0);
+                    }
+                } else if d0.signum() == d1.signum() &&
+                          d0.signum() == d2.signum() {
+                    trans_palette[index3].3 = true;
+                    continue 'outer_loop;
+                }
+            }
+        }
+    }
+
+    // No simplex contains our point. Therefore, search for a triangle we can project onto or a line segment or the closest point (whichever allows
+    // us to project to the closest point).
+
+    // Start with the closest point
+    let mut best_sample = trans_palette[0].0;
+    let mut best_dist2 = trans_palette[0].2;
+
+    // FIXME: The following code does orthogonal projection in linear space, which is wrong. We want the plane/line defined by linear space, but we want the
+    // CIEDE-closest point. As an approximation, we try to make something that looks a bit more like LAB but is a linear transformation.
+    let plab_pixel = PseudoLab::from(linear_pixel);
+
+    // Try to beat it with a line segment
+    for index2 in 1..trans_palette.len() {
+        for index1 in 0..index2 {
+            // TODO: just scale directly
+            let point_1 = PseudoLab::from(trans_palette[index1].1 + linear_pixel);
+            let point_2 = PseudoLab::from(trans_palette[index2].1 + linear_pixel);
+
+            // Project our point onto the line segment
+            let vec_12 = subtract(point_2, point_1);
+            let vec_1p = subtract(plab_pixel, point_1);
+            let mag2_12 = vec_12.dot(vec_12);
+            let proj_1p = vec_12 * (vec_1p.dot(vec_12) / mag2_12);
+            let projected = proj_1p + point_1;
+
+            // Test whether we are actually in the segment, not elsewhere on the line
+            let offset_mag = proj_1p.dot(vec_12);
+            //eprintln!("Line: {}", offset_mag / mag2_12);
+            if offset_mag >= 0.0 && offset_mag <= mag2_12 {
+                // Only consider points that are better than the best seen
+                let lab_projected = Lab::from(projected);
+                let dist2 = distance2(lab_projected, lab_pixel);
+                //eprintln!(" Projected: {:?}", LinearRgb::from(projected).data);
+                //eprintln!(" Dist: {}, Point: {}", dist2, best_dist2);
+                if dist2 < best_dist2 {
+                    // Ensure our palette is sorted
+                    let bias_shifted = if trans_palette[index1].4 < trans_palette[index2].4 {
+                        1.0 - bias
+                    } else {
+                        bias
+                    };
+
+                    // Select between the two points
+                    if bias_shifted * mag2_12 <= offset_mag {
+                        best_sample = trans_palette[index2].0;
+                    } else {
+                        best_sample = trans_palette[index1].0;
+                    }
+                    best_dist2 = dist2;
+                }
+            }
+        }
+    }
+
+    // Try to beat it with a triangle
+    for index3 in 2..trans_palette.len() {
+        for index2 in 1..index3 {
+            for index1 in 0..index2 {
+                let point_1 = PseudoLab::from(trans_palette[index1].1 + linear_pixel);
+                let point_2 = PseudoLab::from(trans_palette[index2].1 + linear_pixel);
+                let point_3 = PseudoLab::from(trans_palette[index3].1 + linear_pixel);
+
+                // Project onto the plane
+                let vec_12 = subtract(point_2, point_1);
+                let vec_13 = subtract(point_3, point_1);
+                let vec_1p = subtract(plab_pixel, point_1);
+                let normal = vec_12.cross(vec_13);
+                let mag2_normal = normal.dot(normal); // TODO: What happens if this is 0?
+                let offset = normal * (vec_1p.dot(normal) / mag2_normal); // Do the projection by projecting onto the normal and subtracting
+                let projected = (-offset) + plab_pixel;
+                let lab_projected = Lab::from(projected);
+                let dist2 = distance2(lab_projected, lab_pixel);
+                if dist2 < best_dist2 {
+                    let proj_p1 = offset - vec_1p;
+                    let proj_p2 = proj_p1 + vec_12;
+                    let proj_p3 = proj_p1 + vec_13;
+
+                    let d_area_all = mag2_normal.sqrt();
+                    let normal_23 = proj_p2.cross(proj_p3);
+                    let d_area_23 = normal_23.dot(normal_23).sqrt();
+                    let normal_13 = proj_p1.cross(proj_p3);
+                    let d_area_13 = normal_13.dot(normal_13).sqrt();
+
+                    let coord1 = d_area_23 / d_area_all;
+                    let coord2 = d_area_13 / d_area_all;
+                    let coord3 = 1.0 - coord1 - coord2;
+
+                    if coord1 >= 0.0 &&
+                       coord2 >= 0.0 &&
+                       coord3 >= 0.0 {
+
+                        let mut simplex = [(coord1, index1), (coord2, index2), (coord3, index3)];
+                        simplex.sort_unstable_by(|&(_, i1), &(_, i2)| trans_palette[i1].4.partial_cmp(&trans_palette[i2].4).unwrap());
+
+                        let mut bias_left = bias;
+                        if bias_left <= simplex[0].0 {
+                            best_sample = trans_palette[simplex[0].1].0;
+                        } else {
+                            bias_left -= simplex[0].0;
+                            if bias_left <= simplex[1].0 {
+                                best_sample = trans_palette[simplex[1].1].0;
+                            } else {
+                                best_sample = trans_palette[simplex[2].1].0;
+                            }
+                        }
+
+                        best_dist2 = dist2;
+                    }
+                }
+            }
+        }
+    }
+
+    (best_sample, best_dist2)
+}
+
+// Projection errors smaller than this are only rounding, from pixels that lie on the surface of the gamut
+pub const GAMUT_TOLERANCE: f64 = 1e-6;
+
+// Runs the simplex search on every pixel of the image without actually dithering, returning how far each pixel (in
+// row-major order) is from the gamut of the palette, using the same distance function. This is zero for every pixel that
+// can be reproduced exactly on average and positive for those that fall back to projection.
+pub fn gamut_errors(img: &LinearImage, palette: &[Srgb8], linear_palette: &[LinearRgb], lab_palette: &[Lab], distance2: fn(Lab, Lab) -> f64) -> Vec<f64> {
+    img.pixels.par_iter().map(|&pixel| {
+        tight_simplex_projected(pixel, palette, linear_palette, lab_palette, 0.5, distance2).1.sqrt()
+    }).collect()
+}
+
+pub fn visualize_bias(_pixel: LinearRgb, _palette: &[Srgb8], _linear_palette: &[LinearRgb], _lab_palette: &[Lab], bias: f64, _distance2: fn(Lab, Lab) -> f64) -> Srgb8 {
+    Srgb8::from(LinearRgb { data: [bias, bias, bias] })
+}
@@ -0,0 +1,239 @@
+use crate::color::Srgb8;
+use crate::files;
+use crate::palettes::PaletteOutput;
+
+use std::ffi::OsStr;
+use std::fmt::Write as _;
+
+// Writing dithered images as the raw bytes that embedded displays and framebuffers take, or as a C header with those
+// bytes in an array, ready to compile into firmware.
+
+pub const RAW_FORMATS: [&str; 6] = ["rgb565le", "rgb332", "gray4", "mono-hlsb", "mono-vlsb", "indexed8"];
+
+#[derive(Copy, Clone)]
+pub enum RawFormat {
+    // 16 bits per pixel, little endian, with red in the top 5 bits
+    Rgb565Le,
+    // 8 bits per pixel, with red in the top 3 bits and blue in the bottom 2
+    Rgb332,
+    // 4-bit grayscale, two pixels per byte with the left one in the high nibble
+    Gray4,
+    // 1 bit per pixel, with each byte holding 8 pixels of a row, leftmost in the most significant bit
+    MonoHlsb,
+    // 1 bit per pixel, with each byte holding 8 pixels of a column, topmost in the least significant bit, as used by
+    // SSD1306-style displays
+    MonoVlsb,
+    // One byte per pixel, giving its index in the palette
+    Indexed8,
+}
+
+pub enum Export {
+    Raw(RawFormat),
+    CHeader(RawFormat),
+}
+
+pub fn parse_raw_format(name: &str) -> RawFormat {
+    match name {
+        "rgb565le" => RawFormat::Rgb565Le,
+        "rgb332" => RawFormat::Rgb332,
+        "gray4" => RawFormat::Gray4,
+        "mono-hlsb" => RawFormat::MonoHlsb,
+        "mono-vlsb" => RawFormat::MonoVlsb,
+        "indexed8" => RawFormat::Indexed8,
+        _ => panic!("Unsupported raw format {:?}! The options are {}.", name, RAW_FORMATS.join(", ")),
+    }
+}
+
+// Parses the value of --export: `raw:FORMAT`, or `c-header` optionally followed by `:FORMAT` (indexed8 by default)
+pub fn parse(spec: &str) -> Export {
+    match spec.split_once(':') {
+        Some(("raw", format)) => Export::Raw(parse_raw_format(format)),
+        Some(("c-header", format)) => Export::CHeader(parse_raw_format(format)),
+        None if spec == "c-header" => Export::CHeader(RawFormat::Indexed8),
+        _ => panic!("Unsupported export {:?}! Use raw:FORMAT, c-header or c-header:FORMAT.", spec),
+    }
+}
+
+// Rounds an 8-bit channel value to the given number of bits
+fn reduce(value: u8, bits: u32) -> u16 {
+    let max = (1u32 << bits) - 1;
+    ((value as u32 * max + 127) / 255) as u16
+}
+
+// The brightness of a pixel for the grayscale and monochrome formats, from the stored values as displays would use them
+fn luma(pixel: Srgb8) -> u8 {
+    let [r, g, b] = pixel.0;
+    ((r as u32 * 299 + g as u32 * 587 + b as u32 * 114 + 500) / 1000) as u8
+}
+
+// The palette's colors laid out by index, with black filling any gaps, or None if the indices don't fit in a byte
+fn palette_table(output: &PaletteOutput) -> Option<Vec<Srgb8>> {
+    let size = output.indices.iter().max().map_or(0, |&index| index + 1);
+    if size > 256 {
+        return None;
+    }
+    let mut table = vec![image::Rgb([0, 0, 0]); size];
+    for (&index, &color) in output.indices.iter().zip(&output.colors).rev() {
+        table[index] = color;
+    }
+    Some(table)
+}
+
+// The index of the palette entry chosen for each pixel, which has to fit in a byte. Entries can share a written color,
+// so this goes by the entry's position in the palette rather than by color.
+fn indices(positions: &[usize], output: &PaletteOutput) -> Vec<u8> {
+    if palette_table(output).is_none() {
+        panic!("Indexed exports need palette indices below 256, but this palette has {} colors!", output.colors.len());
+    }
+    positions.iter().map(|&position| output.indices[position] as u8).collect()
+}
+
+// Packs the written colors of an image into the given format. `positions` gives the palette position of each pixel, in
+// row-major order, for the indexed format.
+pub fn pack(img: &image::RgbImage, positions: &[usize], output: &PaletteOutput, format: RawFormat) -> Vec<u8> {
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    match format {
+        RawFormat::Rgb565Le => img.pixels().flat_map(|pixel| {
+            let [r, g, b] = pixel.0;
+            ((reduce(r, 5) << 11) | (reduce(g, 6) << 5) | reduce(b, 5)).to_le_bytes()
+        }).collect(),
+        RawFormat::Rgb332 => img.pixels().map(|pixel| {
+            let [r, g, b] = pixel.0;
+            ((reduce(r, 3) << 5) | (reduce(g, 3) << 2) | reduce(b, 2)) as u8
+        }).collect(),
+        RawFormat::Gray4 => {
+            let row_bytes = width.div_ceil(2);
+            let mut data = vec![0; row_bytes * height];
+            for (x, y, &pixel) in img.enumerate_pixels() {
+                let (x, y) = (x as usize, y as usize);
+                data[y * row_bytes + x / 2] |= (reduce(luma(pixel), 4) as u8) << (4 - 4 * (x % 2));
+            }
+            data
+        },
+        RawFormat::MonoHlsb => {
+            let row_bytes = width.div_ceil(8);
+            let mut data = vec![0; row_bytes * height];
+            for (x, y, _) in img.enumerate_pixels().filter(|&(_, _, &pixel)| luma(pixel) >= 128) {
+                data[y as usize * row_bytes + x as usize / 8] |= 0x80 >> (x % 8);
+            }
+            data
+        },
+        RawFormat::MonoVlsb => {
+            let mut data = vec![0; width * height.div_ceil(8)];
+            for (x, y, _) in img.enumerate_pixels().filter(|&(_, _, &pixel)| luma(pixel) >= 128) {
+                data[y as usize / 8 * width + x as usize] |= 1 << (y % 8);
+            }
+            data
+        },
+        RawFormat::Indexed8 => indices(positions, output),
+    }
+}
+
+// Turns a file name into something usable as a C identifier, for naming the arrays
+fn identifier(name: &OsStr) -> String {
+    let stem = std::path::Path::new(name).file_stem().map_or("image".into(), |stem| stem.to_string_lossy());
+    let mut identifier: String = stem.chars().map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' }).collect();
+    if identifier.is_empty() || identifier.starts_with(|c: char| c.is_ascii_digit()) || files::is_stdio(name) {
+        identifier.insert_str(0, "image_");
+    }
+    identifier.trim_end_matches('_').to_string()
+}
+
+pub fn c_header(img: &image::RgbImage, positions: &[usize], output: &PaletteOutput, format: RawFormat, name: &str) -> String {
+    let data = pack(img, positions, output, format);
+    let upper = name.to_ascii_uppercase();
+    let mut header = String::new();
+    writeln!(header, "#ifndef {}_H", upper).unwrap();
+    writeln!(header, "#define {}_H", upper).unwrap();
+    writeln!(header).unwrap();
+    writeln!(header, "#include <stdint.h>").unwrap();
+    writeln!(header).unwrap();
+    writeln!(header, "#define {}_WIDTH {}", upper, img.width()).unwrap();
+    writeln!(header, "#define {}_HEIGHT {}", upper, img.height()).unwrap();
+    writeln!(header).unwrap();
+
+    // Only indexed data refers to the palette. The other formats hold the colors themselves.
+    if let (RawFormat::Indexed8, Some(palette)) = (format, palette_table(output)) {
+        writeln!(header, "#define {}_PALETTE_SIZE {}", upper, palette.len()).unwrap();
+        writeln!(header, "static const uint8_t {}_palette[{}][3] = {{", name, palette.len()).unwrap();
+        for color in &palette {
+            writeln!(header, "    {{ 0x{:02x}, 0x{:02x}, 0x{:02x} }},", color.0[0], color.0[1], color.0[2]).unwrap();
+        }
+        writeln!(header, "}};").unwrap();
+        writeln!(header).unwrap();
+    }
+
+    writeln!(header, "static const uint8_t {}_data[{}] = {{", name, data.len()).unwrap();
+    for line in data.chunks(16) {
+        let bytes: Vec<String> = line.iter().map(|byte| format!("0x{:02x}", byte)).collect();
+        writeln!(header, "    {},", bytes.join(", ")).unwrap();
+    }
+    writeln!(header, "}};").unwrap();
+    writeln!(header).unwrap();
+    writeln!(header, "#endif").unwrap();
+    header
+}
+
+pub fn save(export: &Export, img: &image::RgbImage, positions: &[usize], output: &PaletteOutput, name: &OsStr) {
+    match *export {
+        Export::Raw(format) => files::write(name, &pack(img, positions, output, format)),
+        Export::CHeader(format) => files::write(name, c_header(img, positions, output, format, &identifier(name)).as_bytes()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLACK: Srgb8 = image::Rgb([0, 0, 0]);
+    const WHITE: Srgb8 = image::Rgb([255, 255, 255]);
+
+    // Packs a row-major list of pixels, each written as it is
+    fn pack_pixels(width: u32, height: u32, pixels: &[Srgb8], format: RawFormat) -> Vec<u8> {
+        let img = image::RgbImage::from_fn(width, height, |x, y| pixels[(y * width + x) as usize]);
+        let mut palette = pixels.to_vec();
+        palette.sort_unstable_by_key(|color| color.0);
+        palette.dedup();
+        let positions: Vec<usize> = pixels.iter().map(|&pixel| palette.iter().position(|&color| color == pixel).unwrap()).collect();
+        pack(&img, &positions, &PaletteOutput::identity(&palette), format)
+    }
+
+    #[test]
+    fn mono_hlsb_puts_the_leftmost_pixel_in_the_top_bit() {
+        // 10 pixels wide, so the row takes two bytes, with the second padded out
+        let mut pixels = vec![BLACK; 10];
+        pixels[0] = WHITE;
+        pixels[9] = WHITE;
+        assert_eq!(pack_pixels(10, 1, &pixels, RawFormat::MonoHlsb), [0x80, 0x40]);
+    }
+
+    #[test]
+    fn mono_vlsb_puts_the_topmost_pixel_in_the_bottom_bit() {
+        // 2 pixels wide and 10 high, so there are two bands of 8 rows, each a byte per column
+        let mut pixels = vec![BLACK; 20];
+        pixels[0] = WHITE; // (0, 0)
+        pixels[3] = WHITE; // (1, 1)
+        pixels[18] = WHITE; // (0, 9)
+        pixels[17] = WHITE; // (1, 8)
+        assert_eq!(pack_pixels(2, 10, &pixels, RawFormat::MonoVlsb), [0x01, 0x02, 0x02, 0x01]);
+    }
+
+    #[test]
+    fn gray4_puts_the_left_pixel_in_the_high_nibble() {
+        let gray = image::Rgb([0x88, 0x88, 0x88]);
+        assert_eq!(pack_pixels(3, 1, &[gray, BLACK, WHITE], RawFormat::Gray4), [0x80, 0xf0]);
+    }
+
+    #[test]
+    fn rgb565le_is_little_endian_with_red_on_top() {
+        let pixels = [image::Rgb([255, 0, 0]), image::Rgb([0, 255, 0]), image::Rgb([0, 0, 255])];
+        assert_eq!(pack_pixels(3, 1, &pixels, RawFormat::Rgb565Le), [0x00, 0xf8, 0xe0, 0x07, 0x1f, 0x00]);
+    }
+
+    #[test]
+    fn indexed8_keeps_the_index_of_entries_with_the_same_written_color() {
+        let img = image::RgbImage::from_pixel(2, 1, BLACK);
+        let output = PaletteOutput { colors: vec![BLACK, BLACK], indices: vec![3, 7] };
+        assert_eq!(pack(&img, &[1, 0], &output, RawFormat::Indexed8), [7, 3]);
+    }
+}
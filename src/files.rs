@@ -0,0 +1,131 @@
+use crate::animation::Animation;
+use crate::color::{self, LinearImage, ColorSpace};
+use crate::profiles;
+
+use std::ffi::OsStr;
+use std::io::{Read, Write};
+
+// Reading and writing images, where a file name of `-` means stdin or stdout so that we can sit in a pipeline.
+
+pub const OUTPUT_FORMATS: [&str; 5] = ["png", "gif", "bmp", "ppm", "tga"];
+
+pub fn is_stdio(name: &OsStr) -> bool {
+    name == "-"
+}
+
+// An input file, read entirely into memory. This lets us detect the format from the contents, which is the only option
+// for stdin, and try decoding it both as an animation and as an ordinary image.
+pub struct InputFile {
+    data: Vec<u8>,
+    format: image::ImageFormat,
+}
+
+impl InputFile {
+    pub fn read(name: &OsStr) -> InputFile {
+        let data = if is_stdio(name) {
+            let mut data = Vec::new();
+            std::io::stdin().lock().read_to_end(&mut data).unwrap();
+            data
+        } else {
+            std::fs::read(name).unwrap()
+        };
+
+        // Some formats (like TGA) have no magic number, so fall back on the extension for those
+        let format = match image::guess_format(&data) {
+            Ok(format) => format,
+            Err(_) => match image::ImageFormat::from_path(name) {
+                Ok(format) => format,
+                Err(_) => panic!("Could not tell what format {:?} is in!", name),
+            },
+        };
+
+        InputFile { data, format }
+    }
+
+    pub fn animation(&self) -> Option<Animation> {
+        Animation::decode(&self.data, self.format)
+    }
+
+    // Decodes the file as a still image, at whatever precision it was stored in, and converts it to the working space
+    pub fn image(&self) -> LinearImage {
+        LinearImage::from_dynamic(self.decode(), &self.space())
+    }
+
+    // The same, keeping colors outside the working space's gamut for the adjustments to bring back into range
+    pub fn unclipped_image(&self) -> LinearImage {
+        LinearImage::from_dynamic_unclipped(self.decode(), &self.space())
+    }
+
+    fn decode(&self) -> image::DynamicImage {
+        image::load_from_memory_with_format(&self.data, self.format).unwrap()
+    }
+
+    fn space(&self) -> ColorSpace {
+        match self.format {
+            image::ImageFormat::Png => profiles::detect_png(&self.data),
+            _ => ColorSpace::SRGB,
+        }
+    }
+}
+
+// Parses the value of --format, which is one of OUTPUT_FORMATS
+pub fn parse_format(format: &str) -> image::ImageFormat {
+    match format {
+        "png" => image::ImageFormat::Png,
+        "gif" => image::ImageFormat::Gif,
+        "bmp" => image::ImageFormat::Bmp,
+        "ppm" => image::ImageFormat::Pnm,
+        "tga" => image::ImageFormat::Tga,
+        _ => panic!("Unsupported output format {:?}!", format),
+    }
+}
+
+pub fn write(name: &OsStr, data: &[u8]) {
+    if is_stdio(name) {
+        std::io::stdout().lock().write_all(data).unwrap();
+    } else {
+        std::fs::write(name, data).unwrap();
+    }
+}
+
+// Writes an image in the given format. Without one, files are written in whatever format their extension suggests,
+// and stdout gets a PNG. Outside of sRGB, PNGs are tagged with the working space; other formats have no way to say
+// what space they are in, so viewers will take them to be sRGB.
+pub fn save_image(img: &image::RgbImage, name: &OsStr, format: Option<image::ImageFormat>) {
+    if color::working_space() != ColorSpace::SRGB {
+        let format = format.or_else(|| image::ImageFormat::from_path(name).ok()).unwrap_or(image::ImageFormat::Png);
+        if format == image::ImageFormat::Png {
+            write(name, &profiles::encode_png(img));
+            return;
+        }
+        eprintln!("Warning: {:?} files can't record the color space, so viewers will assume the output is sRGB", format);
+    }
+
+    if format.is_none() && !is_stdio(name) {
+        img.save(name).unwrap();
+        return;
+    }
+
+    let output_format = match format.unwrap_or(image::ImageFormat::Png) {
+        // Match the extension-based choice, rather than the PAM files `image` picks by default
+        image::ImageFormat::Pnm => image::ImageOutputFormat::Pnm(image::codecs::pnm::PnmSubtype::Pixmap(image::codecs::pnm::SampleEncoding::Binary)),
+        format => format.into(),
+    };
+    let mut data = std::io::Cursor::new(Vec::new());
+    img.write_to(&mut data, output_format).unwrap();
+    write(name, &data.into_inner());
+}
+
+// The same for animations, which can only be written as GIFs or APNGs
+pub fn save_animation(animation: &Animation, name: &OsStr, format: Option<image::ImageFormat>) {
+    let format = match format {
+        Some(format) => format,
+        None if is_stdio(name) => image::ImageFormat::Png,
+        None => image::ImageFormat::from_path(name).unwrap_or_else(|_| panic!("Animations can only be written as GIF or PNG files!")),
+    };
+    let mut data = Vec::new();
+    if let Err(message) = animation.write(&mut data, Some(format)) {
+        panic!("{}", message);
+    }
+    write(name, &data);
+}
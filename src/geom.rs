@@ -90,3 +90,145 @@ pub fn midpoint<P: Affine3>(p1: P, p2: P) -> P {
         (p1_coords[2] + p2_coords[2]) * 0.5,
     ])
 }
+
+// A 3x3 matrix, stored as a list of rows, for converting between color spaces
+pub type Matrix3 = [[f64; 3]; 3];
+
+pub fn diagonal(values: [f64; 3]) -> Matrix3 {
+    [[values[0], 0.0, 0.0], [0.0, values[1], 0.0], [0.0, 0.0, values[2]]]
+}
+
+pub fn transform(matrix: &Matrix3, vector: [f64; 3]) -> [f64; 3] {
+    [
+        matrix[0][0] * vector[0] + matrix[0][1] * vector[1] + matrix[0][2] * vector[2],
+        matrix[1][0] * vector[0] + matrix[1][1] * vector[1] + matrix[1][2] * vector[2],
+        matrix[2][0] * vector[0] + matrix[2][1] * vector[1] + matrix[2][2] * vector[2],
+    ]
+}
+
+pub fn multiply(lhs: &Matrix3, rhs: &Matrix3) -> Matrix3 {
+    let mut product = [[0.0; 3]; 3];
+    for (i, row) in product.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| lhs[i][k] * rhs[k][j]).sum();
+        }
+    }
+    product
+}
+
+// Inverts a matrix using its adjugate, panicking if it is singular
+pub fn invert(matrix: &Matrix3) -> Matrix3 {
+    let m = matrix;
+    let cofactor = |i: usize, j: usize| {
+        let (r0, r1) = ((i + 1) % 3, (i + 2) % 3);
+        let (c0, c1) = ((j + 1) % 3, (j + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let det = m[0][0] * cofactor(0, 0) + m[0][1] * cofactor(0, 1) + m[0][2] * cofactor(0, 2);
+    assert!(det.abs() > 1e-12, "Tried to invert a singular matrix!");
+    let mut inverse = [[0.0; 3]; 3];
+    for (i, row) in inverse.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = cofactor(j, i) / det;
+        }
+    }
+    inverse
+}
+
+// The convex hull of a set of points, as a list of triangles whose vertices are ordered counterclockwise when viewed from
+// outside. This is built incrementally, adding points one at a time and replacing whatever faces they can see. That is
+// quadratic in the worst case, but palettes are small and most points are either inside or coplanar with the hull.
+pub struct ConvexHull<P> {
+    pub points: Vec<P>,
+    pub faces: Vec<[usize; 3]>,
+}
+
+impl<P: Affine3 + Copy> ConvexHull<P> {
+    // Returns None if the points are all coplanar, as there is no solid hull to speak of.
+    pub fn new(points: &[P]) -> Option<ConvexHull<P>> {
+        const EPSILON: f64 = 1e-12;
+
+        if points.is_empty() {
+            return None;
+        }
+
+        // Pick an initial tetrahedron that is as large as possible, to avoid precision issues
+        fn farthest(values: impl Iterator<Item=f64>) -> (usize, f64) {
+            values.enumerate().fold((0, 0.0), |(i1, d1), (i2, d2)| if d2 > d1 { (i2, d2) } else { (i1, d1) })
+        }
+        let i0 = 0;
+        let (i1, _) = farthest(points.iter().map(|&p| {
+            let offset = subtract(p, points[i0]);
+            offset.dot(offset)
+        }));
+        let axis = subtract(points[i1], points[i0]);
+        let (i2, area2) = farthest(points.iter().map(|&p| {
+            let normal = axis.cross(subtract(p, points[i0]));
+            normal.dot(normal)
+        }));
+        if area2 < EPSILON {
+            return None;
+        }
+        let normal = axis.cross(subtract(points[i2], points[i0]));
+        let (i3, volume) = farthest(points.iter().map(|&p| normal.dot(subtract(p, points[i0])).abs()));
+        if volume < EPSILON {
+            return None;
+        }
+
+        let mut hull = ConvexHull {
+            points: points.to_vec(),
+            faces: if normal.dot(subtract(points[i3], points[i0])) > 0.0 {
+                vec![[i0, i2, i1], [i0, i1, i3], [i1, i2, i3], [i2, i0, i3]]
+            } else {
+                vec![[i0, i1, i2], [i0, i3, i1], [i1, i3, i2], [i2, i3, i0]]
+            }
+        };
+
+        for (i, &point) in points.iter().enumerate() {
+            let visible: Vec<bool> = hull.faces.iter().map(|&face| hull.height(face, point) > EPSILON).collect();
+            if !visible.iter().any(|&v| v) {
+                continue;
+            }
+
+            // The horizon consists of the edges of visible faces that are not shared with another visible face
+            let visible_edges: Vec<[usize; 2]> = hull.faces.iter().zip(&visible).filter(|&(_, &v)| v).flat_map(|(face, _)| {
+                vec![[face[0], face[1]], [face[1], face[2]], [face[2], face[0]]]
+            }).collect();
+            let horizon: Vec<[usize; 2]> = visible_edges.iter().cloned().filter(|&[a, b]| !visible_edges.contains(&[b, a])).collect();
+
+            let mut visible_iter = visible.iter();
+            hull.faces.retain(|_| !*visible_iter.next().unwrap());
+            for [a, b] in horizon {
+                hull.faces.push([a, b, i]);
+            }
+        }
+
+        Some(hull)
+    }
+
+    // The outward normal of a face, scaled by twice its area
+    pub fn normal(&self, face: [usize; 3]) -> Vec3<P> {
+        subtract(self.points[face[1]], self.points[face[0]]).cross(subtract(self.points[face[2]], self.points[face[0]]))
+    }
+
+    // How far above the plane of a face a point is, in units of the normal's length
+    fn height(&self, face: [usize; 3], point: P) -> f64 {
+        self.normal(face).dot(subtract(point, self.points[face[0]]))
+    }
+
+    // The largest distance from the point to the plane of any face it is outside of. This is zero inside the hull
+    // and otherwise a lower bound on (and usually equal to) the distance to the hull.
+    pub fn distance_outside(&self, point: P) -> f64 {
+        self.faces.iter().map(|&face| {
+            let normal = self.normal(face);
+            normal.dot(subtract(point, self.points[face[0]])) / normal.dot(normal).sqrt()
+        }).fold(0.0, f64::max)
+    }
+
+    // Every edge of the hull, listed once
+    pub fn edges(&self) -> Vec<[usize; 2]> {
+        self.faces.iter().flat_map(|face| vec![[face[0], face[1]], [face[1], face[2]], [face[2], face[0]]])
+                         .filter(|&[a, b]| a < b)
+                         .collect()
+    }
+}
@@ -2,512 +2,269 @@ extern crate clap;
 extern crate image;
 extern crate rayon;
 extern crate rand;
+extern crate glob;
 
 mod palettes;
 mod color;
 mod geom;
-
-use color::{Srgb8, LinearRgb, Lab, PseudoLab};
-use geom::{Vec3, determinant, subtract};
-
-use image::Pixel;
-use rand::Rng as _;
-use rayon::iter::{IntoParallelIterator, ParallelIterator, ParallelBridge};
-
-// Traditional Floyd-Steinberg dithering. Or it would be, except that everything is gamma-correct and using CIEDE2000, which seems to
-// result in some pretty drastic changes, or at least occasional failures that look significanty worse than any other implementation I've
-// seen. This may be the result of errors being done in floating point, which allows them to grow arbitrarily large and excessive, combined
-// with CIEDE2000 continuing to choose a value that doesn't correct for the error. Therefore, there is also a manual clamping that can be
-// enabled to force errors within a reasonable range. TODO: make color comparison configuable, and see if that fixes things
-fn floyd_steinberg(img: &mut image::RgbImage, palette: &[Srgb8], linear_palette: &[LinearRgb], lab_palette: &[Lab], serpentine: bool, clamp: bool, distance2: fn(Lab, Lab) -> f64) {
-    let select_color = |rgb: LinearRgb| -> usize {
-        let lab = Lab::from(rgb);
-        // Since error diffusion is mostly inherently serial, we parallelize the search for the nearest color
-        if lab_palette.len() < 250 { // TODO: don't just do an ad-hoc switch
-            (0..lab_palette.len()).map(|i| (i, distance2(lab, lab_palette[i])))
-                                  .fold((!0, core::f64::INFINITY), |(i1, d1), (i2, d2)| {
-                                      if d1 < d2 {
-                                          (i1, d1)
-                                      } else {
-                                          (i2, d2)
-                                      }
-                                  }).0
-        } else {
-            (0..lab_palette.len()).into_par_iter()
-                                  .map(|i| (i, distance2(lab, lab_palette[i])))
-                                  .reduce(|| (!0, core::f64::INFINITY), |(i1, d1), (i2, d2)| {
-                                      if d1 < d2 {
-                                          (i1, d1)
-                                      } else {
-                                          (i2, d2)
-                                      }
-                                  }).0
+mod bias;
+mod dither;
+mod preview;
+mod metrics;
+mod animation;
+mod batch;
+mod sweep;
+mod files;
+mod resize;
+mod adjust;
+mod profiles;
+mod analysis;
+mod separation;
+mod export;
+mod terminal;
+mod text;
+mod constrained;
+
+use color::{LinearRgb, LinearImage, Lab};
+use dither::Algorithm;
+
+// Print every registered name of the given kind (or of every kind), one per line, along with a short description.
+fn list(kind: Option<&str>) {
+    fn print_table(title: &str, rows: Vec<(String, String, &str)>) {
+        let name_width = rows.iter().map(|row| row.0.len()).max().unwrap_or(0);
+        let info_width = rows.iter().map(|row| row.1.len()).max().unwrap_or(0);
+        println!("{}:", title);
+        for (names, info, description) in rows {
+            if info_width == 0 {
+                println!("  {:name_width$}  {}", names, description, name_width = name_width);
+            } else {
+                println!("  {:name_width$}  {:info_width$}  {}", names, info, description, name_width = name_width, info_width = info_width);
+            }
         }
-    };
+    }
 
-    let do_clamp = |rgb: LinearRgb| if clamp { rgb.clamp() } else { rgb };
+    if kind.is_none() || kind == Some("palettes") {
+        print_table("Palettes", palettes::PALETTES.iter().map(|palette| {
+            let info = match palette.source {
+                palettes::PaletteSource::Fixed(build) => format!("{} colors", build().len()),
+                palettes::PaletteSource::Procedural(_) => "procedural".to_string(),
+                palettes::PaletteSource::Mapped(build) => format!("{} colors", build().len()),
+            };
+            (palette.names.join(", "), info, palette.description)
+        }).collect());
+    }
+    if kind.is_none() || kind == Some("algorithms") {
+        print_table("Algorithms", dither::ALGORITHMS.iter().map(|algorithm| {
+            let info = match algorithm.algorithm {
+                Algorithm::Ordered(_) => "ordered",
+                Algorithm::FloydSteinberg { .. } => "error diffusion",
+                Algorithm::PerChannel => "per channel",
+            };
+            (algorithm.names.join(", "), info.to_string(), algorithm.description)
+        }).collect());
+    }
+    if kind.is_none() || kind == Some("biases") {
+        print_table("Biases", bias::BIASES.iter().map(|bias| {
+            let info = match bias.source {
+                bias::BiasSource::Pattern(_) => "pattern",
+                bias::BiasSource::Screen(_) => "halftone screen",
+            };
+            (bias.names.join(", "), info.to_string(), bias.description)
+        }).collect());
+    }
+    if kind.is_none() || kind == Some("distances") {
+        print_table("Distances", color::DISTANCES.iter().map(|distance| (distance.names.join(", "), String::new(), distance.description)).collect());
+    }
+    if kind.is_none() || kind == Some("white-points") {
+        print_table("White points", color::WHITE_POINTS.iter().map(|white_point| {
+            (white_point.names.join(", "), format!("x={:.4} y={:.4}", white_point.white_point.x, white_point.white_point.y), white_point.description)
+        }).collect());
+    }
+    if kind.is_none() || kind == Some("color-spaces") {
+        print_table("Color spaces", color::COLOR_SPACES.iter().map(|space| (space.names.join(", "), String::new(), space.description)).collect());
+    }
+}
 
-    // At any point in the loop, this contains the error for the next row in positions 0..=x+1 and the error
-    // for the current row in the remaining positions
-    let mut row_error: Vec<Vec3<LinearRgb>> = core::iter::repeat(Vec3::zero()).take(img.width() as usize).collect();
-    for y in 0..img.height()-1 {
-        if y % 10 == 0 {
-            eprintln!("Processing line {}", y);
-        }
+// The arguments that choose how to dither, shared by the main command and `batch`
+fn dithering_args() -> [clap::Arg<'static>; 12] {
+    [
+        clap::Arg::new("PALETTE").short('p').long("palette").takes_value(true).default_value("simplex").help("Chooses the palette to quantize to"),
+        clap::Arg::new("PALETTE_SPACE").long("palette-space").takes_value(true).default_value("srgb").help("Sets the color space that a fixed palette's colors are given in"),
+        clap::Arg::new("CHANNELS").long("channels").takes_value(true).conflicts_with("PALETTE").help("Reduces each channel to a number of bits, like r5g6b5, dithering them separately unless an algorithm is given"),
+        clap::Arg::new("BOUNDING_PALETTE").long("bounding-palette").takes_value(true).conflicts_with_all(&["PALETTE", "CHANNELS"]).help("Builds a simplex palette inside the gamut of a fixed palette or palette file, rather than the image's own"),
+        clap::Arg::new("PALETTE_SIZE").short('c').long("colors").takes_value(true).default_value("16").help("How many colors to use in a procedural palette"),
+        clap::Arg::new("DISTANCE").short('d').long("distance").takes_value(true).default_value("CIEDE2000").help("Chooses how to calculate how far apart colors are"),
+        clap::Arg::new("BIAS").short('b').long("bias").takes_value(true).default_value("plastic+triangle").help("Chooses the bias pattern for ordered dithering algorithms"),
+        clap::Arg::new("ALGORITHM").short('a').long("algorithm").takes_value(true).default_value("simplex").help("Chooses the dithering algorithm to use"),
+        clap::Arg::new("PALETTE_ORDER").long("palette-order").takes_value(true).default_value("luma").possible_values(palettes::PALETTE_ORDERS).help("Sets the order of a procedural palette's colors: by luma, hue, usage, or the order they were created in"),
+        clap::Arg::new("BLOCK").long("block").takes_value(true).help("Limits the colors in each WxH block of pixels, like 8x8 for the ZX Spectrum"),
+        clap::Arg::new("BLOCK_COLORS").long("block-colors").takes_value(true).default_value("2").help("Sets how many colors each --block can use, counting shared ones"),
+        clap::Arg::new("SHARED_COLORS").long("shared-colors").takes_value(true).default_value("0").help("Sets how many of each --block's colors are shared by the whole image, picking the most used ones"),
+    ]
+}
 
-        // If serpentine is set, then we use the same algorithm, but traverse the arrays in the opposite order
-        // every other row.
-        let widthm1 = img.width() - 1;
-        let flip = |x| if serpentine && (y & 1) != 0 { widthm1 - x } else { x };
-
-        // Because row_error[x + 1] holds the next row's error, we need to save the current row's
-        // error so that it doesn't get overwritten.
-        let mut next_error = row_error[flip(0) as usize];
-
-        // Separate the left side: because we cannot send error off the image, send it downwards.
-        let first_target = do_clamp(next_error + LinearRgb::from(*img.get_pixel(flip(0), y)));
-        next_error = row_error[flip(1) as usize];
-        let first_selection = select_color(first_target);
-        let first_error = subtract(first_target, linear_palette[first_selection]);
-        *img.get_pixel_mut(flip(0), y) = palette[first_selection];
-        row_error[flip(0) as usize] = first_error * 0.5;
-        row_error[flip(1) as usize] = first_error * 0.0625;
-        next_error += first_error * 0.4375;
-
-        for x in 1..img.width()-1 {
-            let target = do_clamp(next_error + LinearRgb::from(*img.get_pixel(flip(x), y)));
-            next_error = row_error[flip(x + 1) as usize];
-            let selection = select_color(target);
-            let error = subtract(target, linear_palette[selection]);
-            *img.get_pixel_mut(flip(x), y) = palette[selection];
-            row_error[flip(x - 1) as usize] += error * 0.1875;
-            row_error[flip(x) as usize] += error * 0.3125;
-            row_error[flip(x + 1) as usize] = error * 0.0625;
-            next_error += error * 0.4325;
-        }
+// The arguments that shape the halftone screen biases
+fn screen_args() -> [clap::Arg<'static>; 3] {
+    [
+        clap::Arg::new("SCREEN_ANGLE").long("screen-angle").takes_value(true).allow_hyphen_values(true).default_value("45").help("Sets the angle of halftone screens, in degrees"),
+        clap::Arg::new("SCREEN_FREQUENCY").long("screen-frequency").takes_value(true).default_value("50").help("Sets the frequency of halftone screens, in lines per inch"),
+        clap::Arg::new("DPI").long("dpi").takes_value(true).default_value("300").help("Sets the resolution the output will be printed or shown at, in pixels per inch, for halftone screens"),
+    ]
+}
 
-        // Separate the righ side: to avoid sending error over the edge, distribute it proportionally downwards.
-        let last_target = do_clamp(next_error + LinearRgb::from(*img.get_pixel(flip(img.width() - 1), y)));
-        let last_selection = select_color(last_target);
-        let last_error = subtract(last_target, linear_palette[last_selection]);
-        *img.get_pixel_mut(flip(img.width() - 1), y) = palette[last_selection];
-        row_error[flip(img.width() - 2) as usize] += last_error * 0.375;
-        row_error[flip(img.width() - 1) as usize] += last_error * 0.625;
-    }
+// Where the colors to dither to come from
+enum PaletteChoice {
+    // A fixed palette, either named or read from a file
+    Entries(Vec<palettes::PaletteEntry>),
+    Procedural(palettes::PaletteBuilder),
+    // From --bounding-palette, a simplex palette built inside these colors
+    Bounded(Vec<palettes::PaletteEntry>),
+    // From --channels, which replaces the palette with a grid
+    Channels([usize; 3]),
+}
 
-    // Handle the last row by pushing error entirely rightwards.
-    // TODO: flip for serpentine?
-    let mut error = Vec3::zero();
-    for x in 0..img.width() {
-        let target = do_clamp(error + row_error[x as usize] + LinearRgb::from(*img.get_pixel(x, img.height() - 1)));
-        let selection = select_color(target);
-        error = subtract(target, linear_palette[selection]);
-        *img.get_pixel_mut(x, img.height() - 1) = palette[selection];
-    }
+// The options given by `dithering_args` and `screen_args`, looked up in their registries
+struct Settings {
+    palette: PaletteChoice,
+    palette_space: color::ColorSpace,
+    palette_size: usize,
+    distance2_func: fn(Lab, Lab) -> f64,
+    palette_order: palettes::PaletteOrder,
+    bias_func: bias::BiasFunc,
+    algorithm: &'static Algorithm,
+    blocks: Option<constrained::Blocks>,
 }
 
-// Simple quantization: map each pixel to the nearest palette color.
-fn nearest(pixel: Srgb8, palette: &[Srgb8], _linear_palette: &[LinearRgb], lab_palette: &[Lab], _bias: f64, distance2: fn(Lab, Lab) -> f64) -> Srgb8 {
-    let lab_pixel = Lab::from(pixel);
-    let mut best_dist = std::f64::INFINITY;
-    let mut best = None;
-    for (&opt, &lab_opt) in palette.iter().zip(lab_palette.iter()) {
-        let dist = distance2(lab_opt, lab_pixel);
-        if dist < best_dist {
-            best_dist = dist;
-            best = Some(opt);
+impl Settings {
+    fn from_matches(arg_matches: &clap::ArgMatches) -> Settings {
+        let channel_bits = arg_matches.value_of("CHANNELS").map(|spec| match palettes::parse_channel_bits(spec) {
+            Some(bits) => bits,
+            None => panic!("Invalid channel layout {:?}! Use something like r5g6b5, with 1 to 8 bits per channel.", spec),
+        });
+        if channel_bits == Some([8, 8, 8]) {
+            panic!("r8g8b8 would be a palette of every 8-bit color, which is what images are saved with anyway! Use fewer bits.");
+        }
+        let palette = match (channel_bits, arg_matches.value_of("BOUNDING_PALETTE")) {
+            (Some(bits), _) => PaletteChoice::Channels(bits),
+            (None, Some(bounds_name)) => match palette_choice(bounds_name) {
+                PaletteChoice::Entries(entries) => PaletteChoice::Bounded(entries),
+                _ => panic!("The bounding palette must be a fixed palette or a palette file!"),
+            },
+            (None, None) => palette_choice(arg_matches.value_of("PALETTE").unwrap()),
+        };
+        let palette_space = find_color_space(arg_matches.value_of("PALETTE_SPACE").unwrap());
+        // Grids are what the per-channel algorithm is for, so it's picked for them unless asked otherwise (or unless
+        // --block needs colors chosen together)
+        let is_grid = match palette {
+            PaletteChoice::Channels(_) => true,
+            PaletteChoice::Entries(ref entries) => dither::grid_levels(&palettes::shown_colors(entries, &palette_space)).is_some(),
+            _ => false,
+        };
+        let algorithm = if is_grid && arg_matches.occurrences_of("ALGORITHM") == 0 && !arg_matches.is_present("BLOCK") {
+            find_algorithm("per-channel")
+        } else {
+            find_algorithm(arg_matches.value_of("ALGORITHM").unwrap())
+        };
+        Settings {
+            palette,
+            palette_space,
+            palette_size: arg_matches.value_of_t_or_exit("PALETTE_SIZE"),
+            distance2_func: find_distance(arg_matches.value_of("DISTANCE").unwrap()),
+            palette_order: palettes::parse_palette_order(arg_matches.value_of("PALETTE_ORDER").unwrap()),
+            bias_func: find_bias(arg_matches.value_of("BIAS").unwrap()).build(&bias::ScreenGeometry::from_matches(arg_matches)),
+            algorithm,
+            blocks: constrained::Blocks::from_matches(arg_matches),
         }
     }
 
-    best.unwrap()
-}
-
-// A simple dithering scheme in the style of Yliluoma or simplex dithering: it chooses two nearby palette colors,
-// then quasirandomly chooses between them. This is really just a toy and should probably be removed. It doesn't
-// work very well.
-fn nearest2_inv2_dist(pixel: Srgb8, palette: &[Srgb8], _linear_palette: &[LinearRgb], lab_palette: &[Lab], bias: f64, distance2: fn(Lab, Lab) -> f64) -> image::Rgb<u8> {
-    let lab_pixel = Lab::from(pixel);
-    let mut best_dist = std::f64::INFINITY;
-    let mut best2_dist = std::f64::INFINITY;
-    let mut best = None;
-    let mut best2 = None;
-    for (&opt, &lab_opt) in palette.iter().zip(lab_palette.iter()) {
-        let dist = distance2(lab_opt, lab_pixel);
-        if dist < best_dist {
-            best2_dist = best_dist;
-            best2 = best;
-            best_dist = dist;
-            best = Some(opt);
-        } else if dist < best2_dist {
-            best2_dist = dist;
-            best2 = Some(opt);
+    fn build_palette(&self, pixels: &[LinearRgb]) -> Vec<color::Srgb8> {
+        match self.palette {
+            PaletteChoice::Entries(ref entries) => palettes::shown_colors(entries, &self.palette_space),
+            PaletteChoice::Procedural(build) => {
+                let mut palette = build(self.palette_size, pixels, self.distance2_func);
+                palettes::sort_palette(&mut palette, self.palette_order, pixels, self.distance2_func);
+                palette
+            },
+            PaletteChoice::Bounded(ref entries) => {
+                let bounds = palettes::shown_colors(entries, &self.palette_space);
+                let mut palette = palettes::make_bounded_simplex_palette(self.palette_size, &bounds, pixels, self.distance2_func);
+                palettes::sort_palette(&mut palette, self.palette_order, pixels, self.distance2_func);
+                palette
+            },
+            PaletteChoice::Channels([r_bits, g_bits, b_bits]) => palettes::grid(1 << r_bits, 1 << g_bits, 1 << b_bits),
         }
     }
 
-    let inv2_best = 1.0 / best_dist;
-    let inv2_best2 = 1.0 / best2_dist;
-    let total = inv2_best + inv2_best2;
-    if bias * total <= inv2_best {
-        best.unwrap()
-    } else {
-        best2.unwrap()
+    // Dithers with the chosen algorithm, keeping to the limits of --block if it was given
+    fn dither(&self, source: &LinearImage, palette: &[color::Srgb8], linear_palette: &[LinearRgb], lab_palette: &[Lab]) -> image::RgbImage {
+        match self.blocks {
+            Some(ref blocks) => constrained::dither(blocks, source, palette, linear_palette, lab_palette, self),
+            None => dither::dither_image(source, self.algorithm, palette, linear_palette, lab_palette, &self.bias_func, self.distance2_func),
+        }
     }
-}
 
-fn nearest2_project(pixel: Srgb8, palette: &[Srgb8], linear_palette: &[LinearRgb], lab_palette: &[Lab], bias: f64, distance2: fn(Lab, Lab) -> f64) -> image::Rgb<u8> {
-    let linear_pixel = LinearRgb::from(pixel);
-    let lab_pixel = Lab::from(linear_pixel);
-
-    let mut trans_palette: Vec<_> = palette.iter().cloned().enumerate().map(|(i, rgb8)| {
-        let linear = subtract(linear_palette[i], linear_pixel); // Shift to our pixel being at the origin, since this simplifies a good chunk of the math.
-        let lab = lab_palette[i];
-        let dist2 = distance2(lab_pixel, lab);
-        (rgb8, linear, dist2, lab.l)
-    }).collect();
-    trans_palette.sort_unstable_by(|&(_, _, d1, _), &(_, _, d2, _)| d1.partial_cmp(&d2).unwrap());
-
-    // Fast path that also avoids some of the most annoying edge cases: if we're on a palette color, just return that.
-    if trans_palette[0].2 < 1e-20 {
-        return trans_palette[0].0;
-    }
-
-    let plab_pixel = PseudoLab::from(linear_pixel);
-
-    for index2 in 1..trans_palette.len() {
-        for index1 in 0..index2 {
-            // TODO: just scale directly
-            let point_1 = PseudoLab::from(trans_palette[index1].1 + linear_pixel);
-            let point_2 = PseudoLab::from(trans_palette[index2].1 + linear_pixel);
-
-            // Project our point onto the line segment
-            let vec_12 = subtract(point_2, point_1);
-            let vec_1p = subtract(plab_pixel, point_1);
-            let mag2_12 = vec_12.dot(vec_12);
-            let proj_1p = vec_12 * (vec_1p.dot(vec_12) / mag2_12);
-            let projected = proj_1p + point_1;
-
-            // Test whether we are actually in the segment, not elsewhere on the line
-            let offset_mag = proj_1p.dot(vec_12);
-            //eprintln!("Line: {}", offset_mag / mag2_12);
-            if offset_mag >= 0.0 && offset_mag <= mag2_12 {
-                // Ensure our palette is sorted
-                let bias_shifted = if trans_palette[index1].3 < trans_palette[index2].3 {
-                    1.0 - bias
-                } else {
-                    bias
-                };
-
-                // Select between the two points
-                if bias_shifted * mag2_12 <= offset_mag {
-                    return trans_palette[index2].0;
-                } else {
-                    return trans_palette[index1].0;
-                }
-            }
+    // What gets written for each color of a palette from `build_palette`
+    fn palette_output(&self, palette: &[color::Srgb8]) -> palettes::PaletteOutput {
+        match self.palette {
+            PaletteChoice::Entries(ref entries) => palettes::PaletteOutput::from_entries(entries, palette),
+            _ => palettes::PaletteOutput::identity(palette),
         }
     }
-
-    return trans_palette[0].0;
 }
 
-// The simplex ordered dithering algorithm. The majority of the actual code here is dealing with edge cases.
-// The basic idea is to look for a simplex in linear space that contains the actual color value of the pixel
-// we wish to represent. Then, we represent that pixel in barycentric coordinates within the simplex so that
-// it can be seen as a weighted average of simplex values. Finally, we use the bias value to quasirandomly
-// pick between the corners of the simplex, weighted by the barycentric coordinates: this has an expected
-// value equal to the actual pixel value.
-//
-// When choosing a simplex, we wish to minimize the number of jarring transitions that are supposed to average
-// out, so we want a simplex that is psychovisually small and close to the desired color. As such, we sort all
-// palette points by their distance to the desired color and choose the simplex that fits inside the smallest
-// ball around the desired color, i.e. we minimize that maximum deviation from our desired color. Ties are broken
-// by considering the closeness of successively closer corners of the simplex.
-//
-// Once we've chosen a simplex, we need to "randomly" choose between the vertices. However, since our bias is
-// quasirandom, it's important that we don't add more randomness, which would decrease the quality of the dither.
-// To ensure consistency, we therefore first sort the vertices by luma. This means that low bias values result
-// in a darker choice and high bias values give rise to a lighter choice, consistently across the image. Without
-// this, the dither ends up very clumpy and not sharp.
-//
-//
-// Note that if P is inside a simplex, then, for any other point C, P must be inside one of the 4 simplices
-// obtained by replacing one of the corners with C. For the purposes of dithering, this implies that if our
-// desired color is inside any simplex at all, then we can replace one of the corners with whatever palette color
-// is closest to our desired color. Moreover, this substitution can only decrease the size of the simplex
-// according to our metric. Therefore, we can safely assume that one of the corners of our simplex will be the
-// nearest palette color, and we only need to search for 3 other corners, not 4. This reduces the worst-case
-// runtime of our naive search from O(n^4) to O(n^3).
-//
-// There are definitely faster ways of performing this search - for example, an online convex hull algorithm
-// on the surface of a sphere could be used to add palette points one by one until a simplex is found, then
-// tear down point by point to determine the remaining corners. However, I haven't put much effort into optimizing
-// yet, and this code is already quite complicated due to all the edge cases. Moreover, due to the complexity
-// of CIEDE2000, much of the runtime seems to be consumed in color distance calculations. Avoiding doing the
-// calculation between every pixel and every palette color, possibly with some sort of spatial data structure,
-// would seem more prudent and effective. TODO: Optimize!
-fn tight_simplex(pixel: Srgb8, palette: &[Srgb8], linear_palette: &[LinearRgb], lab_palette: &[Lab], bias: f64, distance2: fn(Lab, Lab) -> f64) -> Srgb8 {
-    let linear_pixel = LinearRgb::from(pixel);
-    let lab_pixel = Lab::from(linear_pixel);
-
-    // The palette, sorted by distance from the pixel. We cache the distance, linear color, and luma and also store a flag
-    // to indicate when we know for certain that a color will be useless.
-    let mut trans_palette: Vec<_> = palette.iter().cloned().enumerate().map(|(i, rgb8)| {
-        let linear = subtract(linear_palette[i], linear_pixel); // Shift to our pixel being at the origin, since this simplifies a good chunk of the math.
-        let lab = lab_palette[i];
-        let dist2 = distance2(lab_pixel, lab);
-        (rgb8, linear, dist2, false, lab.l)
-    }).collect();
-    trans_palette.sort_unstable_by(|&(_, _, d1, _, _), &(_, _, d2, _, _)| d1.partial_cmp(&d2).unwrap());
-
-    // Fast path that also avoids some of the most annoying edge cases: if we're on a palette color, just return that.
-    if trans_palette[0].2 < 1e-20 {
-        return trans_palette[0].0;
-    }
-
-    'outer_loop:
-    for index3 in 3..trans_palette.len() {
-        for index2 in 2..index3 {
-            if trans_palette[index2].3 { continue; } // Skip known-useless colors.
-            for index1 in 1..index2 {
-                if trans_palette[index1].3 { continue; }
-                let index0 = 0;
-
-                // Calculate the signed volumes of the simplices with our pixel and 3 other points.
-                // If all of these have the same sign, then we are inside the simplex.
-                let points = [
-                    trans_palette[index0].1,
-                    trans_palette[index1].1,
-                    trans_palette[index2].1,
-                    trans_palette[index3].1,
-                ];
-                let d0 = determinant([points[1], points[3], points[2]]);
-                let d1 = determinant([points[0], points[2], points[3]]);
-                let d2 = determinant([points[0], points[3], points[1]]);
-                let d3 = determinant([points[0], points[1], points[2]]);
-                let d_all = d0 + d1 + d2 + d3;
-
-                // Handle degenerate cases separately
-                if d3.abs() < 1e-15 || d2.abs() < 1e-15 || d1.abs() < 1e-15 || d0.abs() < 1e-15 {//d_all.abs() < 1e-15 {
-                    //eprintln!("  Degeneracy ({},{},{},{})! d_all={}, d0={}, d1={}, d2={}, d3={}", index0, index1, index2, index3, d_all, d0, d1, d2, d3);
-                    for &face in &[[0,1,2],[0,1,3],[0,2,3],[1,2,3]] {
-                        let face_points = [points[face[0]], points[face[1]], points[face[2]]];
-                        let det = determinant(face_points);
-                        if det.abs() < 1e-12 {
-                            let vec_01 = face_points[1] - face_points[0];
-                            let vec_02 = face_points[2] - face_points[0];
-                            let normal = vec_01.cross(vec_02);
-                            let size2 = normal.dot(normal);
-                            //eprintln!("    Face ({},{},{}) has determinant {}, size2 {}", face[0], face[1], face[2], det, size2);
-                            if size2 > 1e-18 {
-                                let coeff_0 = normal.dot(face_points[1].cross(face_points[2]));
-                                let coeff_1 = normal.dot(face_points[2].cross(face_points[0]));
-                                let coeff_2 = normal.dot(face_points[0].cross(face_points[1]));
-                                //eprintln!("    Valid triangle: {},{},{}", coeff_0, coeff_1, coeff_2);
-
-                                if coeff_0 >= -1e-15 && coeff_1 >= -1e-15 && coeff_2 >= -1e-15 {
-                                    //eprintln!("      Success!");
-                                    let indexes = [index0, index1, index2, index3];
-                                    let mut simplex = [(coeff_0, face[0]), (coeff_1, face[1]), (coeff_2, face[2])];
-                                    simplex.sort_unstable_by(|&(_, f1), &(_, f2)| trans_palette[indexes[f1]].4.partial_cmp(&trans_palette[indexes[f2]].4).unwrap());
-                                    if bias <= simplex[0].0 / size2 {
-                                        return trans_palette[indexes[simplex[0].1]].0;
-                                    } else if bias - simplex[0].0 / size2 <= simplex[1].0 / size2 {
-                                        return trans_palette[indexes[simplex[1].1]].0;
-                                    } else {
-                                        return trans_palette[indexes[simplex[2].1]].0;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    for &edge in &[[0,1], [0,2], [0,3], [1,2], [1,3], [2,3]] {
-                        let edge_points = [points[edge[0]], points[edge[1]]];
-                        let vec_01 = edge_points[1] - edge_points[0];
-                        let vec_0p = -edge_points[0];
-                        let normal = vec_01.cross(vec_0p);
-                        if normal.dot(normal) < 1e-15 {
-                            let len2 = vec_01.dot(vec_01);
-                            let partial = vec_0p.dot(vec_01);
-                            if partial >= 0.0 && partial <= len2 {
-                                let indexes = [index0, index1, index2, index3];
-                                let mut simplex = [(partial / len2, edge[0]), (1.0 - partial / len2, edge[1])];
-                                simplex.sort_unstable_by(|&(_, f1), &(_, f2)| trans_palette[indexes[f1]].4.partial_cmp(&trans_palette[indexes[f2]].4).unwrap());
-                                if bias <= simplex[0].0 {
-                                    return trans_palette[indexes[simplex[0].1]].0;
-                                } else {
-                                    return trans_palette[indexes[simplex[1].1]].0;
-                                }
-                            }
-                        }
-                    }
-                } else if d_all.signum() == d0.signum() &&
-                          d_all.signum() == d1.signum() &&
-                          d_all.signum() == d2.signum() &&
-                          d_all.signum() == d3.signum() {
-
-                    // We're inside the simplex! 
-                    let mut simplex = [(d0, index0), (d1, index1), (d2, index2), (d3, index3)];
-                    simplex.sort_unstable_by(|&(_, i1), &(_, i2)| trans_palette[i1].4.partial_cmp(&trans_palette[i2].4).unwrap());
-
-                    let mut bias_left = bias;
-                    if bias_left <= simplex[0].0 / d_all {
-                        return trans_palette[simplex[0].1].0;
-                    } else {
-                        bias_left -= simplex[0].0 / d_all;
-                    }
-                    if bias_left <= simplex[1].0 / d_all {
-                        return trans_palette[simplex[1].1].0;
-                    } else {
-                        bias_left -= simplex[1].0 / d_all;
-                    }
-                    if bias_left <= simplex[2].0 / d_all {
-                        return trans_palette[simplex[2].1].0;
-                    } else {
-                        return trans_palette[simplex[3].1].0;
-                    }
-                } else if d0.signum() == d1.signum() &&
-                          d0.signum() == d2.signum() {
-                    trans_palette[index3].3 = true;
-                    continue 'outer_loop;
-                }
-            }
-        }
+// A palette given by name or, if it isn't a palette's name but is a file, read from a palette file
+fn palette_choice(name: &str) -> PaletteChoice {
+    if palettes::lookup(name).is_none() && std::path::Path::new(name).is_file() {
+        return PaletteChoice::Entries(palettes::parse_palette_file(&std::fs::read_to_string(name).unwrap()));
     }
-
-    // No simplex contains our point. Therefore, search for a triangle we can project onto or a line segment or the closest point (whichever allows
-    // us to project to the closest point).
-
-    // Start with the closest point
-    let mut best_sample = trans_palette[0].0;
-    let mut best_dist2 = trans_palette[0].2;
-
-    // FIXME: The following code does orthogonal projection in linear space, which is wrong. We want the plane/line defined by linear space, but we want the
-    // CIEDE-closest point. As an approximation, we try to make something that looks a bit more like LAB but is a linear transformation.
-    let plab_pixel = PseudoLab::from(linear_pixel);
-
-    // Try to beat it with a line segment
-    for index2 in 1..trans_palette.len() {
-        for index1 in 0..index2 {
-            // TODO: just scale directly
-            let point_1 = PseudoLab::from(trans_palette[index1].1 + linear_pixel);
-            let point_2 = PseudoLab::from(trans_palette[index2].1 + linear_pixel);
-
-            // Project our point onto the line segment
-            let vec_12 = subtract(point_2, point_1);
-            let vec_1p = subtract(plab_pixel, point_1);
-            let mag2_12 = vec_12.dot(vec_12);
-            let proj_1p = vec_12 * (vec_1p.dot(vec_12) / mag2_12);
-            let projected = proj_1p + point_1;
-
-            // Test whether we are actually in the segment, not elsewhere on the line
-            let offset_mag = proj_1p.dot(vec_12);
-            //eprintln!("Line: {}", offset_mag / mag2_12);
-            if offset_mag >= 0.0 && offset_mag <= mag2_12 {
-                // Only consider points that are better than the best seen
-                let lab_projected = Lab::from(projected);
-                let dist2 = distance2(lab_projected, lab_pixel);
-                //eprintln!(" Projected: {:?}", LinearRgb::from(projected).data);
-                //eprintln!(" Dist: {}, Point: {}", dist2, best_dist2);
-                if dist2 < best_dist2 {
-                    // Ensure our palette is sorted
-                    let bias_shifted = if trans_palette[index1].4 < trans_palette[index2].4 {
-                        1.0 - bias
-                    } else {
-                        bias
-                    };
-
-                    // Select between the two points
-                    if bias_shifted * mag2_12 <= offset_mag {
-                        best_sample = trans_palette[index2].0;
-                    } else {
-                        best_sample = trans_palette[index1].0;
-                    }
-                    best_dist2 = dist2;
-                }
-            }
-        }
+    let named_palette = find_palette(name);
+    match named_palette.source {
+        palettes::PaletteSource::Procedural(build) => PaletteChoice::Procedural(build),
+        _ => PaletteChoice::Entries(named_palette.entries().unwrap()),
     }
+}
 
-    // Try to beat it with a triangle
-    for index3 in 2..trans_palette.len() {
-        for index2 in 1..index3 {
-            for index1 in 0..index2 {
-                let point_1 = PseudoLab::from(trans_palette[index1].1 + linear_pixel);
-                let point_2 = PseudoLab::from(trans_palette[index2].1 + linear_pixel);
-                let point_3 = PseudoLab::from(trans_palette[index3].1 + linear_pixel);
-
-                // Project onto the plane
-                let vec_12 = subtract(point_2, point_1);
-                let vec_13 = subtract(point_3, point_1);
-                let vec_1p = subtract(plab_pixel, point_1);
-                let normal = vec_12.cross(vec_13);
-                let mag2_normal = normal.dot(normal); // TODO: What happens if this is 0?
-                let offset = normal * (vec_1p.dot(normal) / mag2_normal); // Do the projection by projecting onto the normal and subtracting
-                let projected = (-offset) + plab_pixel;
-                let lab_projected = Lab::from(projected);
-                let dist2 = distance2(lab_projected, lab_pixel);
-                if dist2 < best_dist2 {
-                    let proj_p1 = offset - vec_1p;
-                    let proj_p2 = proj_p1 + vec_12;
-                    let proj_p3 = proj_p1 + vec_13;
-
-                    let d_area_all = mag2_normal.sqrt();
-                    let normal_23 = proj_p2.cross(proj_p3);
-                    let d_area_23 = normal_23.dot(normal_23).sqrt();
-                    let normal_13 = proj_p1.cross(proj_p3);
-                    let d_area_13 = normal_13.dot(normal_13).sqrt();
-
-                    let coord1 = d_area_23 / d_area_all;
-                    let coord2 = d_area_13 / d_area_all;
-                    let coord3 = 1.0 - coord1 - coord2;
-
-                    if coord1 >= 0.0 &&
-                       coord2 >= 0.0 &&
-                       coord3 >= 0.0 {
-
-                        let mut simplex = [(coord1, index1), (coord2, index2), (coord3, index3)];
-                        simplex.sort_unstable_by(|&(_, i1), &(_, i2)| trans_palette[i1].4.partial_cmp(&trans_palette[i2].4).unwrap());
-
-                        let mut bias_left = bias;
-                        if bias_left <= simplex[0].0 {
-                            best_sample = trans_palette[simplex[0].1].0;
-                        } else {
-                            bias_left -= simplex[0].0;
-                            if bias_left <= simplex[1].0 {
-                                best_sample = trans_palette[simplex[1].1].0;
-                            } else {
-                                best_sample = trans_palette[simplex[2].1].0;
-                            }
-                        }
+// Looks up options by name, pointing towards the list of valid names if nothing matches
+fn find_palette(name: &str) -> &'static palettes::NamedPalette {
+    match palettes::lookup(name) {
+        Some(named_palette) => named_palette,
+        None => panic!("Unrecognized palette {:?}! Run `dither list palettes` to see the options, or give a palette file.", name),
+    }
+}
 
-                        best_dist2 = dist2;
-                    }
-                }
-            }
-        }
+fn find_distance(name: &str) -> fn(Lab, Lab) -> f64 {
+    match color::lookup_distance(name) {
+        Some(distance) => distance.func,
+        None => panic!("Unrecognized color distance function {:?}! Run `dither list distances` to see the options.", name),
     }
+}
 
-    return best_sample;
+fn find_white_point(name: &str) -> color::WhitePoint {
+    match color::lookup_white_point(name) {
+        Some(white_point) => white_point,
+        None => panic!("Unrecognized white point {:?}! Run `dither list white-points` to see the options, or give an xy chromaticity like 0.3127,0.3290.", name),
+    }
 }
 
-fn visualize_bias(_pixel: Srgb8, _palette: &[Srgb8], _linear_palette: &[LinearRgb], _lab_palette: &[Lab], bias: f64, _distance2: fn(Lab, Lab) -> f64) -> Srgb8 {
-    Srgb8::from(LinearRgb { data: [bias, bias, bias] })
+fn find_color_space(name: &str) -> color::ColorSpace {
+    match color::lookup_color_space(name) {
+        Some(named_space) => named_space.space,
+        None => panic!("Unrecognized color space {:?}! Run `dither list color-spaces` to see the options.", name),
+    }
 }
 
-fn spread(x: u8) -> u16 {
-    let mut ret = x as u16;
-    ret = (ret ^ (ret << 4)) & 0x0f0f;
-    ret = (ret ^ (ret << 2)) & 0x3333;
-    ret = (ret ^ (ret << 1)) & 0x5555;
-    ret
+fn find_bias(name: &str) -> &'static bias::NamedBias {
+    match bias::lookup(name) {
+        Some(bias) => bias,
+        None => panic!("Unrecognized bias function {:?}! Run `dither list biases` to see the options.", name),
+    }
 }
 
-fn bayer(bits: u32, x: u32, y: u32) -> f64 {
-    let size = 1 << bits;
-    let grid_x = spread((x%size) as u8);
-    let grid_y = spread((y%size) as u8);
-    let mixed = (grid_x ^ (grid_x << 1) ^ grid_y).reverse_bits() >> (16 - 2*bits);
-    (0.5 + mixed as f64) / (size*size) as f64
+fn find_algorithm(name: &str) -> &'static Algorithm {
+    match dither::lookup(name) {
+        Some(named_algorithm) => &named_algorithm.algorithm,
+        None => panic!("Unrecognized algorithm {:?}! Run `dither list algorithms` to see the options.", name),
+    }
 }
 
 fn main() {
@@ -516,203 +273,328 @@ fn main() {
             .version("0.1")
             .author("Jonathan S <gereeter+code@gmail.com>")
             .about("High-quality ordered dithering")
-            .arg(clap::Arg::new("PALETTE").short('p').long("palette").takes_value(true).default_value("simplex").help("Chooses the palette to quantize to"))
-            .arg(clap::Arg::new("PALETTE_SIZE").short('c').long("colors").takes_value(true).default_value("16").help("How many colors to use in a procedural palette"))
-            .arg(clap::Arg::new("DISTANCE").short('d').long("distance").takes_value(true).default_value("CIEDE2000").help("Chooses how to calculate how far apart colors are"))
-            .arg(clap::Arg::new("BIAS").short('b').long("bias").takes_value(true).default_value("plastic+triangle").help("Chooses the bias pattern for ordered dithering algorithms"))
-            .arg(clap::Arg::new("ALGORITHM").short('a').long("algorithm").takes_value(true).default_value("simplex").help("Chooses the dithering algorithm to use"))
+            .setting(clap::AppSettings::SubcommandsNegateReqs)
+            .setting(clap::AppSettings::ArgsNegateSubcommands)
+            .args(dithering_args())
+            .args(screen_args())
+            .arg(clap::Arg::new("WHITE_POINT").long("white-point").takes_value(true).global(true).default_value("d65").help("Sets the reference white for Lab color distances, by name or as an xy chromaticity like 0.3127,0.3290"))
+            .arg(clap::Arg::new("WORKING_SPACE").long("working-space").takes_value(true).global(true).default_value("srgb").help("Sets the RGB color space to dither in and tag the output with"))
             .arg(clap::Arg::new("summarize").short('s').long("summarize").help("Print a summary of the palette colors used in the output"))
-            .arg(clap::Arg::new("OUTPUT").short('o').long("output").takes_value(true).default_value("out.png").help("Sets where to write the dithered file to"))
-            .arg(clap::Arg::new("IMAGE").required(true).help("Sets the image to dither"))
+            .arg(clap::Arg::new("PALETTE_PREVIEW").long("palette-preview").takes_value(true).allow_invalid_utf8(true).help("Writes an image of the palette's colors, with usage bars if --summarize is given"))
+            .arg(clap::Arg::new("preview-labels").long("preview-labels").requires("PALETTE_PREVIEW").help("Labels each color in the palette preview with its index"))
+            .arg(clap::Arg::new("GAMUT_PREVIEW").long("gamut-preview").takes_value(true).allow_invalid_utf8(true).help("Writes a PNG or SVG projection of the palette's gamut, marking the image colors outside it"))
+            .arg(clap::Arg::new("report-gamut").long("report-gamut").help("Reports how many pixels lie outside the palette's gamut, and how far"))
+            .arg(clap::Arg::new("GAMUT_MASK").long("gamut-mask").takes_value(true).allow_invalid_utf8(true).help("Writes an image highlighting the pixels outside the palette's gamut"))
+            .arg(clap::Arg::new("PREVIEW").long("preview").takes_value(true).possible_values(terminal::PREVIEW_KINDS).help("Prints the dithered image to the terminal, with half-block characters (terminal) or as a Sixel image (sixel)"))
+            .arg(clap::Arg::new("METRICS").long("metrics").takes_value(true).min_values(0).require_equals(true).default_missing_value("text").possible_values(["text", "json"]).help("Prints quality metrics comparing the output to the input"))
+            .arg(clap::Arg::new("palette-per-frame").long("palette-per-frame").help("For animations, builds a separate procedural palette for each frame instead of one for the whole animation"))
+            .arg(clap::Arg::new("stable").long("stable").help("For animations, keeps the output of pixels that don't change from one frame to the next"))
+            .arg(clap::Arg::new("RESIZE").long("resize").takes_value(true).conflicts_with("SCALE").help("Resizes the image to WxH before dithering, keeping the aspect ratio if W or H is left out"))
+            .arg(clap::Arg::new("SCALE").long("scale").takes_value(true).help("Scales the image by the given factor before dithering"))
+            .arg(clap::Arg::new("FILTER").long("filter").takes_value(true).default_value("catmullrom").possible_values(resize::FILTERS).help("Chooses the resampling filter for --resize and --scale"))
+            .arg(clap::Arg::new("EXPOSURE").long("exposure").takes_value(true).allow_hyphen_values(true).help("Brightens (or darkens, if negative) the image by the given number of stops before dithering"))
+            .arg(clap::Arg::new("GAMMA").long("gamma").takes_value(true).help("Applies a gamma adjustment before dithering, brightening the midtones if greater than 1"))
+            .arg(clap::Arg::new("CONTRAST").long("contrast").takes_value(true).help("Multiplies the contrast around middle gray before dithering"))
+            .arg(clap::Arg::new("SATURATION").long("saturation").takes_value(true).help("Multiplies the saturation before dithering"))
+            .arg(clap::Arg::new("auto-levels").long("auto-levels").help("Stretches the image to use the full range from black to white before dithering"))
+            .arg(clap::Arg::new("UNSHARP").long("unsharp").takes_value(true).help("Sharpens the image with an unsharp mask of the given strength before dithering"))
+            .arg(clap::Arg::new("UNSHARP_SIGMA").long("unsharp-sigma").takes_value(true).requires("UNSHARP").help("Sets the radius (standard deviation) of the unsharp mask's blur [default: 1]"))
+            .arg(clap::Arg::new("UPSCALE").long("upscale").takes_value(true).help("Enlarges the dithered image by a whole number, with each pixel becoming a block"))
+            .arg(clap::Arg::new("OUTPUT").short('o').long("output").takes_value(true).allow_invalid_utf8(true).default_value("out.png").help("Sets where to write the dithered file to, or - for stdout"))
+            .arg(clap::Arg::new("FORMAT").long("format").takes_value(true).possible_values(files::OUTPUT_FORMATS).help("Sets the output format, instead of guessing from the file extension"))
+            .arg(clap::Arg::new("EXPORT").long("export").takes_value(true).conflicts_with("FORMAT").help("Writes raw framebuffer bytes (raw:rgb565le, raw:rgb332, raw:gray4, raw:mono-hlsb, raw:mono-vlsb or raw:indexed8) or a C header (c-header or c-header:FORMAT) instead of an image"))
+            .arg(clap::Arg::new("IMAGE").required(true).allow_invalid_utf8(true).help("Sets the image to dither, or - for stdin"))
+            .subcommand(clap::App::new("list")
+                .about("Lists the available palettes, algorithms, biases and distances")
+                .arg(clap::Arg::new("KIND").possible_values(["palettes", "algorithms", "biases", "distances", "white-points", "color-spaces"]).help("Only list one kind of option")))
+            .subcommand(clap::App::new("compare")
+                .about("Measures how closely a dithered image reproduces the original")
+                .arg(clap::Arg::new("json").long("json").help("Prints the metrics as JSON"))
+                .arg(clap::Arg::new("ORIGINAL").required(true).allow_invalid_utf8(true).help("The original image, or - for stdin"))
+                .arg(clap::Arg::new("DITHERED").required(true).allow_invalid_utf8(true).help("The dithered image, or - for stdin")))
+            .subcommand(clap::App::new("batch")
+                .about("Dithers many images with the same settings")
+                .args(dithering_args())
+                .args(screen_args())
+                .arg(clap::Arg::new("shared-palette").long("shared-palette").help("Builds one procedural palette from all the inputs together"))
+                .arg(clap::Arg::new("SUBSAMPLE").long("subsample").takes_value(true).default_value("1").help("Only uses every Nth pixel of the inputs to build procedural palettes"))
+                .arg(clap::Arg::new("PALETTE_OUTPUT").long("palette-output").takes_value(true).allow_invalid_utf8(true).requires("shared-palette").help("Sets where to write the shared palette, as a .hex file [default: palette.hex]"))
+                .arg(clap::Arg::new("OUT_DIR").long("out-dir").takes_value(true).allow_invalid_utf8(true).help("Sets the directory to write the results to, instead of next to each input"))
+                .arg(clap::Arg::new("NAME").long("name").takes_value(true).default_value("{stem}_dithered.{ext}").help("Sets the file name of each result, filling in {stem}, {ext}, {algorithm}, {palette}, {bias}, {distance} and {colors}"))
+                .arg(clap::Arg::new("force").long("force").help("Dithers every input, even if its output is newer than it"))
+                .arg(clap::Arg::new("INPUTS").required(true).multiple_values(true).help("Sets the images to dither, as file names or glob patterns")))
+            .subcommand(clap::App::new("sweep")
+                .about("Dithers an image with every combination of the given options, and lays out the results side by side")
+                .args(dithering_args())
+                .args(screen_args())
+                .mut_arg("PALETTE", |arg| arg.help("Chooses the palettes to compare, separated by commas"))
+                .mut_arg("BIAS", |arg| arg.help("Chooses the bias patterns to compare, separated by commas"))
+                .mut_arg("ALGORITHM", |arg| arg.help("Chooses the dithering algorithms to compare, separated by commas"))
+                .arg(clap::Arg::new("metrics").long("metrics").help("Shows quality metrics under each result, and prints them in full"))
+                .arg(clap::Arg::new("OUTPUT").short('o').long("output").takes_value(true).allow_invalid_utf8(true).default_value("sweep.png").help("Sets where to write the contact sheet to, or - for stdout"))
+                .arg(clap::Arg::new("IMAGE").required(true).allow_invalid_utf8(true).help("Sets the image to dither, or - for stdin")))
+            .subcommand(clap::App::new("separate")
+                .about("Separates an image into CMYK and halftones each ink with its own screen angle")
+                .args(screen_args())
+                .arg(clap::Arg::new("BIAS").short('b').long("bias").takes_value(true).default_value("round-screen").help("Chooses the halftone screen (or other bias pattern) for every ink"))
+                .arg(clap::Arg::new("ANGLES").long("angles").takes_value(true).default_value("15,75,0,45").help("Sets the screen angles for cyan, magenta, yellow and black, separated by commas"))
+                .arg(clap::Arg::new("BLACK_GENERATION").long("black-generation").takes_value(true).default_value("gcr").possible_values(["gcr", "ucr"]).help("Chooses whether black replaces the colored inks everywhere (gcr) or only in the shadows (ucr)"))
+                .arg(clap::Arg::new("BLACK_AMOUNT").long("black-amount").takes_value(true).default_value("0.5").help("Sets how much of the gray component black replaces, from 0 to 1"))
+                .arg(clap::Arg::new("INK_LIMIT").long("ink-limit").takes_value(true).default_value("300").help("Sets the most ink allowed on any spot, as a total percentage"))
+                .arg(clap::Arg::new("PLATES").long("plates").takes_value(true).help("Writes each ink's plate as a 1-bit PNG named PREFIX_c.png, PREFIX_m.png, PREFIX_y.png and PREFIX_k.png"))
+                .arg(clap::Arg::new("OUTPUT").short('o').long("output").takes_value(true).allow_invalid_utf8(true).default_value("separated.png").help("Sets where to write a preview of the printed result to, or - for stdout"))
+                .arg(clap::Arg::new("FORMAT").long("format").takes_value(true).possible_values(files::OUTPUT_FORMATS).help("Sets the preview's format, instead of guessing from the file extension"))
+                .arg(clap::Arg::new("IMAGE").required(true).allow_invalid_utf8(true).help("Sets the image to separate, or - for stdin")))
+            .subcommand(clap::App::new("text")
+                .about("Dithers an image into character cells of shade glyphs, as ANSI text or a rendered image")
+                .args(dithering_args())
+                .args(screen_args())
+                .mut_arg("PALETTE", |arg| arg.default_value("rgbi"))
+                .arg(clap::Arg::new("COLUMNS").long("columns").takes_value(true).default_value("80").help("Sets how many characters wide the result is"))
+                .arg(clap::Arg::new("OUTPUT").short('o').long("output").takes_value(true).allow_invalid_utf8(true).default_value("out.ans").help("Sets where to write the result to: ANSI text for .ans, .txt or - (stdout), or else a rendered image"))
+                .arg(clap::Arg::new("IMAGE").required(true).allow_invalid_utf8(true).help("Sets the image to dither, or - for stdin")))
+            .subcommand(clap::App::new("analyze-bias")
+                .about("Measures a bias pattern's distribution, spectrum and period, and plots them")
+                .arg(clap::Arg::new("SIZE").long("size").takes_value(true).default_value("256").help("Sets the size of the square of the pattern to analyze, which must be a power of two"))
+                .arg(clap::Arg::new("OUTPUT").short('o').long("output").takes_value(true).default_value("bias").help("Sets the prefix of the plots' file names"))
+                .args(screen_args())
+                .arg(clap::Arg::new("BIAS").required(true).help("The bias pattern to analyze")))
             .get_matches();
 
+    let color_matches = arg_matches.subcommand().map_or(&arg_matches, |(_, matches)| matches);
+    color::configure(
+        find_color_space(color_matches.value_of("WORKING_SPACE").unwrap()),
+        find_white_point(color_matches.value_of("WHITE_POINT").unwrap()),
+    );
+
+    if let Some(list_matches) = arg_matches.subcommand_matches("list") {
+        list(list_matches.value_of("KIND"));
+        return;
+    }
+
+    if let Some(compare_matches) = arg_matches.subcommand_matches("compare") {
+        let original = files::InputFile::read(compare_matches.value_of_os("ORIGINAL").unwrap()).image();
+        let dithered = files::InputFile::read(compare_matches.value_of_os("DITHERED").unwrap()).image();
+        if (original.width, original.height) != (dithered.width, dithered.height) {
+            panic!("Can't compare a {}x{} image with a {}x{} one! Both images must be the same size.", original.width, original.height, dithered.width, dithered.height);
+        }
+        let metrics = metrics::Metrics::compute(&original, &dithered);
+        if compare_matches.is_present("json") {
+            print!("{}", metrics.to_json());
+        } else {
+            print!("{}", metrics.to_text());
+        }
+        return;
+    }
+
+    if let Some(batch_matches) = arg_matches.subcommand_matches("batch") {
+        batch::run(batch_matches);
+        return;
+    }
+
+    if let Some(sweep_matches) = arg_matches.subcommand_matches("sweep") {
+        sweep::run(sweep_matches);
+        return;
+    }
+
+    if let Some(separate_matches) = arg_matches.subcommand_matches("separate") {
+        separation::run(separate_matches);
+        return;
+    }
+
+    if let Some(text_matches) = arg_matches.subcommand_matches("text") {
+        text::run(text_matches);
+        return;
+    }
+
+    if let Some(analyze_matches) = arg_matches.subcommand_matches("analyze-bias") {
+        analysis::run(analyze_matches);
+        return;
+    }
+
     let file_name = arg_matches.value_of_os("IMAGE").unwrap();
     let out_file_name = arg_matches.value_of_os("OUTPUT").unwrap();
-    let mut img = image::open(&file_name).unwrap().into_rgb8();
-
-    let distance2_func = match arg_matches.value_of("DISTANCE").unwrap() {
-        "CIE1994" | "CIE94" => Lab::cie1994_distance2,
-        "symCIE1994" => Lab::sym_cie1994_distance2,
-        "wdsCIE1994" => Lab::wds_cie1994_distance2,
-        "CIEDE2000" => Lab::ciede2000_distance2,
-        "contCIEDE2000" => Lab::cont_ciede2000_distance2,
-        _ => panic!("Unrecognized color distance function!"),
-    };
-
-    let bias_func = match arg_matches.value_of("BIAS").unwrap() {
-        "interleavedgradient" => Box::new(|x:u32, y:u32| {
-            (52.9829189 * (0.06711056 * x as f64 + 0.00583715 * y as f64).fract()).fract()
-        }) as Box<dyn Fn(u32,u32)->f64+Sync>,
-        "plastic" => Box::new(|x:u32, y:u32| {
-            let plastic = 1.32471795724474602596;
-            (x as f64 / plastic + y as f64 / plastic.powi(2)).fract()
-        }) as Box<dyn Fn(u32,u32)->f64+Sync>,
-        "plastic+triangle" => Box::new(|x:u32, y:u32| {
-            // As suggested in The Unreasonable Effectiveness of Quasirandom Sequences (Martin Roberts),
-            // using a simple linear function based on the plastic number, composed with a triangle wave,
-            // gives good results for bias.
-            let plastic = 1.32471795724474602596;
-            let r_bias = (x as f64 / plastic + y as f64 / plastic.powi(2)).fract();
-            if r_bias < 0.5 {
-                2.0 * r_bias
-            } else {
-                2.0 - 2.0 * r_bias
+
+    let format = arg_matches.value_of("FORMAT").map(files::parse_format);
+    let export = arg_matches.value_of("EXPORT").map(export::parse);
+    let settings = Settings::from_matches(&arg_matches);
+    let distance2_func = settings.distance2_func;
+
+    // Everything else we print goes to stdout, so it would end up mixed in with the image
+    if files::is_stdio(out_file_name) && ["summarize", "METRICS", "report-gamut", "PREVIEW"].iter().any(|&arg| arg_matches.is_present(arg)) {
+        panic!("Summaries, metrics, gamut reports and previews can't be printed while writing the image to stdout!");
+    }
+
+    let input = files::InputFile::read(file_name);
+    let scale = if arg_matches.is_present("SCALE") { Some(arg_matches.value_of_t_or_exit("SCALE")) } else { None };
+    let new_size = |width, height| resize::requested_size(arg_matches.value_of("RESIZE"), scale, width, height);
+    let filter = resize::parse_filter(arg_matches.value_of("FILTER").unwrap());
+    let adjustments = adjust::Adjustments::from_matches(&arg_matches);
+    let upscale: u32 = if arg_matches.is_present("UPSCALE") { arg_matches.value_of_t_or_exit("UPSCALE") } else { 1 };
+    if upscale == 0 {
+        panic!("The upscaling factor must be at least 1!");
+    }
+
+    if let Some(mut animation) = input.animation() {
+        if ["summarize", "METRICS", "PALETTE_PREVIEW", "GAMUT_PREVIEW", "report-gamut", "GAMUT_MASK", "PREVIEW"].iter().any(|&arg| arg_matches.is_present(arg)) {
+            panic!("Summaries, metrics and previews are not supported for animations!");
+        }
+        if export.is_some() {
+            panic!("Animations can't be exported as raw data!");
+        }
+
+        let mut sources = animation.unclipped_sources();
+        if let Some((width, height)) = new_size(animation.width, animation.height) {
+            for source in &mut sources {
+                *source = resize::resize(source, width, height, filter);
             }
-        }) as Box<dyn Fn(u32,u32)->f64+Sync>,
-        "bayer2" => Box::new(|x:u32, y:u32| {
-            bayer(1, x, y)
-        }) as Box<dyn Fn(u32,u32)->f64+Sync>,
-        "bayer4" => Box::new(|x:u32, y:u32| {
-            bayer(2, x, y)
-        }) as Box<dyn Fn(u32,u32)->f64+Sync>,
-        "bayer8" => Box::new(|x:u32, y:u32| {
-            bayer(3, x, y)
-        }) as Box<dyn Fn(u32,u32)->f64+Sync>,
-        "bayer16" => Box::new(|x:u32, y:u32| {
-            bayer(4, x, y)
-        }),
-        "bayer256" => Box::new(|x:u32, y:u32| {
-            bayer(8, x, y)
-        }),
-        // Mostly useful for debugging
-        "block8" => Box::new(|x:u32, y:u32| {
-            let mixed = (x%8) + 8 * (y%8);
-            (0.5 + mixed as f64) / 64.0
-        }),
-        "zblock8" => Box::new(|x:u32, y:u32| {
-            let mixed = spread((x%8) as u8) | (spread((y%8) as u8) << 1);
-            (0.5 + mixed as f64) / 64.0
-        }),
-        // And for fun
-        "dot8" => Box::new(|x:u32, y:u32| {
-            let arr = [[60, 52, 51, 37, 36, 50, 59, 63],
-                       [53, 43, 28, 19, 18, 27, 42, 58],
-                       [44, 29, 13,  5,  4, 12, 26, 49],
-                       [38, 20,  6,  0,  3, 11, 17, 35],
-                       [39, 21,  7,  1,  2, 10, 16, 34],
-                       [45, 30, 14,  8,  9, 15, 25, 48],
-                       [54, 40, 31, 22, 23, 24, 41, 57],
-                       [61, 55, 46, 32, 33, 47, 56, 62]];
-            let mixed: u8 = arr[(y%8) as usize][(x%8) as usize];
-            (0.5 + mixed as f64) / 64.0
-        }),
-        "random" => Box::new(|_:u32, _:u32| {
-            rand::thread_rng().gen::<f64>()
-        }),
-        _ => panic!("Unrecognized bias function!")
-    };
-
-    let palette_size: usize = arg_matches.value_of_t_or_exit("PALETTE_SIZE");
-
-    let palette = match arg_matches.value_of("PALETTE").unwrap() {
-        "bw" | "1bit" => vec![image::Rgb([0,0,0]), image::Rgb([255,255,255])],
-        "gray256" | "grey256" => (0..=255).map(|v| image::Rgb([v,v,v])).collect(),
-        "websafe" | "r6g6b6" => palettes::grid(6, 6, 6),
-        "reallysafe" => palettes::REALLYSAFE.to_vec(),
-        "3bit" | "r2g2b2" => palettes::grid(2, 2, 2),
-        "rgbi" => palettes::RGBI.to_vec(),
-        "microsoft16" => palettes::MICROSOFT16.to_vec(),
-        "macintosh16" => palettes::MACINTOSH16.to_vec(),
-        "r3g3b2" => palettes::grid(3, 3, 2),
-        "8bit" | "r8g8b4" => palettes::grid(8, 8, 4),
-        "12bit" | "r16g16b16" => palettes::grid(16, 16, 16),
-        "15bit" | "r32g32b32" => palettes::grid(32, 32, 32),
-        "yliluoma" => palettes::YLILUOMA_EXAMPLE.to_vec(),
-        "yliluoma_alternate" => palettes::YLILUOMA_EXAMPLE_ALTERNATE.to_vec(),
-        "petz" => {
-          let mut unclean = palettes::PETZ_SOURCE.to_vec();
-          // The petz palette has duplicates, so remove them.
-          unclean.sort_unstable_by(|a, b| a.channels().cmp(&b.channels()));
-          unclean.dedup();
-          unclean
-        },
-        "petz_safe" => {
-          let mut unclean = palettes::PETZ_SOURCE.to_vec();
-          // Removing in reverse doesn't mess up the other indices
-          // Would be nice if `drain_filter` got indices for this purpose
-          for unstable_index in (8..=9).chain(229..=243).chain(246..=248).chain(255..=255).rev() {
-            unclean.swap_remove(unstable_index);
-          }
-          // The petz palette has duplicates, so remove them.
-          unclean.sort_unstable_by(|a, b| a.channels().cmp(&b.channels()));
-          unclean.dedup();
-          unclean
-        },
-        "octree" => palettes::make_box_palette(palette_size, img.pixels().cloned(), palettes::Split::Half, true),
-        "octree-notight" => palettes::make_box_palette(palette_size, img.pixels().cloned(), palettes::Split::Half, false),
-        "mediancut-box" => palettes::make_box_palette(palette_size, img.pixels().cloned(), palettes::Split::Median, true),
-        "meancut-box" => palettes::make_box_palette(palette_size, img.pixels().cloned(), palettes::Split::Mean, true),
-        "simplex" => palettes::make_simplex_palette(palette_size, img.pixels().cloned(), distance2_func),
-        _ => panic!("Unrecognized palette!")
-    };
+            animation.resize(width, height);
+        }
+        adjustments.apply(&mut sources);
+
+        let shared_palette = if arg_matches.is_present("palette-per-frame") {
+            None
+        } else {
+            let pixels: Vec<_> = sources.iter().zip(&animation.transparent).flat_map(|(source, transparent)| animation::opaque_pixels(source, transparent)).collect();
+            Some(settings.build_palette(&pixels))
+        };
+
+        // With --stable, we remember the previous frame before and after dithering, so that we can tell which pixels have
+        // not changed. For ordered dithering with a shared palette, those pixels would come out the same anyway, but this
+        // also keeps them still with error diffusion, random biases and per-frame palettes.
+        let mut previous: Option<(&LinearImage, image::RgbImage)> = None;
+        let frame_count = sources.len();
+        for (i, (source, transparent)) in sources.iter().zip(&animation.transparent).enumerate() {
+            eprintln!("Dithering frame {} of {}", i + 1, frame_count);
+            let palette = match shared_palette {
+                Some(ref palette) => palette.clone(),
+                None => settings.build_palette(&animation::opaque_pixels(source, transparent).collect::<Vec<_>>()),
+            };
+            let linear_palette: Vec<_> = palette.iter().cloned().map(LinearRgb::from).collect();
+            let lab_palette: Vec<_> = linear_palette.iter().cloned().map(Lab::from).collect();
+
+            let mut frame = settings.dither(source, &palette, &linear_palette, &lab_palette);
+
+            if arg_matches.is_present("stable") {
+                if let Some((previous_source, previous_dithered)) = previous {
+                    let palette_colors: std::collections::HashSet<_> = palette.iter().cloned().collect();
+                    for (x, y, pixel) in frame.enumerate_pixels_mut() {
+                        let previous_pixel = *previous_dithered.get_pixel(x, y);
+                        if source.get(x, y).data == previous_source.get(x, y).data && palette_colors.contains(&previous_pixel) {
+                            *pixel = previous_pixel;
+                        }
+                    }
+                }
+                previous = Some((source, frame.clone()));
+            }
+            animation.frames[i] = settings.palette_output(&palette).apply(&frame, &palette);
+        }
+
+        if upscale > 1 {
+            animation.upscale(upscale);
+        }
+        files::save_animation(&animation, out_file_name, format);
+        return;
+    }
+
+    let mut source = input.unclipped_image();
+    if let Some((width, height)) = new_size(source.width, source.height) {
+        source = resize::resize(&source, width, height, filter);
+    }
+    adjustments.apply(std::slice::from_mut(&mut source));
+
+    let palette = settings.build_palette(&source.pixels);
 
     eprintln!("Generated palette. Dithering...");
 
+    if let Some(gamut_file_name) = arg_matches.value_of_os("GAMUT_PREVIEW") {
+        let pixels: Vec<_> = source.to_srgb8().pixels().cloned().collect();
+        let plot = preview::GamutPlot::new(&palette, &pixels, distance2_func);
+        eprintln!("{} distinct image colors lie outside the palette's gamut", plot.outside_colors);
+        if std::path::Path::new(gamut_file_name).extension().is_some_and(|ext| ext.eq_ignore_ascii_case("svg")) {
+            std::fs::write(gamut_file_name, plot.to_svg()).unwrap();
+        } else {
+            plot.to_image().save(gamut_file_name).unwrap();
+        }
+    }
+
     let linear_palette: Vec<_> = palette.iter().cloned().map(LinearRgb::from).collect();
     let lab_palette: Vec<_> = linear_palette.iter().cloned().map(Lab::from).collect();
 
-    let algorithm = match arg_matches.value_of("ALGORITHM").unwrap() {
-        "nearest" => nearest,
-        "nearest2:d^-2" => nearest2_inv2_dist,
-        "nearest2:project" => nearest2_project,
-        "simplex" => tight_simplex,
-        "floyd-steinberg" => {
-            floyd_steinberg(&mut img, &palette, &linear_palette, &lab_palette, false, false, distance2_func);
-            img.save(out_file_name).unwrap();
-            return;
-        },
-        "floyd-steinberg+serpentine" => {
-            floyd_steinberg(&mut img, &palette, &linear_palette, &lab_palette, true, false, distance2_func);
-            img.save(out_file_name).unwrap();
-            return;
-        },
-        "floyd-steinberg+clamp" => {
-            floyd_steinberg(&mut img, &palette, &linear_palette, &lab_palette, false, true, distance2_func);
-            img.save(out_file_name).unwrap();
-            return;
-        },
-        "floyd-steinberg+clamp+serpentine" => {
-            floyd_steinberg(&mut img, &palette, &linear_palette, &lab_palette, true, true, distance2_func);
-            img.save(out_file_name).unwrap();
-            return;
-        },
-        // For debugging
-        "visualize-bias" => visualize_bias,
-        _ => panic!("Unrecognized algorithm!")
-    };
-
-    // Actually do the dithering
-    img.enumerate_pixels_mut().par_bridge().for_each(|(x, y, pixel)| {
-        let bias = bias_func(x, y);
+    let gamut_mask = arg_matches.value_of_os("GAMUT_MASK");
+    if arg_matches.is_present("report-gamut") || gamut_mask.is_some() {
+        let errors = dither::gamut_errors(&source, &palette, &linear_palette, &lab_palette, distance2_func);
+
+        if arg_matches.is_present("report-gamut") {
+            let outside = errors.iter().filter(|&&error| error > dither::GAMUT_TOLERANCE).count();
+            let total_error: f64 = errors.iter().sum();
+            let (worst_index, worst_error) = errors.iter().cloned().enumerate().fold((0, 0.0), |(i1, e1), (i2, e2)| if e2 > e1 { (i2, e2) } else { (i1, e1) });
+            println!("Pixels outside the palette's gamut: {} of {} ({:.2}%)", outside, errors.len(), 100.0 * outside as f64 / errors.len() as f64);
+            if outside > 0 {
+                println!("Mean projection error: {:.3} over all pixels, {:.3} over pixels outside the gamut", total_error / errors.len() as f64, total_error / outside as f64);
+                println!("Worst projection error: {:.3} at ({}, {})", worst_error, worst_index as u32 % source.width, worst_index as u32 / source.width);
+            }
+        }
 
-        if x == 0 && y % 10 == 0 {
-            eprintln!("Processing line {}", y);
+        if let Some(mask_file_name) = gamut_mask {
+            files::save_image(&preview::render_gamut_mask(&source, &errors), mask_file_name, None);
         }
+    }
 
-        //eprintln!();
-        //eprintln!("Pixel at ({}, {})", x, y);
-        *pixel = algorithm(*pixel, &palette, &linear_palette, &lab_palette, bias, distance2_func);
-    });
+    let img = settings.dither(&source, &palette, &linear_palette, &lab_palette);
+
+    // Everything else looks at the colors as they will be seen, but the file gets the values that produce them
+    let palette_output = settings.palette_output(&palette);
+    let written = palette_output.apply(&img, &palette);
+    let output = if upscale > 1 { resize::upscale(&written, upscale) } else { written };
+    match export {
+        Some(ref export) => {
+            // Indexed exports write the index of the entry the dither chose, which the written color alone can't give
+            let positions = dither::palette_positions(&img, &palette);
+            let positions = if upscale > 1 { resize::upscale_values(&positions, img.width(), img.height(), upscale) } else { positions };
+            export::save(export, &output, &positions, &palette_output, out_file_name)
+        },
+        None => files::save_image(&output, out_file_name, format),
+    }
 
-    img.save(out_file_name).unwrap();
+    if let Some(kind) = arg_matches.value_of("PREVIEW") {
+        terminal::show(kind, &img);
+    }
 
-    if arg_matches.is_present("summarize") {
-        let root_palette: &[_] = match arg_matches.value_of("PALETTE").unwrap() {
-            "petz" | "petz_safe" => &palettes::PETZ_SOURCE,
-            _ => &palette
-        };
+    if arg_matches.is_present("METRICS") {
+        let metrics = metrics::Metrics::compute(&source, &LinearImage::from_rgb8(&img));
+        if arg_matches.value_of("METRICS") == Some("json") {
+            print!("{}", metrics.to_json());
+        } else {
+            print!("{}", metrics.to_text());
+        }
+    }
 
-        let mut counts = [0].repeat(root_palette.len());
+    let summarize = arg_matches.is_present("summarize");
+    let palette_preview = arg_matches.value_of_os("PALETTE_PREVIEW");
+    if summarize || palette_preview.is_some() {
+        // Counted by the palette's own indices, which for hardware palettes can skip some and repeat others
+        let root_indices = &palette_output.indices;
+        let mut counts = [0].repeat(root_indices.iter().max().map_or(0, |&index| index + 1));
 
         for &pixel in img.pixels() {
-            let index = root_palette.iter().position(|&pal_color| pal_color == pixel).unwrap();
-            counts[index] += 1;
+            let position = palette.iter().position(|&pal_color| pal_color == pixel).unwrap();
+            counts[root_indices[position]] += 1;
         }
 
-        for (i, &count) in counts.iter().enumerate() {
-          if count > 0 {
-            println!("{}:\t{}", i, count);
-          }
+        if summarize {
+            for (i, &count) in counts.iter().enumerate() {
+              if count > 0 {
+                println!("{}:\t{}", i, count);
+              }
+            }
+        }
+
+        if let Some(preview_file_name) = palette_preview {
+            let palette_counts: Vec<usize> = root_indices.iter().map(|&index| counts[index]).collect();
+            let labels = if arg_matches.is_present("preview-labels") { Some(&root_indices[..]) } else { None };
+            let usage = if summarize { Some(&palette_counts[..]) } else { None };
+            files::save_image(&preview::render_swatches(&palette, labels, usage), preview_file_name, None);
         }
     }
-}
\ No newline at end of file
+}
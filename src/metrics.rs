@@ -0,0 +1,161 @@
+use crate::color::{self, LinearRgb, LinearImage, Lab};
+
+use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
+
+// The standard deviations of the low-pass filters used to compare images as they would be seen from further away. A
+// good dither should be nearly indistinguishable from the original once the dither pattern is blurred away.
+pub const LOW_PASS_SIGMAS: [f64; 4] = [1.0, 2.0, 4.0, 8.0];
+
+pub struct Metrics {
+    pub psnr: f64,
+    pub ssim: f64,
+    // The difference between the average colors of the two images, in linear RGB and as a CIEDE2000 distance
+    pub mean_color_difference: [f64; 3],
+    pub mean_color_delta_e: f64,
+    // The mean CIEDE2000 distance after blurring both images, for each of LOW_PASS_SIGMAS
+    pub low_pass_delta_e: Vec<f64>,
+}
+
+impl Metrics {
+    pub fn compute(original: &LinearImage, dithered: &LinearImage) -> Metrics {
+        assert!((original.width, original.height) == (dithered.width, dithered.height), "Images to compare must be the same size!");
+        let (width, height) = (original.width, original.height);
+
+        // PSNR and SSIM are traditionally measured on encoded (e.g. sRGB) values from 0 to 255. These are rounded to
+        // 16-bit precision, which leaves 8-bit values exact (so identical images have an infinite PSNR) without losing
+        // the extra precision of deeper images.
+        let encoded = |img: &LinearImage| -> Vec<[f64; 3]> {
+            let encode = |value: f64| (color::encode(value.clamp(0.0, 1.0)) * 65535.0).round() / 257.0;
+            img.pixels.iter().map(|p| [encode(p.data[0]), encode(p.data[1]), encode(p.data[2])]).collect()
+        };
+        let (encoded_original, encoded_dithered) = (encoded(original), encoded(dithered));
+
+        let squared_error: f64 = encoded_original.iter().zip(&encoded_dithered).map(|(p1, p2)| {
+            (0..3).map(|c| (p1[c] - p2[c]).powi(2)).sum::<f64>()
+        }).sum();
+        let mse = squared_error / (3.0 * width as f64 * height as f64);
+        let psnr = 10.0 * (255.0 * 255.0 / mse).log10();
+
+        let luma = |pixels: &[[f64; 3]]| -> Vec<f64> {
+            pixels.iter().map(|p| 0.299 * p[0] + 0.587 * p[1] + 0.114 * p[2]).collect()
+        };
+        let ssim = ssim(&luma(&encoded_original), &luma(&encoded_dithered), width, height);
+
+        let linear_original = &original.pixels;
+        let linear_dithered = &dithered.pixels;
+
+        let mean = |pixels: &[LinearRgb]| {
+            let mut total = [0.0; 3];
+            for pixel in pixels {
+                for (total, value) in total.iter_mut().zip(&pixel.data) {
+                    *total += value;
+                }
+            }
+            LinearRgb { data: [total[0] / pixels.len() as f64, total[1] / pixels.len() as f64, total[2] / pixels.len() as f64] }
+        };
+        let mean_original = mean(linear_original);
+        let mean_dithered = mean(linear_dithered);
+        let mean_color_difference = [
+            mean_dithered.data[0] - mean_original.data[0],
+            mean_dithered.data[1] - mean_original.data[1],
+            mean_dithered.data[2] - mean_original.data[2],
+        ];
+        let mean_color_delta_e = Lab::ciede2000_distance2(Lab::from(mean_original), Lab::from(mean_dithered)).sqrt();
+
+        let low_pass_delta_e = LOW_PASS_SIGMAS.iter().map(|&sigma| {
+            let blurred_original = gaussian_blur(linear_original, width, height, sigma);
+            let blurred_dithered = gaussian_blur(linear_dithered, width, height, sigma);
+            let total: f64 = blurred_original.into_par_iter().zip(blurred_dithered).map(|(p1, p2)| {
+                Lab::ciede2000_distance2(Lab::from(p1), Lab::from(p2)).sqrt()
+            }).sum();
+            total / (width as f64 * height as f64)
+        }).collect();
+
+        Metrics { psnr, ssim, mean_color_difference, mean_color_delta_e, low_pass_delta_e }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut text = format!("PSNR: {:.3} dB\n", self.psnr);
+        text += &format!("SSIM: {:.5}\n", self.ssim);
+        text += &format!("Mean color difference: {:+.6} {:+.6} {:+.6} (linear RGB), {:.4} (CIEDE2000)\n",
+                         self.mean_color_difference[0], self.mean_color_difference[1], self.mean_color_difference[2], self.mean_color_delta_e);
+        for (sigma, delta_e) in LOW_PASS_SIGMAS.iter().zip(&self.low_pass_delta_e) {
+            text += &format!("Mean CIEDE2000 after blurring (sigma = {}): {:.4}\n", sigma, delta_e);
+        }
+        text
+    }
+
+    pub fn to_json(&self) -> String {
+        // JSON has no representation for infinity, which is the PSNR of identical images
+        let psnr = if self.psnr.is_finite() { format!("{}", self.psnr) } else { "null".to_string() };
+        let low_pass: Vec<String> = LOW_PASS_SIGMAS.iter().zip(&self.low_pass_delta_e).map(|(sigma, delta_e)| {
+            format!("{{\"sigma\": {}, \"delta_e\": {}}}", sigma, delta_e)
+        }).collect();
+        format!("{{\"psnr\": {}, \"ssim\": {}, \"mean_color_difference\": [{}, {}, {}], \"mean_color_delta_e\": {}, \"low_pass_delta_e\": [{}]}}\n",
+                psnr, self.ssim,
+                self.mean_color_difference[0], self.mean_color_difference[1], self.mean_color_difference[2],
+                self.mean_color_delta_e, low_pass.join(", "))
+    }
+}
+
+fn gaussian_kernel(sigma: f64) -> Vec<f64> {
+    let radius = (3.0 * sigma).ceil() as i64;
+    let weights: Vec<f64> = (-radius..=radius).map(|offset| (-(offset * offset) as f64 / (2.0 * sigma * sigma)).exp()).collect();
+    let total: f64 = weights.iter().sum();
+    weights.into_iter().map(|weight| weight / total).collect()
+}
+
+// Separable Gaussian blur on a row-major buffer, treating pixels past the edge as copies of the edge pixels
+fn blur<T: Copy + Send + Sync>(values: &[T], width: u32, height: u32, sigma: f64, zero: T, add_scaled: fn(T, T, f64) -> T) -> Vec<T> {
+    let kernel = gaussian_kernel(sigma);
+    let radius = (kernel.len() / 2) as i64;
+    let (width, height) = (width as i64, height as i64);
+
+    let pass = |input: &[T], horizontal: bool| -> Vec<T> {
+        (0..width * height).into_par_iter().map(|i| {
+            let (x, y) = (i % width, i / width);
+            kernel.iter().enumerate().fold(zero, |total, (k, &weight)| {
+                let offset = k as i64 - radius;
+                let (sx, sy) = if horizontal {
+                    ((x + offset).max(0).min(width - 1), y)
+                } else {
+                    (x, (y + offset).max(0).min(height - 1))
+                };
+                add_scaled(total, input[(sy * width + sx) as usize], weight)
+            })
+        }).collect()
+    };
+
+    pass(&pass(values, true), false)
+}
+
+pub fn gaussian_blur(pixels: &[LinearRgb], width: u32, height: u32, sigma: f64) -> Vec<LinearRgb> {
+    blur(pixels, width, height, sigma, LinearRgb { data: [0.0; 3] }, |total, pixel, weight| LinearRgb { data: [
+        total.data[0] + pixel.data[0] * weight,
+        total.data[1] + pixel.data[1] * weight,
+        total.data[2] + pixel.data[2] * weight,
+    ] })
+}
+
+// The structural similarity index of Wang et al. (2004) on a single channel with values in [0, 255], using the usual
+// 11x11 Gaussian window with a standard deviation of 1.5.
+fn ssim(image1: &[f64], image2: &[f64], width: u32, height: u32) -> f64 {
+    const C1: f64 = (0.01 * 255.0) * (0.01 * 255.0);
+    const C2: f64 = (0.03 * 255.0) * (0.03 * 255.0);
+    let local_mean = |values: &[f64]| blur(values, width, height, 1.5, 0.0, |total, value, weight| total + value * weight);
+
+    let mu1 = local_mean(image1);
+    let mu2 = local_mean(image2);
+    let mean11 = local_mean(&image1.iter().map(|v| v * v).collect::<Vec<_>>());
+    let mean22 = local_mean(&image2.iter().map(|v| v * v).collect::<Vec<_>>());
+    let mean12 = local_mean(&image1.iter().zip(image2).map(|(v1, v2)| v1 * v2).collect::<Vec<_>>());
+
+    let total: f64 = (0..image1.len()).map(|i| {
+        let variance1 = mean11[i] - mu1[i] * mu1[i];
+        let variance2 = mean22[i] - mu2[i] * mu2[i];
+        let covariance = mean12[i] - mu1[i] * mu2[i];
+        ((2.0 * mu1[i] * mu2[i] + C1) * (2.0 * covariance + C2)) /
+            ((mu1[i] * mu1[i] + mu2[i] * mu2[i] + C1) * (variance1 + variance2 + C2))
+    }).sum();
+    total / image1.len() as f64
+}
@@ -1,7 +1,22 @@
-use crate::color::{self, Srgb8, LinearRgb, Lab};
-use crate::geom::{determinant, subtract, midpoint};
+use crate::color::{self, Srgb8, LinearRgb, LinearImage, Lab, ColorSpace};
+use crate::geom::{determinant, subtract, midpoint, ConvexHull};
+use crate::preview;
 
 use image::Pixel;
+use rayon::iter::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator};
+
+// Parses a channel layout like `r5g6b5` (as in RGB565) into the number of bits of red, green and blue, each from 1 to 8
+pub fn parse_channel_bits(spec: &str) -> Option<[usize; 3]> {
+    let mut bits = [0; 3];
+    let mut rest = spec.to_ascii_lowercase();
+    for (channel, letter) in ['r', 'g', 'b'].iter().enumerate() {
+        rest = rest.strip_prefix(*letter)?.to_string();
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        bits[channel] = rest[..digits].parse().ok().filter(|bits| (1..=8).contains(bits))?;
+        rest = rest[digits..].to_string();
+    }
+    if rest.is_empty() { Some(bits) } else { None }
+}
 
 pub fn grid(r_levels: usize, g_levels: usize, b_levels: usize) -> Vec<Srgb8> {
     let mut palette = Vec::with_capacity(r_levels * g_levels * b_levels);
@@ -404,67 +419,846 @@ pub const PETZ_SOURCE: [Srgb8; 256] = [
     image::Rgb([0xD4, 0xD0, 0xC8]),
 ];
 
-// Simplex dithering wants small simplices containing every target color, so we attempt to build a palette explicitly for
-// that purpose. We start with the RGB cube, divided into 6 simplices based on hue (all sharing white and black). Then, since
-// that palette only contains 8 colors, we repeatedly split simplices in half by introducing a new color on their longest
-// edge, up until we have the desired number of palette colors. Currently, simplices are chosen for splitting based on a
-// heuristic squaring the maximal perceptual distance withing the simplex (since large simplices benefit from splitting more)
-// and multiplying by the number of target points inside that simplex (since those splits will help a larger fraction of the
-// image).
-//
-// As a final post-processing pass, each simplex is shrunk to fit the colors it contains to attempt to reduce the error.
-pub fn make_simplex_palette(palette_size: usize, pixels: impl Iterator<Item=image::Rgb<u8>>, distance2: fn(Lab, Lab) -> f64) -> Vec<image::Rgb<u8>> {
-    struct SimplexCut {
-        vertices_rgb: [Srgb8; 4],
-        vertices_lin: [LinearRgb; 4],
-        vertices_lab: [Lab; 4],
-        diameter2: f64,
-        diameter_edge: [usize; 2],
-        points: Vec<[f64; 4]>
-    }
-
-    impl SimplexCut {
-        // The heuristic used to select which simplex to cut
-        fn weight(&self) -> f64 {
-            self.diameter2 * self.points.len() as f64
-        }
-
-        // Shrink to fit contained points
-        fn optimize(&mut self, referenced_points: &mut std::collections::HashMap<image::Rgb<u8>, usize>, distance2: fn(Lab, Lab) -> f64) {
-            let mut changed = false;
-            // Loop through all edges and maximally shrink that edge
-            for opt_vertex in 0..4 {
-                for other_vertex in (0..4).filter(|&v| v != opt_vertex) {
-                    let mut max_ratio = 0.0;
-                    for coords in &self.points {
-                        let ratio = coords[opt_vertex] / coords[other_vertex];
-                        if ratio > max_ratio {
-                            max_ratio = ratio;
-                        }
+// The Ricoh 2C02 PPU used in the NTSC NES, in hardware index order ($00-$3F). The PPU generates composite video
+// directly, so there is no single canonical RGB palette; these are the widely used values from
+// https://en.wikipedia.org/wiki/List_of_video_game_console_palettes#Famicom/NES. Most of the unusable $xD-$xF
+// entries are black, so the palette contains duplicates.
+pub const NES: [Srgb8; 64] = [
+    image::Rgb([0x7c,0x7c,0x7c]),
+    image::Rgb([0x00,0x00,0xfc]),
+    image::Rgb([0x00,0x00,0xbc]),
+    image::Rgb([0x44,0x28,0xbc]),
+    image::Rgb([0x94,0x00,0x84]),
+    image::Rgb([0xa8,0x00,0x20]),
+    image::Rgb([0xa8,0x10,0x00]),
+    image::Rgb([0x88,0x14,0x00]),
+    image::Rgb([0x50,0x30,0x00]),
+    image::Rgb([0x00,0x78,0x00]),
+    image::Rgb([0x00,0x68,0x00]),
+    image::Rgb([0x00,0x58,0x00]),
+    image::Rgb([0x00,0x40,0x58]),
+    image::Rgb([0x00,0x00,0x00]),
+    image::Rgb([0x00,0x00,0x00]),
+    image::Rgb([0x00,0x00,0x00]),
+    image::Rgb([0xbc,0xbc,0xbc]),
+    image::Rgb([0x00,0x78,0xf8]),
+    image::Rgb([0x00,0x58,0xf8]),
+    image::Rgb([0x68,0x44,0xfc]),
+    image::Rgb([0xd8,0x00,0xcc]),
+    image::Rgb([0xe4,0x00,0x58]),
+    image::Rgb([0xf8,0x38,0x00]),
+    image::Rgb([0xe4,0x5c,0x10]),
+    image::Rgb([0xac,0x7c,0x00]),
+    image::Rgb([0x00,0xb8,0x00]),
+    image::Rgb([0x00,0xa8,0x00]),
+    image::Rgb([0x00,0xa8,0x44]),
+    image::Rgb([0x00,0x88,0x88]),
+    image::Rgb([0x00,0x00,0x00]),
+    image::Rgb([0x00,0x00,0x00]),
+    image::Rgb([0x00,0x00,0x00]),
+    image::Rgb([0xf8,0xf8,0xf8]),
+    image::Rgb([0x3c,0xbc,0xfc]),
+    image::Rgb([0x68,0x88,0xfc]),
+    image::Rgb([0x98,0x78,0xf8]),
+    image::Rgb([0xf8,0x78,0xf8]),
+    image::Rgb([0xf8,0x58,0x98]),
+    image::Rgb([0xf8,0x78,0x58]),
+    image::Rgb([0xfc,0xa0,0x44]),
+    image::Rgb([0xf8,0xb8,0x00]),
+    image::Rgb([0xb8,0xf8,0x18]),
+    image::Rgb([0x58,0xd8,0x54]),
+    image::Rgb([0x58,0xf8,0x98]),
+    image::Rgb([0x00,0xe8,0xd8]),
+    image::Rgb([0x78,0x78,0x78]),
+    image::Rgb([0x00,0x00,0x00]),
+    image::Rgb([0x00,0x00,0x00]),
+    image::Rgb([0xfc,0xfc,0xfc]),
+    image::Rgb([0xa4,0xe4,0xfc]),
+    image::Rgb([0xb8,0xb8,0xf8]),
+    image::Rgb([0xd8,0xb8,0xf8]),
+    image::Rgb([0xf8,0xb8,0xf8]),
+    image::Rgb([0xf8,0xa4,0xc0]),
+    image::Rgb([0xf0,0xd0,0xb0]),
+    image::Rgb([0xfc,0xe0,0xa8]),
+    image::Rgb([0xf8,0xd8,0x78]),
+    image::Rgb([0xd8,0xf8,0x78]),
+    image::Rgb([0xb8,0xf8,0xb8]),
+    image::Rgb([0xb8,0xf8,0xd8]),
+    image::Rgb([0x00,0xfc,0xfc]),
+    image::Rgb([0xf8,0xd8,0xf8]),
+    image::Rgb([0x00,0x00,0x00]),
+    image::Rgb([0x00,0x00,0x00]),
+];
+
+// The Commodore 64's VIC-II, in hardware index order, using Philip "Pepto" Timmermann's measured palette from
+// https://www.pepto.de/projects/colorvic/2001/
+pub const C64: [Srgb8; 16] = [
+    image::Rgb([0x00,0x00,0x00]),
+    image::Rgb([0xff,0xff,0xff]),
+    image::Rgb([0x68,0x37,0x2b]),
+    image::Rgb([0x70,0xa4,0xb2]),
+    image::Rgb([0x6f,0x3d,0x86]),
+    image::Rgb([0x58,0x8d,0x43]),
+    image::Rgb([0x35,0x28,0x79]),
+    image::Rgb([0xb8,0xc7,0x6f]),
+    image::Rgb([0x6f,0x4f,0x25]),
+    image::Rgb([0x43,0x39,0x00]),
+    image::Rgb([0x9a,0x67,0x59]),
+    image::Rgb([0x44,0x44,0x44]),
+    image::Rgb([0x6c,0x6c,0x6c]),
+    image::Rgb([0x9a,0xd2,0x84]),
+    image::Rgb([0x6c,0x5e,0xb5]),
+    image::Rgb([0x95,0x95,0x95]),
+];
+
+// The four IBM CGA 320x200 palettes: palette 0 (green/red/brown) and palette 1 (cyan/magenta/white), each in
+// low and high intensity, with the default black background. See https://en.wikipedia.org/wiki/Color_Graphics_Adapter
+pub const CGA: [Srgb8; 16] = [
+    image::Rgb([0x00,0x00,0x00]),
+    image::Rgb([0x00,0xaa,0x00]),
+    image::Rgb([0xaa,0x00,0x00]),
+    image::Rgb([0xaa,0x55,0x00]),
+    image::Rgb([0x00,0x00,0x00]),
+    image::Rgb([0x55,0xff,0x55]),
+    image::Rgb([0xff,0x55,0x55]),
+    image::Rgb([0xff,0xff,0x55]),
+    image::Rgb([0x00,0x00,0x00]),
+    image::Rgb([0x00,0xaa,0xaa]),
+    image::Rgb([0xaa,0x00,0xaa]),
+    image::Rgb([0xaa,0xaa,0xaa]),
+    image::Rgb([0x00,0x00,0x00]),
+    image::Rgb([0x55,0xff,0xff]),
+    image::Rgb([0xff,0x55,0xff]),
+    image::Rgb([0xff,0xff,0xff]),
+];
+
+// The Sinclair ZX Spectrum's normal (first half) and BRIGHT (second half) colors, in hardware index order. Black is
+// the same in both modes. See https://en.wikipedia.org/wiki/ZX_Spectrum_graphic_modes#Colour_palette
+pub const ZX_SPECTRUM: [Srgb8; 16] = [
+    image::Rgb([0x00,0x00,0x00]),
+    image::Rgb([0x00,0x00,0xd7]),
+    image::Rgb([0xd7,0x00,0x00]),
+    image::Rgb([0xd7,0x00,0xd7]),
+    image::Rgb([0x00,0xd7,0x00]),
+    image::Rgb([0x00,0xd7,0xd7]),
+    image::Rgb([0xd7,0xd7,0x00]),
+    image::Rgb([0xd7,0xd7,0xd7]),
+    image::Rgb([0x00,0x00,0x00]),
+    image::Rgb([0x00,0x00,0xff]),
+    image::Rgb([0xff,0x00,0x00]),
+    image::Rgb([0xff,0x00,0xff]),
+    image::Rgb([0x00,0xff,0x00]),
+    image::Rgb([0x00,0xff,0xff]),
+    image::Rgb([0xff,0xff,0x00]),
+    image::Rgb([0xff,0xff,0xff]),
+];
+
+// The four shades of green on the original DMG Game Boy's LCD, from darkest to lightest. These are approximations
+// of the unlit panel, as used by most emulators. See https://en.wikipedia.org/wiki/List_of_video_game_console_palettes#Game_Boy
+pub const GAME_BOY: [Srgb8; 4] = [
+    image::Rgb([0x0f,0x38,0x0f]),
+    image::Rgb([0x30,0x62,0x30]),
+    image::Rgb([0x8b,0xac,0x0f]),
+    image::Rgb([0x9b,0xbc,0x0f]),
+];
+
+// The PICO-8 fantasy console's standard 16 colors, followed by the 16 "secret" colors accessible at indices 128-143.
+// Taken from https://pico-8.fandom.com/wiki/Palette
+pub const PICO8: [Srgb8; 32] = [
+    image::Rgb([0x00,0x00,0x00]),
+    image::Rgb([0x1d,0x2b,0x53]),
+    image::Rgb([0x7e,0x25,0x53]),
+    image::Rgb([0x00,0x87,0x51]),
+    image::Rgb([0xab,0x52,0x36]),
+    image::Rgb([0x5f,0x57,0x4f]),
+    image::Rgb([0xc2,0xc3,0xc7]),
+    image::Rgb([0xff,0xf1,0xe8]),
+    image::Rgb([0xff,0x00,0x4d]),
+    image::Rgb([0xff,0xa3,0x00]),
+    image::Rgb([0xff,0xec,0x27]),
+    image::Rgb([0x00,0xe4,0x36]),
+    image::Rgb([0x29,0xad,0xff]),
+    image::Rgb([0x83,0x76,0x9c]),
+    image::Rgb([0xff,0x77,0xa8]),
+    image::Rgb([0xff,0xcc,0xaa]),
+    image::Rgb([0x29,0x18,0x14]),
+    image::Rgb([0x11,0x1d,0x35]),
+    image::Rgb([0x42,0x21,0x36]),
+    image::Rgb([0x12,0x53,0x59]),
+    image::Rgb([0x74,0x2f,0x29]),
+    image::Rgb([0x49,0x33,0x3b]),
+    image::Rgb([0xa2,0x88,0x79]),
+    image::Rgb([0xf3,0xef,0x7d]),
+    image::Rgb([0xbe,0x12,0x50]),
+    image::Rgb([0xff,0x6c,0x24]),
+    image::Rgb([0xa8,0xe7,0x2e]),
+    image::Rgb([0x00,0xb5,0x43]),
+    image::Rgb([0x06,0x5a,0xb5]),
+    image::Rgb([0x75,0x46,0x65]),
+    image::Rgb([0xff,0x6e,0x59]),
+    image::Rgb([0xff,0x9d,0x81]),
+];
+
+// The Atari 2600's NTSC TIA palette, in hardware order (16 hues of 8 luminances each, ignoring the unused low bit).
+// Taken from https://en.wikipedia.org/wiki/List_of_video_game_console_palettes#Atari_2600
+pub const ATARI2600_NTSC: [Srgb8; 128] = [
+    image::Rgb([0x00,0x00,0x00]),
+    image::Rgb([0x40,0x40,0x40]),
+    image::Rgb([0x6c,0x6c,0x6c]),
+    image::Rgb([0x90,0x90,0x90]),
+    image::Rgb([0xb0,0xb0,0xb0]),
+    image::Rgb([0xc8,0xc8,0xc8]),
+    image::Rgb([0xdc,0xdc,0xdc]),
+    image::Rgb([0xec,0xec,0xec]),
+    image::Rgb([0x44,0x44,0x00]),
+    image::Rgb([0x64,0x64,0x10]),
+    image::Rgb([0x84,0x84,0x24]),
+    image::Rgb([0xa0,0xa0,0x34]),
+    image::Rgb([0xb8,0xb8,0x40]),
+    image::Rgb([0xd0,0xd0,0x50]),
+    image::Rgb([0xe8,0xe8,0x5c]),
+    image::Rgb([0xfc,0xfc,0x68]),
+    image::Rgb([0x70,0x28,0x00]),
+    image::Rgb([0x84,0x44,0x14]),
+    image::Rgb([0x98,0x5c,0x28]),
+    image::Rgb([0xac,0x78,0x3c]),
+    image::Rgb([0xbc,0x8c,0x4c]),
+    image::Rgb([0xcc,0xa0,0x5c]),
+    image::Rgb([0xdc,0xb4,0x68]),
+    image::Rgb([0xec,0xc8,0x78]),
+    image::Rgb([0x84,0x18,0x00]),
+    image::Rgb([0x98,0x34,0x18]),
+    image::Rgb([0xac,0x50,0x30]),
+    image::Rgb([0xc0,0x68,0x48]),
+    image::Rgb([0xd0,0x80,0x5c]),
+    image::Rgb([0xe0,0x94,0x70]),
+    image::Rgb([0xec,0xa8,0x80]),
+    image::Rgb([0xfc,0xbc,0x94]),
+    image::Rgb([0x88,0x00,0x00]),
+    image::Rgb([0x9c,0x20,0x20]),
+    image::Rgb([0xb0,0x3c,0x3c]),
+    image::Rgb([0xc0,0x58,0x58]),
+    image::Rgb([0xd0,0x70,0x70]),
+    image::Rgb([0xe0,0x88,0x88]),
+    image::Rgb([0xec,0xa0,0xa0]),
+    image::Rgb([0xfc,0xb4,0xb4]),
+    image::Rgb([0x78,0x00,0x5c]),
+    image::Rgb([0x8c,0x20,0x74]),
+    image::Rgb([0xa0,0x3c,0x88]),
+    image::Rgb([0xb0,0x58,0x9c]),
+    image::Rgb([0xc0,0x70,0xb0]),
+    image::Rgb([0xd0,0x84,0xc0]),
+    image::Rgb([0xdc,0x9c,0xd0]),
+    image::Rgb([0xec,0xb0,0xe0]),
+    image::Rgb([0x48,0x00,0x78]),
+    image::Rgb([0x60,0x20,0x90]),
+    image::Rgb([0x78,0x3c,0xa4]),
+    image::Rgb([0x8c,0x58,0xb8]),
+    image::Rgb([0xa0,0x70,0xcc]),
+    image::Rgb([0xb4,0x84,0xdc]),
+    image::Rgb([0xc4,0x9c,0xec]),
+    image::Rgb([0xd4,0xb0,0xfc]),
+    image::Rgb([0x14,0x00,0x84]),
+    image::Rgb([0x30,0x20,0x98]),
+    image::Rgb([0x4c,0x3c,0xac]),
+    image::Rgb([0x68,0x58,0xc0]),
+    image::Rgb([0x7c,0x70,0xd0]),
+    image::Rgb([0x94,0x88,0xe0]),
+    image::Rgb([0xa8,0xa0,0xec]),
+    image::Rgb([0xbc,0xb4,0xfc]),
+    image::Rgb([0x00,0x00,0x88]),
+    image::Rgb([0x1c,0x20,0x9c]),
+    image::Rgb([0x38,0x40,0xb0]),
+    image::Rgb([0x50,0x5c,0xc0]),
+    image::Rgb([0x68,0x74,0xd0]),
+    image::Rgb([0x7c,0x8c,0xe0]),
+    image::Rgb([0x90,0xa4,0xec]),
+    image::Rgb([0xa4,0xb8,0xfc]),
+    image::Rgb([0x00,0x18,0x7c]),
+    image::Rgb([0x1c,0x38,0x90]),
+    image::Rgb([0x38,0x54,0xa8]),
+    image::Rgb([0x50,0x70,0xbc]),
+    image::Rgb([0x68,0x88,0xcc]),
+    image::Rgb([0x7c,0x9c,0xdc]),
+    image::Rgb([0x90,0xb4,0xec]),
+    image::Rgb([0xa4,0xc8,0xfc]),
+    image::Rgb([0x00,0x2c,0x5c]),
+    image::Rgb([0x1c,0x4c,0x78]),
+    image::Rgb([0x38,0x68,0x90]),
+    image::Rgb([0x50,0x84,0xac]),
+    image::Rgb([0x68,0x9c,0xc0]),
+    image::Rgb([0x7c,0xb4,0xd4]),
+    image::Rgb([0x90,0xcc,0xe8]),
+    image::Rgb([0xa4,0xe0,0xfc]),
+    image::Rgb([0x00,0x3c,0x2c]),
+    image::Rgb([0x1c,0x5c,0x48]),
+    image::Rgb([0x38,0x7c,0x64]),
+    image::Rgb([0x50,0x9c,0x80]),
+    image::Rgb([0x68,0xb4,0x94]),
+    image::Rgb([0x7c,0xd0,0xac]),
+    image::Rgb([0x90,0xe4,0xc0]),
+    image::Rgb([0xa4,0xfc,0xd4]),
+    image::Rgb([0x00,0x3c,0x00]),
+    image::Rgb([0x20,0x5c,0x20]),
+    image::Rgb([0x40,0x7c,0x40]),
+    image::Rgb([0x5c,0x9c,0x5c]),
+    image::Rgb([0x74,0xb4,0x74]),
+    image::Rgb([0x8c,0xd0,0x8c]),
+    image::Rgb([0xa4,0xe4,0xa4]),
+    image::Rgb([0xb8,0xfc,0xb8]),
+    image::Rgb([0x14,0x38,0x00]),
+    image::Rgb([0x34,0x5c,0x1c]),
+    image::Rgb([0x50,0x7c,0x38]),
+    image::Rgb([0x6c,0x98,0x50]),
+    image::Rgb([0x84,0xb4,0x68]),
+    image::Rgb([0x9c,0xcc,0x7c]),
+    image::Rgb([0xb4,0xe4,0x90]),
+    image::Rgb([0xc8,0xfc,0xa4]),
+    image::Rgb([0x2c,0x30,0x00]),
+    image::Rgb([0x4c,0x50,0x1c]),
+    image::Rgb([0x68,0x70,0x34]),
+    image::Rgb([0x84,0x8c,0x4c]),
+    image::Rgb([0x9c,0xa8,0x64]),
+    image::Rgb([0xb4,0xc0,0x78]),
+    image::Rgb([0xcc,0xd4,0x88]),
+    image::Rgb([0xe0,0xec,0x9c]),
+    image::Rgb([0x44,0x28,0x00]),
+    image::Rgb([0x64,0x48,0x18]),
+    image::Rgb([0x84,0x68,0x30]),
+    image::Rgb([0xa0,0x84,0x44]),
+    image::Rgb([0xb8,0x9c,0x58]),
+    image::Rgb([0xd0,0xb4,0x6c]),
+    image::Rgb([0xe8,0xcc,0x7c]),
+    image::Rgb([0xfc,0xe0,0x8c]),
+];
+
+// The full 64-color EGA palette, in hardware index order. Each index is a 6-bit rgbRGB value, where the lowercase
+// bits add a third of full intensity and the uppercase bits add two thirds.
+// See https://en.wikipedia.org/wiki/Enhanced_Graphics_Adapter#Color_palette
+pub fn ega() -> Vec<Srgb8> {
+    (0..64u8).map(|index| {
+        let channel = |high_bit: u8, low_bit: u8| ((index >> high_bit) & 1) * 0xaa + ((index >> low_bit) & 1) * 0x55;
+        image::Rgb([channel(2, 5), channel(1, 4), channel(0, 3)])
+    }).collect()
+}
+
+// The Amstrad CPC's 27 firmware colors, in firmware index order. Each channel is off, half or full intensity, and the
+// firmware number is 9*green + 3*red + blue. See https://www.cpcwiki.eu/index.php/CPC_Palette
+pub fn amstrad_cpc() -> Vec<Srgb8> {
+    let levels = [0x00, 0x80, 0xff];
+    (0..27).map(|index| image::Rgb([levels[index / 3 % 3], levels[index / 9], levels[index % 3]])).collect()
+}
+
+// xterm's 256 colors: the 16 system colors, a 6x6x6 cube and a ramp of 24 grays. The cube repeats black, white and
+// several system colors, so only the first index for each color is kept, which is the one written for it.
+pub fn xterm256() -> Vec<PaletteEntry> {
+    const SYSTEM: [[u8; 3]; 16] = [
+        [0x00,0x00,0x00], [0xcd,0x00,0x00], [0x00,0xcd,0x00], [0xcd,0xcd,0x00], [0x00,0x00,0xee], [0xcd,0x00,0xcd], [0x00,0xcd,0xcd], [0xe5,0xe5,0xe5],
+        [0x7f,0x7f,0x7f], [0xff,0x00,0x00], [0x00,0xff,0x00], [0xff,0xff,0x00], [0x5c,0x5c,0xff], [0xff,0x00,0xff], [0x00,0xff,0xff], [0xff,0xff,0xff],
+    ];
+    let levels = [0x00, 0x5f, 0x87, 0xaf, 0xd7, 0xff];
+    let colors: Vec<Srgb8> = SYSTEM.iter().map(|&rgb| image::Rgb(rgb))
+        .chain((0..216).map(|i| image::Rgb([levels[i / 36], levels[i / 6 % 6], levels[i % 6]])))
+        .chain((0..24).map(|i| image::Rgb([8 + 10 * i as u8; 3])))
+        .collect();
+    let mut entries: Vec<PaletteEntry> = Vec::new();
+    for (index, &color) in colors.iter().enumerate() {
+        if !entries.iter().any(|entry| entry.shown == color) {
+            entries.push(PaletteEntry { shown: color, written: None, index, measured: false });
+        }
+    }
+    entries
+}
+
+// Hardware palettes often list the same color under several indices. This removes the later copies, keeping the
+// remaining colors in their original order.
+pub fn without_duplicates(palette: &[Srgb8]) -> Vec<Srgb8> {
+    let mut result: Vec<Srgb8> = Vec::with_capacity(palette.len());
+    for &color in palette {
+        if !result.contains(&color) {
+            result.push(color);
+        }
+    }
+    result
+}
+
+// Formats a palette as a .hex file, with one RRGGBB color per line, as used by Lospec and many pixel art editors
+pub fn to_hex(palette: &[Srgb8]) -> String {
+    palette.iter().map(|color| format!("{:02x}{:02x}{:02x}\n", color.0[0], color.0[1], color.0[2])).collect()
+}
+
+// The petz palette has duplicates, so remove them. Each color keeps the first index the game gives it, which is what
+// gets reported and exported.
+pub fn petz() -> Vec<PaletteEntry> {
+    petz_entries(|_| true)
+}
+
+// The petz palette without the colors that the game shades or animates
+pub fn petz_safe() -> Vec<PaletteEntry> {
+    let unstable = |index: usize| (8..=9).contains(&index) || (229..=243).contains(&index) || (246..=248).contains(&index) || index == 255;
+    petz_entries(|index| !unstable(index))
+}
+
+fn petz_entries(keep: impl Fn(usize) -> bool) -> Vec<PaletteEntry> {
+    let first_index = |color: Srgb8| PETZ_SOURCE.iter().position(|&source_color| source_color == color).unwrap();
+    let mut colors: Vec<Srgb8> = PETZ_SOURCE.iter().enumerate().filter(|&(index, _)| keep(index)).map(|(_, &color)| color).collect();
+    colors.sort_unstable_by(|a, b| a.channels().cmp(&b.channels()));
+    colors.dedup();
+    colors.into_iter().map(|color| PaletteEntry { shown: color, written: None, index: first_index(color), measured: false }).collect()
+}
+
+// Color e-paper panels show colors far duller and darker than the values they are driven with, so each of these pairs
+// what a panel displays (in sRGB, under D65 light) with the nominal color that selects it. The displayed colors are
+// estimates, not measurements of a particular panel with an instrument, since panels vary with temperature, age and the
+// light they're seen under anyway. For exact results, measure the panel and give the colors in a palette file.
+fn measured(pairs: &[(Srgb8, Srgb8)]) -> Vec<PaletteEntry> {
+    pairs.iter().enumerate().map(|(index, &(shown, written))| PaletteEntry { shown, written: Some(written), index, measured: true }).collect()
+}
+
+// Spectra 6: black, white, yellow, red, blue and green
+pub const EINK_SPECTRA6: [(Srgb8, Srgb8); 6] = [
+    (image::Rgb([0x19,0x1e,0x21]), image::Rgb([0x00,0x00,0x00])),
+    (image::Rgb([0xe8,0xe8,0xe8]), image::Rgb([0xff,0xff,0xff])),
+    (image::Rgb([0xef,0xde,0x44]), image::Rgb([0xff,0xff,0x00])),
+    (image::Rgb([0xb2,0x13,0x18]), image::Rgb([0xff,0x00,0x00])),
+    (image::Rgb([0x21,0x57,0xba]), image::Rgb([0x00,0x00,0xff])),
+    (image::Rgb([0x12,0x5f,0x20]), image::Rgb([0x00,0xff,0x00])),
+];
+
+// 7-color ACeP, as in the 5.65" and 7.3" panels: black, white, green, blue, red, yellow and orange
+pub const EINK_ACEP7: [(Srgb8, Srgb8); 7] = [
+    (image::Rgb([0x39,0x30,0x39]), image::Rgb([0x00,0x00,0x00])),
+    (image::Rgb([0xdc,0xdc,0xd6]), image::Rgb([0xff,0xff,0xff])),
+    (image::Rgb([0x3a,0x5b,0x46]), image::Rgb([0x00,0xff,0x00])),
+    (image::Rgb([0x3d,0x3b,0x5e]), image::Rgb([0x00,0x00,0xff])),
+    (image::Rgb([0x9c,0x48,0x4b]), image::Rgb([0xff,0x00,0x00])),
+    (image::Rgb([0xd0,0xbe,0x47]), image::Rgb([0xff,0xff,0x00])),
+    (image::Rgb([0xb1,0x6a,0x49]), image::Rgb([0xff,0x80,0x00])),
+];
+
+// Three-color panels with black, white and red pigments
+pub const EINK_BWR: [(Srgb8, Srgb8); 3] = [
+    (image::Rgb([0x26,0x26,0x2a]), image::Rgb([0x00,0x00,0x00])),
+    (image::Rgb([0xd7,0xd7,0xd2]), image::Rgb([0xff,0xff,0xff])),
+    (image::Rgb([0xa8,0x26,0x2b]), image::Rgb([0xff,0x00,0x00])),
+];
+
+// Builds a palette of (at most) the requested size to fit the given pixels
+pub type PaletteBuilder = fn(usize, &[LinearRgb], fn(Lab, Lab) -> f64) -> Vec<Srgb8>;
+
+// One color of a fixed palette, along with what to write out for it. Many targets, like e-paper panels, CRTs and LED
+// matrices, show colors that differ from the values in the file, and hardware palettes are usually known by index.
+#[derive(Copy, Clone)]
+pub struct PaletteEntry {
+    // The color the viewer sees, which every algorithm matches against and averages
+    pub shown: Srgb8,
+    // The device value written to image files to get that color, if it isn't just the shown color
+    pub written: Option<Srgb8>,
+    // The index reported by --summarize and written by indexed exports
+    pub index: usize,
+    // Whether the shown color was measured, in which case it's sRGB whatever --palette-space says
+    pub measured: bool,
+}
+
+pub fn plain_entries(colors: &[Srgb8]) -> Vec<PaletteEntry> {
+    colors.iter().enumerate().map(|(index, &shown)| PaletteEntry { shown, written: None, index, measured: false }).collect()
+}
+
+// Reads a palette file. Each line gives the color the viewer sees as RRGGBB (optionally with a leading #), which is all
+// that .hex files from Lospec and most pixel art editors have, and may go on to give the device color to write instead
+// and an index as @N. Indices otherwise count up from 0. Blank lines and anything after a ; are ignored, and the
+// AARRGGBB colors of Paint.NET palettes have their alpha dropped.
+pub fn parse_palette_file(text: &str) -> Vec<PaletteEntry> {
+    let parse_color = |token: &str| -> Srgb8 {
+        let hex = token.trim_start_matches('#');
+        let hex = if hex.len() == 8 { &hex[2..] } else { hex };
+        match u32::from_str_radix(hex, 16) {
+            Ok(value) if hex.len() == 6 => image::Rgb([(value >> 16) as u8, (value >> 8) as u8, value as u8]),
+            _ => panic!("Invalid palette color {:?}! Use RRGGBB.", token),
+        }
+    };
+
+    let mut entries = Vec::new();
+    for line in text.lines() {
+        let mut tokens = line.split(';').next().unwrap().split_whitespace();
+        let shown = match tokens.next() {
+            Some(token) => parse_color(token),
+            None => continue,
+        };
+        let mut entry = PaletteEntry { shown, written: None, index: entries.len(), measured: false };
+        for token in tokens {
+            match token.strip_prefix('@') {
+                Some(index) => entry.index = match index.parse() {
+                    Ok(index) => index,
+                    Err(_) => panic!("Invalid palette index {:?}!", token),
+                },
+                None if entry.written.is_none() => entry.written = Some(parse_color(token)),
+                None => panic!("Palette lines have at most two colors, but {:?} has more!", line),
+            }
+        }
+        entries.push(entry);
+    }
+    if entries.is_empty() {
+        panic!("The palette file has no colors!");
+    }
+    entries
+}
+
+// The colors of a fixed palette, given as 8-bit values in `palette_space` (or sRGB, for measured colors), re-expressed
+// in the working space
+pub fn shown_colors(entries: &[PaletteEntry], palette_space: &ColorSpace) -> Vec<Srgb8> {
+    let shown: Vec<Srgb8> = entries.iter().map(|entry| entry.shown).collect();
+    let declared = convert_palette(&shown, palette_space);
+    let measured = convert_palette(&shown, &ColorSpace::SRGB);
+    entries.iter().enumerate().map(|(i, entry)| if entry.measured { measured[i] } else { declared[i] }).collect()
+}
+
+pub enum PaletteSource {
+    Fixed(fn() -> Vec<Srgb8>),
+    Procedural(PaletteBuilder),
+    // Palettes that write something other than their colors, in the order of their indices
+    Mapped(fn() -> Vec<PaletteEntry>),
+}
+
+pub struct NamedPalette {
+    pub names: &'static [&'static str],
+    pub description: &'static str,
+    pub source: PaletteSource,
+}
+
+impl NamedPalette {
+    // The entries of a fixed palette, or None for procedural ones
+    pub fn entries(&self) -> Option<Vec<PaletteEntry>> {
+        match self.source {
+            PaletteSource::Fixed(build) => Some(plain_entries(&build())),
+            PaletteSource::Procedural(_) => None,
+            PaletteSource::Mapped(build) => Some(build()),
+        }
+    }
+}
+
+// What gets written for each color of a built palette, in the same order as the palette
+pub struct PaletteOutput {
+    pub colors: Vec<Srgb8>,
+    pub indices: Vec<usize>,
+}
+
+impl PaletteOutput {
+    // For palettes that are written as they are, indexed by position
+    pub fn identity(palette: &[Srgb8]) -> PaletteOutput {
+        PaletteOutput { colors: palette.to_vec(), indices: (0..palette.len()).collect() }
+    }
+
+    // Device values are written as they are, without converting them to the working space
+    pub fn from_entries(entries: &[PaletteEntry], palette: &[Srgb8]) -> PaletteOutput {
+        PaletteOutput {
+            colors: entries.iter().zip(palette).map(|(entry, &color)| entry.written.unwrap_or(color)).collect(),
+            indices: entries.iter().map(|entry| entry.index).collect(),
+        }
+    }
+
+    // Replaces every palette color in a dithered image with what gets written for it
+    pub fn apply(&self, img: &image::RgbImage, palette: &[Srgb8]) -> image::RgbImage {
+        if self.colors == palette {
+            return img.clone();
+        }
+        let mapping: std::collections::HashMap<Srgb8, Srgb8> = palette.iter().cloned().zip(self.colors.iter().cloned()).collect();
+        let mut written = img.clone();
+        for pixel in written.pixels_mut() {
+            *pixel = mapping[&*pixel];
+        }
+        written
+    }
+}
+
+// Re-expresses a palette given in another color space as the nearest 8-bit colors in the working space. Colors outside
+// the working space's gamut are clipped to it.
+pub fn convert_palette(palette: &[Srgb8], space: &ColorSpace) -> Vec<Srgb8> {
+    if *space == color::working_space() {
+        return palette.to_vec();
+    }
+    let img = image::RgbImage::from_fn(palette.len() as u32, 1, |x, _| palette[x as usize]);
+    LinearImage::from_dynamic(image::DynamicImage::ImageRgb8(img), space).to_srgb8().pixels().cloned().collect()
+}
+
+pub const PALETTES: &[NamedPalette] = &[
+    NamedPalette { names: &["bw", "1bit"], description: "Black and white", source: PaletteSource::Fixed(|| vec![image::Rgb([0,0,0]), image::Rgb([255,255,255])]) },
+    NamedPalette { names: &["gray256", "grey256"], description: "Every 8-bit shade of gray", source: PaletteSource::Fixed(|| (0..=255).map(|v| image::Rgb([v,v,v])).collect()) },
+    NamedPalette { names: &["websafe", "r6g6b6"], description: "The 216-color web-safe palette", source: PaletteSource::Fixed(|| grid(6, 6, 6)) },
+    NamedPalette { names: &["reallysafe"], description: "Lehn-Stern reallysafe subset of the websafe palette", source: PaletteSource::Fixed(|| REALLYSAFE.to_vec()) },
+    NamedPalette { names: &["3bit", "r2g2b2"], description: "The corners of the RGB cube", source: PaletteSource::Fixed(|| grid(2, 2, 2)) },
+    NamedPalette { names: &["rgbi"], description: "4-bit RGBI, with a uniform intensity bit", source: PaletteSource::Fixed(|| RGBI.to_vec()) },
+    NamedPalette { names: &["microsoft16"], description: "The 16-color Windows palette", source: PaletteSource::Fixed(|| MICROSOFT16.to_vec()) },
+    NamedPalette { names: &["macintosh16"], description: "The 16-color classic Macintosh palette", source: PaletteSource::Fixed(|| MACINTOSH16.to_vec()) },
+    NamedPalette { names: &["nes"], description: "NES 2C02 PPU", source: PaletteSource::Fixed(|| without_duplicates(&NES)) },
+    NamedPalette { names: &["c64"], description: "Commodore 64 VIC-II (Pepto)", source: PaletteSource::Fixed(|| C64.to_vec()) },
+    NamedPalette { names: &["cga0"], description: "CGA palette 0, low intensity", source: PaletteSource::Fixed(|| CGA[0..4].to_vec()) },
+    NamedPalette { names: &["cga0_high"], description: "CGA palette 0, high intensity", source: PaletteSource::Fixed(|| CGA[4..8].to_vec()) },
+    NamedPalette { names: &["cga1"], description: "CGA palette 1, low intensity", source: PaletteSource::Fixed(|| CGA[8..12].to_vec()) },
+    NamedPalette { names: &["cga1_high"], description: "CGA palette 1, high intensity", source: PaletteSource::Fixed(|| CGA[12..16].to_vec()) },
+    NamedPalette { names: &["ega"], description: "The full EGA palette", source: PaletteSource::Fixed(ega) },
+    NamedPalette { names: &["zxspectrum"], description: "ZX Spectrum, normal and bright", source: PaletteSource::Fixed(|| without_duplicates(&ZX_SPECTRUM)) },
+    NamedPalette { names: &["zxspectrum_normal"], description: "ZX Spectrum, normal colors only", source: PaletteSource::Fixed(|| ZX_SPECTRUM[0..8].to_vec()) },
+    NamedPalette { names: &["zxspectrum_bright"], description: "ZX Spectrum, bright colors only", source: PaletteSource::Fixed(|| ZX_SPECTRUM[8..16].to_vec()) },
+    NamedPalette { names: &["gameboy", "dmg"], description: "The original Game Boy's four greens", source: PaletteSource::Fixed(|| GAME_BOY.to_vec()) },
+    NamedPalette { names: &["pico8"], description: "PICO-8", source: PaletteSource::Fixed(|| PICO8[0..16].to_vec()) },
+    NamedPalette { names: &["pico8_secret"], description: "PICO-8's secret palette", source: PaletteSource::Fixed(|| PICO8[16..32].to_vec()) },
+    NamedPalette { names: &["pico8_full"], description: "PICO-8's standard and secret palettes together", source: PaletteSource::Fixed(|| PICO8.to_vec()) },
+    NamedPalette { names: &["cpc", "amstrad_cpc"], description: "Amstrad CPC firmware colors", source: PaletteSource::Fixed(amstrad_cpc) },
+    NamedPalette { names: &["atari2600"], description: "Atari 2600 NTSC", source: PaletteSource::Fixed(|| ATARI2600_NTSC.to_vec()) },
+    NamedPalette { names: &["xterm256"], description: "xterm's 256 terminal colors", source: PaletteSource::Mapped(xterm256) },
+    NamedPalette { names: &["r3g3b2"], description: "3x3x2 grid", source: PaletteSource::Fixed(|| grid(3, 3, 2)) },
+    NamedPalette { names: &["8bit", "r8g8b4"], description: "8x8x4 grid", source: PaletteSource::Fixed(|| grid(8, 8, 4)) },
+    NamedPalette { names: &["12bit", "r16g16b16"], description: "16x16x16 grid", source: PaletteSource::Fixed(|| grid(16, 16, 16)) },
+    NamedPalette { names: &["15bit", "r32g32b32"], description: "32x32x32 grid", source: PaletteSource::Fixed(|| grid(32, 32, 32)) },
+    NamedPalette { names: &["yliluoma"], description: "The example palette from Yliluoma's dithering article", source: PaletteSource::Fixed(|| YLILUOMA_EXAMPLE.to_vec()) },
+    NamedPalette { names: &["yliluoma_alternate"], description: "A variant of the Yliluoma example that bounds its colors", source: PaletteSource::Fixed(|| YLILUOMA_EXAMPLE_ALTERNATE.to_vec()) },
+    NamedPalette { names: &["petz"], description: "The Petz games' palette", source: PaletteSource::Mapped(petz) },
+    NamedPalette { names: &["petz_safe"], description: "The Petz palette without shaded or animated colors", source: PaletteSource::Mapped(petz_safe) },
+    NamedPalette { names: &["eink:spectra6"], description: "Spectra 6 color e-paper, with estimated displayed colors", source: PaletteSource::Mapped(|| measured(&EINK_SPECTRA6)) },
+    NamedPalette { names: &["eink:acep7"], description: "7-color ACeP e-paper, with estimated displayed colors", source: PaletteSource::Mapped(|| measured(&EINK_ACEP7)) },
+    NamedPalette { names: &["eink:bwr"], description: "Black, white and red e-paper, with estimated displayed colors", source: PaletteSource::Mapped(|| measured(&EINK_BWR)) },
+    NamedPalette { names: &["octree"], description: "Octree-style box subdivision, shrinking boxes to fit", source: PaletteSource::Procedural(|size, pixels, _| make_box_palette(size, pixels.iter().cloned(), Split::Half, true)) },
+    NamedPalette { names: &["octree-notight"], description: "Octree-style box subdivision", source: PaletteSource::Procedural(|size, pixels, _| make_box_palette(size, pixels.iter().cloned(), Split::Half, false)) },
+    NamedPalette { names: &["mediancut-box"], description: "Median cut box subdivision", source: PaletteSource::Procedural(|size, pixels, _| make_box_palette(size, pixels.iter().cloned(), Split::Median, true)) },
+    NamedPalette { names: &["meancut-box"], description: "Mean cut box subdivision", source: PaletteSource::Procedural(|size, pixels, _| make_box_palette(size, pixels.iter().cloned(), Split::Mean, true)) },
+    NamedPalette { names: &["simplex"], description: "Simplex subdivision, designed for simplex dithering", source: PaletteSource::Procedural(|size, pixels, distance2| make_simplex_palette(size, pixels.iter().cloned(), distance2)) },
+    NamedPalette { names: &["simplex-hull"], description: "Simplex subdivision of the image's own gamut, rather than the whole RGB cube", source: PaletteSource::Procedural(make_hull_simplex_palette) },
+];
+
+pub fn lookup(name: &str) -> Option<&'static NamedPalette> {
+    PALETTES.iter().find(|palette| palette.names.contains(&name))
+}
+
+// How many simplices or boxes have each color as a vertex, which is what makes it part of a palette being built. Colors
+// also remember when they were first added, so that the finished palette comes out in the order it was built rather
+// than in whatever order a HashMap happens to keep.
+struct VertexRefs {
+    counts: std::collections::HashMap<Srgb8, usize>,
+    created: std::collections::HashMap<Srgb8, usize>,
+}
+
+impl VertexRefs {
+    fn new() -> VertexRefs {
+        VertexRefs { counts: std::collections::HashMap::new(), created: std::collections::HashMap::new() }
+    }
+
+    fn add(&mut self, color: Srgb8) {
+        let serial = self.created.len();
+        self.created.entry(color).or_insert(serial);
+        *self.counts.entry(color).or_insert(0) += 1;
+    }
+
+    fn remove(&mut self, color: Srgb8) {
+        match self.counts.entry(color) {
+            std::collections::hash_map::Entry::Occupied(mut occ_entry) => {
+                *occ_entry.get_mut() -= 1;
+                if *occ_entry.get() == 0 {
+                    occ_entry.remove();
+                }
+            },
+            _ => unreachable!()
+        }
+    }
+
+    fn count(&self, color: Srgb8) -> usize {
+        self.counts.get(&color).cloned().unwrap_or(0)
+    }
+
+    fn contains(&self, color: Srgb8) -> bool {
+        self.counts.contains_key(&color)
+    }
+
+    fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    // Every color still in use, in the order they were first added
+    fn into_palette(self) -> Vec<Srgb8> {
+        let created = self.created;
+        let mut palette: Vec<Srgb8> = self.counts.into_keys().collect();
+        palette.sort_unstable_by_key(|color| created[color]);
+        palette
+    }
+}
+
+// A simplex of a palette being built by splitting, along with the barycentric coordinates of the target colors inside it
+struct SimplexCut {
+    vertices_rgb: [Srgb8; 4],
+    vertices_lin: [LinearRgb; 4],
+    vertices_lab: [Lab; 4],
+    diameter2: f64,
+    diameter_edge: [usize; 2],
+    points: Vec<[f64; 4]>
+}
+
+// The squared length and vertices of a simplex's longest edge
+fn simplex_diameter(vertices_lab: &[Lab; 4], distance2: fn(Lab, Lab) -> f64) -> (f64, [usize; 2]) {
+    let mut max_distance_seen = 0.0;
+    let mut max_distance_edge = [0, 1];
+    for &edge in &[[0, 1], [0, 2], [0, 3], [1, 2], [1, 3], [2, 3]] {
+        let dist2 = distance2(vertices_lab[edge[0]], vertices_lab[edge[1]]);
+        if dist2 > max_distance_seen {
+            max_distance_seen = dist2;
+            max_distance_edge = edge;
+        }
+    }
+    (max_distance_seen, max_distance_edge)
+}
+
+// The barycentric coordinates of a color in a simplex, all positive if it is inside
+fn barycentric(vertices_lin: &[LinearRgb; 4], lin: LinearRgb) -> [f64; 4] {
+    let shifted_points = vertices_lin.map(|vertex| subtract(vertex, lin));
+    let d0 = determinant([shifted_points[1], shifted_points[3], shifted_points[2]]);
+    let d1 = determinant([shifted_points[0], shifted_points[2], shifted_points[3]]);
+    let d2 = determinant([shifted_points[0], shifted_points[3], shifted_points[1]]);
+    let d3 = determinant([shifted_points[0], shifted_points[1], shifted_points[2]]);
+    let d_all = d0 + d1 + d2 + d3;
+
+    [d0 / d_all, d1 / d_all, d2 / d_all, d3 / d_all]
+}
+
+// Barycentric coordinates in a flat simplex, if the point is inside it. Anything inside one is inside a corner, edge or
+// triangle of it, so those are tried one at a time.
+fn flat_barycentric(vertices_lin: &[LinearRgb; 4], lin: LinearRgb) -> Option<[f64; 4]> {
+    const TOLERANCE: f64 = 1e-9;
+    let in_corner = |a: usize| {
+        let offset = subtract(lin, vertices_lin[a]);
+        if offset.dot(offset) > TOLERANCE * TOLERANCE {
+            return None;
+        }
+        let mut coords = [0.0; 4];
+        coords[a] = 1.0;
+        Some(coords)
+    };
+    let in_edge = |a: usize, b: usize| {
+        let edge = subtract(vertices_lin[b], vertices_lin[a]);
+        let offset = subtract(lin, vertices_lin[a]);
+        let length2 = edge.dot(edge);
+        if length2 <= 1e-30 {
+            return None;
+        }
+        let t = offset.dot(edge) / length2;
+        let off_edge = offset - edge * t;
+        if !(-TOLERANCE..=1.0 + TOLERANCE).contains(&t) || off_edge.dot(off_edge) > TOLERANCE * TOLERANCE {
+            return None;
+        }
+        let mut coords = [0.0; 4];
+        coords[a] = 1.0 - t;
+        coords[b] = t;
+        Some(coords)
+    };
+    let in_triangle = |a: usize, b: usize, c: usize| {
+        let edge1 = subtract(vertices_lin[b], vertices_lin[a]);
+        let edge2 = subtract(vertices_lin[c], vertices_lin[a]);
+        let offset = subtract(lin, vertices_lin[a]);
+        let normal = edge1.cross(edge2);
+        let normal2 = normal.dot(normal);
+        if normal2 <= 1e-30 {
+            return None;
+        }
+        let s = offset.cross(edge2).dot(normal) / normal2;
+        let t = edge1.cross(offset).dot(normal) / normal2;
+        if s < -TOLERANCE || t < -TOLERANCE || s + t > 1.0 + TOLERANCE || offset.dot(normal).abs() > TOLERANCE * normal2.sqrt() {
+            return None;
+        }
+        let mut coords = [0.0; 4];
+        coords[a] = 1.0 - s - t;
+        coords[b] = s;
+        coords[c] = t;
+        Some(coords)
+    };
+    (0..4).find_map(|a| in_corner(a)
+        .or_else(|| (a + 1..4).find_map(|b| in_edge(a, b).or_else(|| (b + 1..4).find_map(|c| in_triangle(a, b, c))))))
+}
+
+// Barycentric coordinates in a simplex, if the point is inside it
+fn contained_coords(vertices_lin: &[LinearRgb; 4], lin: LinearRgb) -> Option<[f64; 4]> {
+    // A simplex shrunk onto a gray ramp or a single color is flat, which leaves barycentric coordinates undefined
+    let [v0, v1, v2, v3] = *vertices_lin;
+    if determinant([subtract(v1, v0), subtract(v2, v0), subtract(v3, v0)]).abs() <= 1e-15 {
+        flat_barycentric(vertices_lin, lin)
+    } else {
+        Some(barycentric(vertices_lin, lin)).filter(|coords| coords.iter().all(|&coord| coord >= -1e-9))
+    }
+}
+
+impl SimplexCut {
+    fn new(vertices_rgb: [Srgb8; 4], points: Vec<[f64; 4]>, distance2: fn(Lab, Lab) -> f64) -> SimplexCut {
+        let vertices_lin = vertices_rgb.map(LinearRgb::from);
+        let vertices_lab = vertices_lin.map(Lab::from);
+        let (diameter2, diameter_edge) = simplex_diameter(&vertices_lab, distance2);
+        SimplexCut { vertices_rgb, vertices_lin, vertices_lab, diameter2, diameter_edge, points }
+    }
+
+    // The heuristic used to select which simplex to cut
+    fn weight(&self) -> f64 {
+        self.diameter2 * self.points.len() as f64
+    }
+
+    // The coordinates of every point with the vertex moved to the given color, if they would all still be inside
+    fn moved_points(&self, vertex: usize, rgb: Srgb8) -> Option<Vec<[f64; 4]>> {
+        let mut vertices_lin = self.vertices_lin;
+        vertices_lin[vertex] = LinearRgb::from(rgb);
+        self.points.iter().map(|&coords| contained_coords(&vertices_lin, self.position(coords))).collect()
+    }
+
+    fn position(&self, coords: [f64; 4]) -> LinearRgb {
+        LinearRgb { data: [0, 1, 2].map(|axis| (0..4).map(|k| coords[k] * self.vertices_lin[k].data[axis]).sum()) }
+    }
+
+    // Shrink to fit contained points
+    fn optimize(&mut self, referenced_points: &mut VertexRefs, distance2: fn(Lab, Lab) -> f64, keep_inside: bool) {
+        let mut changed = false;
+        // Loop through all edges and maximally shrink that edge
+        for opt_vertex in 0..4 {
+            for other_vertex in (0..4).filter(|&v| v != opt_vertex) {
+                // Nothing holds an unused vertex back, so shrinking towards one would let two of them trade places
+                // forever instead of both moving onto the colors that are actually used
+                if self.points.iter().all(|coords| coords[other_vertex] <= 0.0) {
+                    continue;
+                }
+                let mut max_ratio = 0.0;
+                for coords in &self.points {
+                    // Coordinates that should be zero can come out slightly negative, which must still stop a vertex
+                    // with weight from moving
+                    let ratio = coords[opt_vertex] / coords[other_vertex].max(0.0);
+                    if ratio > max_ratio {
+                        max_ratio = ratio;
                     }
-                    if max_ratio < 1e15 {
-                        let factor = 1.0 - 1.0 / (max_ratio + 1.0);
-                        //eprintln!("  Optimization factor {}", factor);
-                        let new_lin = subtract(self.vertices_lin[opt_vertex], self.vertices_lin[other_vertex]) * factor + self.vertices_lin[other_vertex];
-                        let new_rgb = Srgb8::from(new_lin);
-                        if new_rgb != self.vertices_rgb[opt_vertex] &&
-                           (referenced_points.get(&self.vertices_rgb[opt_vertex]) == Some(&1) || referenced_points.contains_key(&new_rgb)) {
-                            changed = true;
-                            let new_lin = LinearRgb::from(new_rgb);
-                            let new_lab = Lab::from(new_lin);
-                            match referenced_points.entry(self.vertices_rgb[opt_vertex]) {
-                                std::collections::hash_map::Entry::Occupied(mut occ_entry) => {
-                                    *occ_entry.get_mut() -= 1;
-                                    if *occ_entry.get() == 0 {
-                                        occ_entry.remove();
-                                    }
-                                },
-                                _ => unreachable!()
-                            }
-                            self.vertices_rgb[opt_vertex] = new_rgb;
-                            self.vertices_lin[opt_vertex] = new_lin;
-                            self.vertices_lab[opt_vertex] = new_lab;
-                            *referenced_points.entry(new_rgb).or_insert(0) += 1;
+                }
+                if max_ratio < 1e15 {
+                    let factor = 1.0 - 1.0 / (max_ratio + 1.0);
+                    //eprintln!("  Optimization factor {}", factor);
+                    let new_lin = subtract(self.vertices_lin[opt_vertex], self.vertices_lin[other_vertex]) * factor + self.vertices_lin[other_vertex];
+                    // Rounding to the nearest color can take the vertex past the points that stopped it, leaving them
+                    // outside, so where they must stay inside it is rounded back towards where it was, and otherwise
+                    // it stays put
+                    let old_rgb = self.vertices_rgb[opt_vertex];
+                    let towards_old = image::Rgb([0, 1, 2].map(|channel| {
+                        let value = color::encode(new_lin.data[channel]) * 255.0;
+                        if old_rgb.0[channel] as f64 > value { value.ceil() as u8 } else { value.floor() as u8 }
+                    }));
+                    let (new_rgb, moved_points) = if !keep_inside || factor < 1e-15 {
+                        (Srgb8::from(new_lin), None)
+                    } else {
+                        [Srgb8::from(new_lin), towards_old].iter()
+                            .find_map(|&rgb| self.moved_points(opt_vertex, rgb).map(|points| (rgb, Some(points))))
+                            .unwrap_or((old_rgb, None))
+                    };
+                    if new_rgb != old_rgb &&
+                       (referenced_points.count(old_rgb) == 1 || referenced_points.contains(new_rgb)) {
+                        changed = true;
+                        let new_lin = LinearRgb::from(new_rgb);
+                        let new_lab = Lab::from(new_lin);
+                        referenced_points.remove(self.vertices_rgb[opt_vertex]);
+                        self.vertices_rgb[opt_vertex] = new_rgb;
+                        self.vertices_lin[opt_vertex] = new_lin;
+                        self.vertices_lab[opt_vertex] = new_lab;
+                        referenced_points.add(new_rgb);
+                        if let Some(points) = moved_points {
+                            self.points = points;
+                        } else {
                             for coords in &mut self.points {
                                 if factor < 1e-15 {
                                     coords[opt_vertex] += coords[other_vertex];
@@ -478,50 +1272,63 @@ pub fn make_simplex_palette(palette_size: usize, pixels: impl Iterator<Item=imag
                     }
                 }
             }
-            if changed {
-                let mut max_distance_seen = 0.0;
-                let mut max_distance_edge = [0, 1];
-                for &edge in &[[0, 1], [0, 2], [0, 3], [1, 2], [1, 3], [2, 3]] {
-                    let dist2 = distance2(self.vertices_lab[edge[0]], self.vertices_lab[edge[1]]);
-                    if dist2 > max_distance_seen {
-                        max_distance_seen = dist2;
-                        max_distance_edge = edge;
+        }
+        if changed {
+            // A vertex that shrank onto another one is the same color, so its share of each point goes to the first of
+            // them. Otherwise, the longest edge could join the copies that have no weight, and splitting it would just
+            // be shrunk away again, forever.
+            for later in 1..4 {
+                if let Some(earlier) = (0..later).find(|&earlier| self.vertices_rgb[earlier] == self.vertices_rgb[later]) {
+                    for coords in &mut self.points {
+                        coords[earlier] += coords[later];
+                        coords[later] = 0.0;
                     }
                 }
-                self.diameter2 = max_distance_seen;
-                self.diameter_edge = max_distance_edge;
             }
+            let (diameter2, diameter_edge) = simplex_diameter(&self.vertices_lab, distance2);
+            self.diameter2 = diameter2;
+            self.diameter_edge = diameter_edge;
         }
     }
+}
 
-    impl std::cmp::PartialEq for SimplexCut {
-        fn eq(&self, other: &SimplexCut) -> bool {
-            self.weight() == other.weight()
-        }
+impl std::cmp::PartialEq for SimplexCut {
+    fn eq(&self, other: &SimplexCut) -> bool {
+        self.weight() == other.weight()
     }
-    impl std::cmp::Eq for SimplexCut { }
-    impl std::cmp::PartialOrd for SimplexCut {
-        fn partial_cmp(&self, other: &SimplexCut) -> Option<std::cmp::Ordering> {
-            self.weight().partial_cmp(&other.weight())
-        }
+}
+impl std::cmp::Eq for SimplexCut { }
+impl std::cmp::PartialOrd for SimplexCut {
+    fn partial_cmp(&self, other: &SimplexCut) -> Option<std::cmp::Ordering> {
+        self.weight().partial_cmp(&other.weight())
     }
-    impl std::cmp::Ord for SimplexCut {
-        fn cmp(&self, other: &SimplexCut) -> std::cmp::Ordering {
-            self.weight().partial_cmp(&other.weight()).unwrap()
-        }
+}
+impl std::cmp::Ord for SimplexCut {
+    fn cmp(&self, other: &SimplexCut) -> std::cmp::Ordering {
+        self.weight().partial_cmp(&other.weight()).unwrap()
     }
+}
 
-    let mut palette = Vec::with_capacity(palette_size);
-
+// Simplex dithering wants small simplices containing every target color, so we attempt to build a palette explicitly for
+// that purpose. We start with the RGB cube, divided into 6 simplices based on hue (all sharing white and black). Then, since
+// that palette only contains 8 colors, we repeatedly split simplices in half by introducing a new color on their longest
+// edge, up until we have the desired number of palette colors. Currently, simplices are chosen for splitting based on a
+// heuristic squaring the maximal perceptual distance withing the simplex (since large simplices benefit from splitting more)
+// and multiplying by the number of target points inside that simplex (since those splits will help a larger fraction of the
+// image).
+//
+// As a final post-processing pass, each simplex is shrunk to fit the colors it contains to attempt to reduce the error.
+pub fn make_simplex_palette(palette_size: usize, pixels: impl Iterator<Item=LinearRgb>, distance2: fn(Lab, Lab) -> f64) -> Vec<image::Rgb<u8>> {
     // Build the initial simplices by subdividing the RGB cube
     let mut hue_split_points = [Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new(), Vec::new()];
 
     for pixel in pixels {
-        if pixel.channels()[0] < pixel.channels()[1] {
-            if pixel.channels()[1] < pixel.channels()[2] {
+        let [r, g, b] = pixel.data;
+        if r < g {
+            if g < b {
                 // Cyan-Blue
                 hue_split_points[3].push(pixel);
-            } else if pixel.channels()[2] < pixel.channels()[0] {
+            } else if b < r {
                 // Yellow-Green
                 hue_split_points[1].push(pixel);
             } else {
@@ -529,10 +1336,10 @@ pub fn make_simplex_palette(palette_size: usize, pixels: impl Iterator<Item=imag
                 hue_split_points[2].push(pixel);
             }
         } else {
-            if pixel.channels()[0] < pixel.channels()[2] {
+            if r < b {
                 // Magenta-Blue
                 hue_split_points[4].push(pixel);
-            } else if pixel.channels()[2] < pixel.channels()[1] {
+            } else if b < g {
                 // Yellow-Red
                 hue_split_points[0].push(pixel);
             } else {
@@ -542,17 +1349,11 @@ pub fn make_simplex_palette(palette_size: usize, pixels: impl Iterator<Item=imag
         }
     }
 
-    let mut nodes = std::collections::BinaryHeap::new();
-    let mut referenced_points = std::collections::HashMap::new();
-
     let black_rgb = image::Rgb([0, 0, 0]);
     let white_rgb = image::Rgb([255, 255, 255]);
-    let black_lin = LinearRgb::from(black_rgb);
-    let white_lin = LinearRgb::from(white_rgb);
-    let black_lab = Lab::from(black_lin);
-    let white_lab = Lab::from(white_lin);
 
     // Now that the image pixels are divided, actually build the SimplexCut nodes to contain them
+    let mut nodes = Vec::new();
     for hue_idx in 0..6 {
         let cube_corners = [
             image::Rgb([255, 0, 0]),
@@ -564,40 +1365,25 @@ pub fn make_simplex_palette(palette_size: usize, pixels: impl Iterator<Item=imag
             image::Rgb([255, 0, 0]),
         ];
 
-        let prev_rgb = cube_corners[hue_idx];
-        let next_rgb = cube_corners[hue_idx + 1];
-        let prev_lin = LinearRgb::from(prev_rgb);
-        let next_lin = LinearRgb::from(next_rgb);
-        let prev_lab = Lab::from(prev_lin);
-        let next_lab = Lab::from(next_lin);
-
-        let node = SimplexCut {
-            vertices_rgb: [black_rgb, white_rgb, prev_rgb, next_rgb],
-            vertices_lin: [black_lin, white_lin, prev_lin, next_lin],
-            vertices_lab: [black_lab, white_lab, prev_lab, next_lab],
-            diameter2: 10000.0,
-            diameter_edge: [0, 1],
-            points: hue_split_points[hue_idx].iter().map(|&rgb| {
-                let lin = LinearRgb::from(rgb);
-                let shifted_points = [
-                    subtract(black_lin, lin),
-                    subtract(white_lin, lin),
-                    subtract(prev_lin, lin),
-                    subtract(next_lin, lin),
-                ];
-                // Tag with barycentric coords
-                let d0 = determinant([shifted_points[1], shifted_points[3], shifted_points[2]]);
-                let d1 = determinant([shifted_points[0], shifted_points[2], shifted_points[3]]);
-                let d2 = determinant([shifted_points[0], shifted_points[3], shifted_points[1]]);
-                let d3 = determinant([shifted_points[0], shifted_points[1], shifted_points[2]]);
-                let d_all = d0 + d1 + d2 + d3;
-
-                [d0 / d_all, d1 / d_all, d2 / d_all, d3 / d_all]
-            }).collect()
-        };
+        let vertices_rgb = [black_rgb, white_rgb, cube_corners[hue_idx], cube_corners[hue_idx + 1]];
+        let vertices_lin = vertices_rgb.map(LinearRgb::from);
+        let points = hue_split_points[hue_idx].iter().map(|&lin| barycentric(&vertices_lin, lin)).collect();
+        nodes.push(SimplexCut { diameter2: 10000.0, diameter_edge: [0, 1], ..SimplexCut::new(vertices_rgb, points, distance2) });
+    }
+
+    split_simplices(palette_size, nodes, distance2, false)
+}
+
+// Starting from simplices that together hold every target color, cuts them until there are `palette_size` colors.
+// With `keep_inside`, the corners of shrunk and split simplices are never rounded so as to leave the colors they hold
+// outside.
+fn split_simplices(palette_size: usize, initial_nodes: Vec<SimplexCut>, distance2: fn(Lab, Lab) -> f64, keep_inside: bool) -> Vec<Srgb8> {
+    let mut nodes = std::collections::BinaryHeap::new();
+    let mut referenced_points = VertexRefs::new();
+    for node in initial_nodes {
         if !node.points.is_empty() {
             for &vertex in &node.vertices_rgb {
-                *referenced_points.entry(vertex).or_insert(0) += 1;
+                referenced_points.add(vertex);
             }
             nodes.push(node);
         }
@@ -613,7 +1399,7 @@ pub fn make_simplex_palette(palette_size: usize, pixels: impl Iterator<Item=imag
         //    split_node.vertices_rgb[2].channels()[0], split_node.vertices_rgb[2].channels()[1], split_node.vertices_rgb[2].channels()[2],
         //    split_node.vertices_rgb[3].channels()[0], split_node.vertices_rgb[3].channels()[1], split_node.vertices_rgb[3].channels()[2],
         //);
-        split_node.optimize(&mut referenced_points, distance2);
+        split_node.optimize(&mut referenced_points, distance2, keep_inside);
         //eprintln!("  #{:02x}{:02x}{:02x}, #{:02x}{:02x}{:02x}, #{:02x}{:02x}{:02x}, #{:02x}{:02x}{:02x}",
         //    split_node.vertices_rgb[0].channels()[0], split_node.vertices_rgb[0].channels()[1], split_node.vertices_rgb[0].channels()[2],
         //    split_node.vertices_rgb[1].channels()[0], split_node.vertices_rgb[1].channels()[1], split_node.vertices_rgb[1].channels()[2],
@@ -621,7 +1407,7 @@ pub fn make_simplex_palette(palette_size: usize, pixels: impl Iterator<Item=imag
         //    split_node.vertices_rgb[3].channels()[0], split_node.vertices_rgb[3].channels()[1], split_node.vertices_rgb[3].channels()[2],
         //);
         //eprintln!("  Diameter edge: {}, {}", split_node.diameter_edge[0], split_node.diameter_edge[1]);
-       
+
         // Choose the division point
         if split_node.points.len() == 1 {
             continue;
@@ -629,99 +1415,330 @@ pub fn make_simplex_palette(palette_size: usize, pixels: impl Iterator<Item=imag
         let end0 = split_node.vertices_rgb[split_node.diameter_edge[0]];
         let end1 = split_node.vertices_rgb[split_node.diameter_edge[1]];
         let split_vertex_lin = midpoint(split_node.vertices_lin[split_node.diameter_edge[0]], split_node.vertices_lin[split_node.diameter_edge[1]]);
-        let split_vertex_rgb = Srgb8::from(split_vertex_lin);
-        //eprintln!("  Split at #{:02x}{:02x}{:02x}", split_vertex_rgb.channels()[0], split_vertex_rgb.channels()[1], split_vertex_rgb.channels()[2]);
-        if split_vertex_rgb == end0 || split_vertex_rgb == end1 {
-            continue;
-        }
-        let split_vertex_lin = LinearRgb::from(split_vertex_rgb);
-        let split_vertex_lab = Lab::from(split_vertex_lin);
         let other0 = (0..4).find(|&x| x != split_node.diameter_edge[0] && x != split_node.diameter_edge[1]).unwrap();
         let other1 = (0..4).rfind(|&x| x != split_node.diameter_edge[1] && x != split_node.diameter_edge[0]).unwrap();
 
         // Divide the pixels between the two halves
-        let mut split_points = [Vec::new(), Vec::new()];
-        for coords in split_node.points {
-            if coords[split_node.diameter_edge[0]] > coords[split_node.diameter_edge[1]] {
-                split_points[0].push([
-                    coords[split_node.diameter_edge[0]] - coords[split_node.diameter_edge[1]],
-                    coords[split_node.diameter_edge[1]] * 2.0,
-                    coords[other0],
-                    coords[other1]
-                ]);
-            } else {
-                split_points[1].push([
-                    coords[split_node.diameter_edge[1]] - coords[split_node.diameter_edge[0]],
-                    coords[split_node.diameter_edge[0]] * 2.0,
-                    coords[other0],
-                    coords[other1]
-                ]);
+        let divide = |split_vertex_rgb: Srgb8| {
+            let mut split_points = [Vec::new(), Vec::new()];
+            for &coords in &split_node.points {
+                let half = if coords[split_node.diameter_edge[0]] > coords[split_node.diameter_edge[1]] { 0 } else { 1 };
+                if keep_inside {
+                    // The split vertex is rounded off the edge, so the halves no longer cover the simplex exactly and
+                    // the points have to be found in them again
+                    let position = split_node.position(coords);
+                    let half_coords = |half: usize| {
+                        let vertices_lin = [split_node.vertices_lin[split_node.diameter_edge[half]], LinearRgb::from(split_vertex_rgb),
+                                            split_node.vertices_lin[other0], split_node.vertices_lin[other1]];
+                        contained_coords(&vertices_lin, position).map(|coords| (half, coords))
+                    };
+                    let (half, coords) = half_coords(half).or_else(|| half_coords(1 - half))?;
+                    split_points[half].push(coords);
+                } else {
+                    let (near, far) = (split_node.diameter_edge[half], split_node.diameter_edge[1 - half]);
+                    split_points[half].push([
+                        coords[near] - coords[far],
+                        coords[far] * 2.0,
+                        coords[other0],
+                        coords[other1]
+                    ]);
+                }
             }
-        }
+            Some(split_points)
+        };
+        // Where the points must stay inside, roundings of the split vertex that leave some of them outside both halves
+        // are passed over
+        let encoded = split_vertex_lin.data.map(|value| color::encode(value) * 255.0);
+        let roundings = (0..8).map(|bits: usize| image::Rgb([0, 1, 2].map(|channel| {
+            if bits >> channel & 1 == 1 { encoded[channel].ceil() as u8 } else { encoded[channel].floor() as u8 }
+        })));
+        let candidates = std::iter::once(Srgb8::from(split_vertex_lin)).chain(roundings.filter(|_| keep_inside));
+        let split = candidates.filter(|&rgb| rgb != end0 && rgb != end1)
+            .find_map(|rgb| divide(rgb).map(|split_points| (rgb, split_points)));
+        let (split_vertex_rgb, split_points) = match split {
+            Some(split) => split,
+            None => continue,
+        };
+        //eprintln!("  Split at #{:02x}{:02x}{:02x}", split_vertex_rgb.channels()[0], split_vertex_rgb.channels()[1], split_vertex_rgb.channels()[2]);
 
         let new_vertices_rgb = [
             [end0, split_vertex_rgb, split_node.vertices_rgb[other0], split_node.vertices_rgb[other1]],
             [end1, split_vertex_rgb, split_node.vertices_rgb[other0], split_node.vertices_rgb[other1]],
         ];
-        let new_vertices_lin = [
-            [split_node.vertices_lin[split_node.diameter_edge[0]], split_vertex_lin, split_node.vertices_lin[other0], split_node.vertices_lin[other1]],
-            [split_node.vertices_lin[split_node.diameter_edge[1]], split_vertex_lin, split_node.vertices_lin[other0], split_node.vertices_lin[other1]],
-        ];
-        let new_vertices_lab = [
-            [split_node.vertices_lab[split_node.diameter_edge[0]], split_vertex_lab, split_node.vertices_lab[other0], split_node.vertices_lab[other1]],
-            [split_node.vertices_lab[split_node.diameter_edge[1]], split_vertex_lab, split_node.vertices_lab[other0], split_node.vertices_lab[other1]],
-        ];
 
         for i in 0..2 {
-            let mut max_distance_seen = 0.0;
-            let mut max_distance_edge = [0, 1];
-            for &edge in &[[0, 1], [0, 2], [0, 3], [1, 2], [1, 3], [2, 3]] {
-                let dist2 = distance2(new_vertices_lab[i][edge[0]], new_vertices_lab[i][edge[1]]);
-                if dist2 > max_distance_seen {
-                    max_distance_seen = dist2;
-                    max_distance_edge = edge;
-                }
-            }
-
-            let node = SimplexCut {
-                vertices_rgb: new_vertices_rgb[i],
-                vertices_lin: new_vertices_lin[i],
-                vertices_lab: new_vertices_lab[i],
-                diameter2: max_distance_seen,
-                diameter_edge: max_distance_edge,
-                points: split_points[i].clone()
-            };
+            let node = SimplexCut::new(new_vertices_rgb[i], split_points[i].clone(), distance2);
             if !node.points.is_empty() {
                 for &vertex in &node.vertices_rgb {
-                    *referenced_points.entry(vertex).or_insert(0) += 1;
+                    referenced_points.add(vertex);
                 }
                 nodes.push(node);
             }
         }
 
         for &vertex in &split_node.vertices_rgb {
-            match referenced_points.entry(vertex) {
-                std::collections::hash_map::Entry::Occupied(mut occ_entry) => {
-                    *occ_entry.get_mut() -= 1;
-                    if *occ_entry.get() == 0 {
-                        occ_entry.remove();
-                    }
-                },
-                _ => unreachable!()
-            }
+            referenced_points.remove(vertex);
         }
     }
 
     // Post-process: improve overlarge simplices
     while let Some(mut node) = nodes.pop() {
-        node.optimize(&mut referenced_points, distance2);
+        node.optimize(&mut referenced_points, distance2, keep_inside);
+    }
+
+    referenced_points.into_palette()
+}
+
+// The RGB cube above gives every palette pure red, green, blue, cyan, magenta, yellow, black and white, even for a
+// sepia photo that comes nowhere near most of them. This variant starts from a gamut fitted around the image's own
+// colors instead, so every pixel is still inside it (and simplex dithering still keeps the mean color) without spending
+// any of the palette on the cube's unused corners.
+//
+// The gamut starts as the cube and is cut down with planes that touch the image's colors from outside, facing in
+// directions spread evenly over the sphere. Each cut leaves every pixel inside, since they are all on one side of the
+// plane. Cutting off a corner usually adds corners, and the gamut may only have three quarters of the palette as
+// corners, leaving the rest of the colors for splitting it into smaller simplices. So once no other cut fits, cuts are
+// swapped for ones that fit better, which approximates the image's colors with a few large faces instead of stopping
+// with a corner of the cube still in place. The gamut's corners are rounded outwards, away from its center, to keep the
+// pixels inside.
+pub fn make_hull_simplex_palette(palette_size: usize, pixels: &[LinearRgb], distance2: fn(Lab, Lab) -> f64) -> Vec<Srgb8> {
+    const DIRECTIONS: usize = 256;
+    // The planes are pushed out a little past the pixels, so that the gamut of something flat, like a grayscale image
+    // or a single color, still has some thickness
+    const MARGIN: f64 = 0.5 / 255.0;
+
+    if pixels.is_empty() {
+        return make_simplex_palette(palette_size, pixels.iter().cloned(), distance2);
+    }
+    let dot = |v1: [f64; 3], v2: [f64; 3]| v1[0] * v2[0] + v1[1] * v2[1] + v1[2] * v2[2];
+
+    // A Fibonacci sphere, with each plane pushed out until it touches the furthest pixel in its direction
+    let golden_angle = std::f64::consts::PI * (3.0 - 5f64.sqrt());
+    let planes: Vec<([f64; 3], f64)> = (0..DIRECTIONS).map(|i| {
+        let z = 1.0 - (2 * i + 1) as f64 / DIRECTIONS as f64;
+        let radius = (1.0 - z * z).sqrt();
+        let angle = golden_angle * i as f64;
+        let normal = [radius * angle.cos(), radius * angle.sin(), z];
+        let offset = pixels.par_iter().map(|pixel| dot(normal, pixel.data)).reduce(|| f64::NEG_INFINITY, f64::max);
+        (normal, offset + MARGIN)
+    }).collect();
+
+    // Each step makes the cut that shrinks the gamut the most while leaving few enough corners, or else the swap of one
+    // cut for another that does. A palette too small for the cube's eight corners takes any step that doesn't add
+    // corners, until it is down to size. Every step takes off at least a hundredth of what is left, so this ends.
+    let limit = (palette_size * 3 / 4).max(4);
+    let mut cuts: Vec<([f64; 3], f64)> = Vec::new();
+    let mut corners = cut_cube_corners(&cuts);
+    let mut volume = 1.0;
+    loop {
+        let smallest = |options: Vec<Vec<([f64; 3], f64)>>| options.into_par_iter().filter_map(|option| {
+            let option_corners = cut_cube_corners(&option);
+            if option_corners.len() <= limit.max(corners.len()) {
+                Some((hull_volume(&option_corners), option, option_corners))
+            } else {
+                None
+            }
+        }).min_by(|(v1, _, _), (v2, _, _)| v1.partial_cmp(v2).unwrap()).filter(|&(option_volume, _, _)| option_volume < volume * 0.99);
+
+        // Adding a plane that cuts off none of the corners would change nothing
+        let cutting: Vec<([f64; 3], f64)> = planes.iter().cloned().filter(|&(normal, offset)| {
+            corners.iter().any(|corner| dot(normal, corner.data) > offset)
+        }).collect();
+        let added = cutting.iter().map(|&plane| cuts.iter().cloned().chain(std::iter::once(plane)).collect()).collect();
+        let best = smallest(added).or_else(|| {
+            let swapped = (0..cuts.len()).flat_map(|i| cutting.iter().map(move |&plane| (i, plane))).map(|(i, plane)| {
+                let mut option = cuts.clone();
+                option[i] = plane;
+                option
+            }).collect();
+            smallest(swapped)
+        });
+        match best {
+            Some((best_volume, best_cuts, best_corners)) => {
+                volume = best_volume;
+                cuts = best_cuts;
+                corners = best_corners;
+            },
+            None => break,
+        }
     }
 
-    for &color in referenced_points.keys() {
-        palette.push(color);
+    // Values that are 8-bit already, apart from rounding error, are kept as they are
+    let encoded: Vec<[f64; 3]> = corners.iter().map(|corner| corner.data.map(|value| color::encode(value.clamp(0.0, 1.0)) * 255.0)).collect();
+    let center = encoded.iter().fold([0.0; 3], |sum, coords| [sum[0] + coords[0], sum[1] + coords[1], sum[2] + coords[2]]).map(|sum| sum / encoded.len() as f64);
+    let mut corners: Vec<Srgb8> = encoded.iter().map(|coords| {
+        let round = |channel: usize| {
+            let value = coords[channel];
+            if (value - value.round()).abs() < 1e-6 {
+                value.round() as u8
+            } else if value < center[channel] {
+                value.floor() as u8
+            } else {
+                value.ceil() as u8
+            }
+        };
+        image::Rgb([round(0), round(1), round(2)])
+    }).collect();
+    corners.sort_unstable_by_key(|color| color.0);
+    corners.dedup();
+    match ConvexHull::new(&corners.iter().cloned().map(LinearRgb::from).collect::<Vec<_>>()) {
+        Some(hull) => simplex_palette_in_hull(palette_size, &corners, &hull, pixels, distance2, true),
+        // Rounding could flatten a gamut that was already very thin
+        None => make_simplex_palette(palette_size, pixels.iter().cloned(), distance2),
     }
+}
 
-    palette
+// The corners of what is left of the RGB cube after cutting it down to the inside of every plane, where the inside of
+// `(normal, offset)` is everything with a dot product with `normal` of at most `offset`. Each corner is where three of
+// the planes or cube faces cross, inside all the others.
+fn cut_cube_corners(cuts: &[([f64; 3], f64)]) -> Vec<LinearRgb> {
+    const EPSILON: f64 = 1e-9;
+
+    let dot = |v1: [f64; 3], v2: [f64; 3]| v1[0] * v2[0] + v1[1] * v2[1] + v1[2] * v2[2];
+    let cross = |v1: [f64; 3], v2: [f64; 3]| [v1[1] * v2[2] - v1[2] * v2[1], v1[2] * v2[0] - v1[0] * v2[2], v1[0] * v2[1] - v1[1] * v2[0]];
+    let mut planes = vec![
+        ([-1.0, 0.0, 0.0], 0.0), ([1.0, 0.0, 0.0], 1.0),
+        ([0.0, -1.0, 0.0], 0.0), ([0.0, 1.0, 0.0], 1.0),
+        ([0.0, 0.0, -1.0], 0.0), ([0.0, 0.0, 1.0], 1.0),
+    ];
+    planes.extend_from_slice(cuts);
+
+    let mut corners: Vec<[f64; 3]> = Vec::new();
+    for i in 0..planes.len() {
+        for j in i + 1..planes.len() {
+            for k in j + 1..planes.len() {
+                let ((n0, d0), (n1, d1), (n2, d2)) = (planes[i], planes[j], planes[k]);
+                let det = dot(n0, cross(n1, n2));
+                if det.abs() < EPSILON {
+                    continue;
+                }
+                // Cramer's rule
+                let (c12, c20, c01) = (cross(n1, n2), cross(n2, n0), cross(n0, n1));
+                let corner = [0, 1, 2].map(|axis| (d0 * c12[axis] + d1 * c20[axis] + d2 * c01[axis]) / det);
+                let inside = planes.iter().all(|&(normal, offset)| dot(normal, corner) <= offset + EPSILON);
+                // More than three planes can cross at the same corner
+                let seen = corners.iter().any(|&other| {
+                    let offset = [0, 1, 2].map(|axis| corner[axis] - other[axis]);
+                    dot(offset, offset) < EPSILON * EPSILON
+                });
+                if inside && !seen {
+                    corners.push(corner);
+                }
+            }
+        }
+    }
+    corners.into_iter().map(|corner| LinearRgb { data: corner }).collect()
+}
+
+// The volume of the convex hull of the points, or zero if they are all in one plane
+fn hull_volume(points: &[LinearRgb]) -> f64 {
+    match ConvexHull::new(points) {
+        Some(hull) => hull.faces.iter().map(|&face| {
+            determinant([subtract(hull.points[face[0]], points[0]), subtract(hull.points[face[1]], points[0]), subtract(hull.points[face[2]], points[0])])
+        }).sum::<f64>() / 6.0,
+        None => 0.0,
+    }
+}
+
+// The same, but inside the convex hull of the given colors instead of the image's
+pub fn make_bounded_simplex_palette(palette_size: usize, bounds: &[Srgb8], pixels: &[LinearRgb], distance2: fn(Lab, Lab) -> f64) -> Vec<Srgb8> {
+    let linear_bounds: Vec<LinearRgb> = bounds.iter().cloned().map(LinearRgb::from).collect();
+    match ConvexHull::new(&linear_bounds) {
+        Some(hull) => simplex_palette_in_hull(palette_size, bounds, &hull, pixels, distance2, false),
+        None => panic!("The bounding palette's colors all lie on one plane, so they don't surround any others!"),
+    }
+}
+
+fn hull_vertices(hull: &ConvexHull<LinearRgb>) -> Vec<usize> {
+    let mut vertices: Vec<usize> = hull.faces.iter().flatten().cloned().collect();
+    vertices.sort_unstable();
+    vertices.dedup();
+    vertices
+}
+
+// Cuts a hull into simplices, each joining one of its faces to its darkest vertex, and splits those as in
+// `make_simplex_palette`. `colors` are the points the hull was built from.
+fn simplex_palette_in_hull(palette_size: usize, colors: &[Srgb8], hull: &ConvexHull<LinearRgb>, pixels: &[LinearRgb], distance2: fn(Lab, Lab) -> f64, keep_inside: bool) -> Vec<Srgb8> {
+    let luma = |i: usize| Lab::from(hull.points[i]).l;
+    let apex = hull_vertices(hull).into_iter().min_by(|&i1, &i2| luma(i1).partial_cmp(&luma(i2)).unwrap().then(colors[i1].0.cmp(&colors[i2].0))).unwrap();
+
+    // Faces in the same plane as the apex would give flat simplices, which nothing is inside of
+    let simplices: Vec<[Srgb8; 4]> = hull.faces.iter().filter(|face| !face.contains(&apex)).map(|face| {
+        [colors[apex], colors[face[0]], colors[face[1]], colors[face[2]]]
+    }).filter(|vertices| {
+        let [v0, v1, v2, v3] = vertices.map(LinearRgb::from);
+        determinant([subtract(v1, v0), subtract(v2, v0), subtract(v3, v0)]).abs() > 1e-15
+    }).collect();
+    let linear_simplices: Vec<[LinearRgb; 4]> = simplices.iter().map(|vertices| vertices.map(LinearRgb::from)).collect();
+
+    // Each pixel goes in the simplex it is furthest inside, which settles pixels on the boundary between two of them.
+    // Pixels outside the hull go in the one they are least far outside, as if they were on its surface.
+    let mut points = vec![Vec::new(); simplices.len()];
+    for &pixel in pixels {
+        let least = |coords: &[f64; 4]| coords.iter().cloned().fold(f64::INFINITY, f64::min);
+        let (i, coords) = linear_simplices.iter().map(|vertices| barycentric(vertices, pixel)).enumerate()
+            .max_by(|(_, coords1), (_, coords2)| least(coords1).partial_cmp(&least(coords2)).unwrap())
+            .unwrap();
+        let coords = coords.map(|coord| coord.max(0.0));
+        let total: f64 = coords.iter().sum();
+        points[i].push(coords.map(|coord| coord / total));
+    }
+
+    let nodes = simplices.into_iter().zip(points).map(|(vertices, points)| SimplexCut::new(vertices, points, distance2)).collect();
+    split_simplices(palette_size, nodes, distance2, keep_inside)
+}
+
+pub const PALETTE_ORDERS: [&str; 4] = ["luma", "hue", "usage", "creation"];
+
+// How procedural palettes are ordered, which decides the indices they are exported and summarized with
+#[derive(Copy, Clone)]
+pub enum PaletteOrder {
+    // From dark to light
+    Luma,
+    // Neutral colors first, then around the color wheel, as in palette previews
+    Hue,
+    // From the color nearest to the most pixels to the least
+    Usage,
+    // The order the builder came up with them, as it split the color space
+    Creation,
+}
+
+pub fn parse_palette_order(name: &str) -> PaletteOrder {
+    match name {
+        "luma" => PaletteOrder::Luma,
+        "hue" => PaletteOrder::Hue,
+        "usage" => PaletteOrder::Usage,
+        "creation" => PaletteOrder::Creation,
+        _ => panic!("Unsupported palette order {:?}! The options are {}.", name, PALETTE_ORDERS.join(", ")),
+    }
+}
+
+// Puts a freshly built palette in the given order. Ties are broken by the colors' values, so that the order only
+// depends on the colors themselves (and, for usage, the pixels).
+pub fn sort_palette(palette: &mut Vec<Srgb8>, order: PaletteOrder, pixels: &[LinearRgb], distance2: fn(Lab, Lab) -> f64) {
+    let luma = |color: Srgb8| Lab::from(color).l;
+    let by_luma = |a: &Srgb8, b: &Srgb8| luma(*a).partial_cmp(&luma(*b)).unwrap().then(a.0.cmp(&b.0));
+    match order {
+        PaletteOrder::Luma => palette.sort_by(by_luma),
+        PaletteOrder::Hue => {
+            palette.sort_by(by_luma);
+            *palette = preview::display_order(palette).iter().map(|&i| palette[i]).collect();
+        },
+        PaletteOrder::Usage => {
+            let lab_palette: Vec<Lab> = palette.iter().map(|&color| Lab::from(color)).collect();
+            let counts = pixels.par_iter().fold(|| vec![0usize; palette.len()], |mut counts, &pixel| {
+                let lab = Lab::from(pixel);
+                let nearest = (0..lab_palette.len()).min_by(|&i1, &i2| distance2(lab, lab_palette[i1]).partial_cmp(&distance2(lab, lab_palette[i2])).unwrap());
+                if let Some(nearest) = nearest {
+                    counts[nearest] += 1;
+                }
+                counts
+            }).reduce(|| vec![0usize; palette.len()], |a, b| a.iter().zip(&b).map(|(x, y)| x + y).collect());
+            let usage: std::collections::HashMap<Srgb8, usize> = palette.iter().cloned().zip(counts).collect();
+            palette.sort_by(|a, b| usage[b].cmp(&usage[a]).then(by_luma(a, b)));
+        },
+        PaletteOrder::Creation => {},
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -731,20 +1748,30 @@ pub enum Split {
     Mean
 }
 
-pub fn make_box_palette(palette_size: usize, pixels: impl Iterator<Item=image::Rgb<u8>>, split: Split, optim: bool) -> Vec<image::Rgb<u8>> {
+// Box subdivision works on encoded (e.g. sRGB) values, with the boxes' corners (which become the palette) on the 8-bit
+// grid. Pixels keep 8 extra fractional bits, so that 16-bit and floating point images are split by their actual colors
+// rather than rounded ones.
+pub fn make_box_palette(palette_size: usize, pixels: impl Iterator<Item=LinearRgb>, split: Split, optim: bool) -> Vec<image::Rgb<u8>> {
+    let encode = |value: f64| (color::encode(value.clamp(0.0, 1.0)) * 255.0 * 256.0).round() as u16;
     let first_node = OctreeNode {
         bounding_box: [[0, 255], [0, 255], [0, 255]],
-        pixels: pixels.collect()
+        pixels: pixels.map(|pixel| [encode(pixel.data[0]), encode(pixel.data[1]), encode(pixel.data[2])]).collect()
     };
 
-    let mut refs = std::collections::HashMap::new();
+    // The 8-bit values just below or above a pixel's channel, and the nearest one
+    fn floor(value: u16) -> u8 { (value >> 8) as u8 }
+    fn ceil(value: u16) -> u8 { ((value + 255) >> 8) as u8 }
+    fn round(value: u16) -> u8 { ((value + 128) >> 8) as u8 }
+    fn widen(value: u8) -> u16 { (value as u16) << 8 }
+
+    let mut refs = VertexRefs::new();
     for corner in first_node.corners() {
-        refs.insert(corner, 1);
+        refs.add(corner);
     }
 
     struct OctreeNode {
         bounding_box: [[u8; 2]; 3],
-        pixels: Vec<image::Rgb<u8>>
+        pixels: Vec<[u16; 3]>
     }
 
     impl std::cmp::PartialEq for OctreeNode {
@@ -775,7 +1802,7 @@ pub fn make_box_palette(palette_size: usize, pixels: impl Iterator<Item=image::R
             )
         }
 
-        fn optimize(&mut self, corn_refs: &mut std::collections::HashMap<image::Rgb<u8>, usize>) {
+        fn optimize(&mut self, corn_refs: &mut VertexRefs) {
             for axis in 0..3 {
                 let other_axes = [[1,2],[0,2],[0,1]][axis];
                 'face_loop:
@@ -788,16 +1815,16 @@ pub fn make_box_palette(palette_size: usize, pixels: impl Iterator<Item=image::R
                             data[axis] = fixed_value;
                             data[other_axes[0]] = self.bounding_box[other_axes[0]][other_side0];
                             data[other_axes[1]] = self.bounding_box[other_axes[1]][other_side1];
-                            if corn_refs.get(&image::Rgb(data)).cloned() != Some(1) {
+                            if corn_refs.count(image::Rgb(data)) != 1 {
                                 continue 'face_loop;
                             }
                         }
                     }
 
                     let furthest = if side == 0 {
-                        self.pixels.iter().map(|px| px.channels()[axis]).min().unwrap_or(self.bounding_box[axis][side])
+                        self.pixels.iter().map(|px| floor(px[axis])).min().unwrap_or(self.bounding_box[axis][side])
                     } else {
-                        self.pixels.iter().map(|px| px.channels()[axis]).max().unwrap_or(self.bounding_box[axis][side])
+                        self.pixels.iter().map(|px| ceil(px[axis])).max().unwrap_or(self.bounding_box[axis][side])
                     };
 
                     if furthest != fixed_value {
@@ -808,9 +1835,9 @@ pub fn make_box_palette(palette_size: usize, pixels: impl Iterator<Item=image::R
                                 data[axis] = fixed_value;
                                 data[other_axes[0]] = self.bounding_box[other_axes[0]][other_side0];
                                 data[other_axes[1]] = self.bounding_box[other_axes[1]][other_side1];
-                                corn_refs.remove(&image::Rgb(data));
+                                corn_refs.remove(image::Rgb(data));
                                 data[axis] = furthest;
-                                *corn_refs.entry(image::Rgb(data)).or_insert(0) += 1;
+                                corn_refs.add(image::Rgb(data));
                             }
                         }
                     }
@@ -848,19 +1875,19 @@ pub fn make_box_palette(palette_size: usize, pixels: impl Iterator<Item=image::R
         let mid = match split {
             Split::Half => split_node.bounding_box[axis][0] + range / 2,
             Split::Median => {
-                split_node.pixels.sort_by(|&p1, &p2| p1.channels()[axis].cmp(&p2.channels()[axis]));
-                let small_count = split_node.pixels.iter().take_while(|&p| p.channels()[axis] == split_node.bounding_box[axis][0]).count();
-                let large_count = split_node.pixels.iter().rev().take_while(|&p| p.channels()[axis] == split_node.bounding_box[axis][1]).count();
+                split_node.pixels.sort_by_key(|p| p[axis]);
+                let small_count = split_node.pixels.iter().take_while(|&p| p[axis] == widen(split_node.bounding_box[axis][0])).count();
+                let large_count = split_node.pixels.iter().rev().take_while(|&p| p[axis] == widen(split_node.bounding_box[axis][1])).count();
                 if small_count + large_count == split_node.pixels.len() {
                     split_node.bounding_box[axis][0] + range / 2
                 } else {
-                    split_node.pixels[small_count + (split_node.pixels.len() - small_count - large_count) / 2].channels()[axis]
+                    round(split_node.pixels[small_count + (split_node.pixels.len() - small_count - large_count) / 2][axis])
                 }
             },
             Split::Mean => {
-                split_node.pixels.sort_by(|&p1, &p2| p1.channels()[axis].cmp(&p2.channels()[axis]));
-                let total = split_node.pixels.iter().map(|&p| color::srgb_decode_channel(p.channels()[axis])).sum::<f64>();
-                let mean = color::srgb_encode_channel(total / split_node.pixels.len() as f64);
+                split_node.pixels.sort_by_key(|p| p[axis]);
+                let total = split_node.pixels.iter().map(|&p| color::decode(p[axis] as f64 / (255.0 * 256.0))).sum::<f64>();
+                let mean = color::encode_channel(total / split_node.pixels.len() as f64);
                 if mean <= split_node.bounding_box[axis][0] || mean >= split_node.bounding_box[axis][1] {
                     split_node.bounding_box[axis][0] + range / 2
                 } else {
@@ -878,28 +1905,20 @@ pub fn make_box_palette(palette_size: usize, pixels: impl Iterator<Item=image::R
             let node = OctreeNode {
                 bounding_box: bounding_box,
                 pixels: split_node.pixels.iter().filter(|&&p| (0..3).all(|channel| {
-                    bounding_box[channel][0] <= p.channels()[channel] && p.channels()[channel] <= bounding_box[channel][1]
+                    widen(bounding_box[channel][0]) <= p[channel] && p[channel] <= widen(bounding_box[channel][1])
                 })).cloned().collect()
             };
 
-            if node.pixels.iter().any(|&p| p.channels()[axis] != mid) {
+            if node.pixels.iter().any(|&p| p[axis] != widen(mid)) {
                 for corner in node.corners() {
-                    *refs.entry(corner).or_insert(0) += 1;
+                    refs.add(corner);
                 }
                 nodes.push(node);
             }
         }
 
         for corner in split_node.corners() {
-            match refs.entry(corner) {
-                std::collections::hash_map::Entry::Occupied(mut occ_entry) => {
-                    *occ_entry.get_mut() -= 1;
-                    if *occ_entry.get() == 0 {
-                        occ_entry.remove();
-                    }
-                },
-                _ => unreachable!()
-            }
+            refs.remove(corner);
         }
     }
 
@@ -913,15 +1932,15 @@ pub fn make_box_palette(palette_size: usize, pixels: impl Iterator<Item=image::R
 
     while refs.len() < palette_size {
         if let Some(node) = nodes.pop() {
-            refs.insert(image::Rgb([
+            refs.add(image::Rgb([
                 node.bounding_box[0][0] + (node.bounding_box[0][1] - node.bounding_box[0][0]) / 2,
                 node.bounding_box[1][0] + (node.bounding_box[1][1] - node.bounding_box[1][0]) / 2,
                 node.bounding_box[2][0] + (node.bounding_box[2][1] - node.bounding_box[2][0]) / 2,
-            ]), 1);
+            ]));
         } else {
             break;
         }
     }
 
-    refs.keys().cloned().collect()
+    refs.into_palette()
 }
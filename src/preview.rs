@@ -0,0 +1,355 @@
+use crate::color::{Srgb8, LinearRgb, LinearImage, Lab};
+use crate::dither;
+use crate::geom::{Affine3, ConvexHull};
+
+//////// Drawing helpers ////////
+
+// A 3x5 pixel font for the digits, one row per byte, using the low 3 bits (most significant on the left)
+const DIGITS: [[u8; 5]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b111, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b001, 0b001, 0b001],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+// The same, for the (upper case) letters
+const LETTERS: [[u8; 5]; 26] = [
+    [0b010, 0b101, 0b111, 0b101, 0b101],
+    [0b110, 0b101, 0b110, 0b101, 0b110],
+    [0b011, 0b100, 0b100, 0b100, 0b011],
+    [0b110, 0b101, 0b101, 0b101, 0b110],
+    [0b111, 0b100, 0b110, 0b100, 0b111],
+    [0b111, 0b100, 0b110, 0b100, 0b100],
+    [0b011, 0b100, 0b101, 0b101, 0b011],
+    [0b101, 0b101, 0b111, 0b101, 0b101],
+    [0b111, 0b010, 0b010, 0b010, 0b111],
+    [0b001, 0b001, 0b001, 0b101, 0b010],
+    [0b101, 0b101, 0b110, 0b101, 0b101],
+    [0b100, 0b100, 0b100, 0b100, 0b111],
+    [0b101, 0b111, 0b111, 0b101, 0b101],
+    [0b110, 0b101, 0b101, 0b101, 0b101],
+    [0b010, 0b101, 0b101, 0b101, 0b010],
+    [0b110, 0b101, 0b110, 0b100, 0b100],
+    [0b010, 0b101, 0b101, 0b110, 0b011],
+    [0b110, 0b101, 0b110, 0b101, 0b101],
+    [0b011, 0b100, 0b010, 0b001, 0b110],
+    [0b111, 0b010, 0b010, 0b010, 0b010],
+    [0b101, 0b101, 0b101, 0b101, 0b111],
+    [0b101, 0b101, 0b101, 0b101, 0b010],
+    [0b101, 0b101, 0b111, 0b111, 0b101],
+    [0b101, 0b101, 0b010, 0b101, 0b101],
+    [0b101, 0b101, 0b010, 0b010, 0b010],
+    [0b111, 0b001, 0b010, 0b100, 0b111],
+];
+
+fn glyph(c: char) -> [u8; 5] {
+    match c.to_ascii_uppercase() {
+        '0'..='9' => DIGITS[c as usize - '0' as usize],
+        c @ 'A'..='Z' => LETTERS[c as usize - 'A' as usize],
+        ' ' => [0b000, 0b000, 0b000, 0b000, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '^' => [0b010, 0b101, 0b000, 0b000, 0b000],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '(' => [0b001, 0b010, 0b010, 0b010, 0b001],
+        ')' => [0b100, 0b010, 0b010, 0b010, 0b100],
+        '@' => [0b010, 0b101, 0b111, 0b100, 0b011],
+        _ => [0b111, 0b001, 0b010, 0b000, 0b010],
+    }
+}
+
+pub fn fill_rect(img: &mut image::RgbImage, x: u32, y: u32, width: u32, height: u32, color: Srgb8) {
+    for py in y..(y + height).min(img.height()) {
+        for px in x..(x + width).min(img.width()) {
+            *img.get_pixel_mut(px, py) = color;
+        }
+    }
+}
+
+// Sets a single pixel, ignoring anything off the edge of the image
+fn plot(img: &mut image::RgbImage, x: f64, y: f64, color: Srgb8) {
+    if x >= 0.0 && y >= 0.0 && x < img.width() as f64 && y < img.height() as f64 {
+        *img.get_pixel_mut(x as u32, y as u32) = color;
+    }
+}
+
+pub fn draw_line(img: &mut image::RgbImage, from: [f64; 2], to: [f64; 2], color: Srgb8) {
+    let steps = (to[0] - from[0]).abs().max((to[1] - from[1]).abs()).ceil().max(1.0);
+    for step in 0..=steps as u32 {
+        let t = step as f64 / steps;
+        plot(img, from[0] + (to[0] - from[0]) * t, from[1] + (to[1] - from[1]) * t, color);
+    }
+}
+
+// Draws a line of text with the top left corner at (x, y), scaling each font pixel up to a square. Each character
+// takes up 4 * scale pixels horizontally and 5 * scale pixels vertically.
+pub fn draw_text(img: &mut image::RgbImage, x: u32, y: u32, text: &str, scale: u32, color: Srgb8) {
+    for (i, c) in text.chars().enumerate() {
+        let glyph_x = x + i as u32 * 4 * scale;
+        for (row, &bits) in glyph(c).iter().enumerate() {
+            for column in 0..3 {
+                if bits & (0b100 >> column) != 0 {
+                    fill_rect(img, glyph_x + column * scale, y + row as u32 * scale, scale, scale, color);
+                }
+            }
+        }
+    }
+}
+
+pub fn draw_number(img: &mut image::RgbImage, x: u32, y: u32, number: usize, scale: u32, color: Srgb8) {
+    draw_text(img, x, y, &number.to_string(), scale, color);
+}
+
+// Black or white, whichever stands out more against the given background
+pub fn contrasting(background: Srgb8) -> Srgb8 {
+    if Lab::from(background).l > 50.0 {
+        image::Rgb([0, 0, 0])
+    } else {
+        image::Rgb([255, 255, 255])
+    }
+}
+
+//////// Palette swatches ////////
+
+const SWATCH_SIZE: u32 = 48;
+
+// Orders palette indices for display: neutral colors first from dark to light, then the rest grouped into twelve hue
+// ranges going around the color wheel, each from dark to light.
+pub fn display_order(palette: &[Srgb8]) -> Vec<usize> {
+    let hue_group = |lab: Lab| {
+        if lab.chroma() < 5.0 {
+            0
+        } else {
+            1 + ((lab.hue() / core::f64::consts::PI + 1.0) * 6.0) as usize % 12
+        }
+    };
+
+    let mut order: Vec<usize> = (0..palette.len()).collect();
+    order.sort_by(|&i1, &i2| {
+        let lab1 = Lab::from(palette[i1]);
+        let lab2 = Lab::from(palette[i2]);
+        hue_group(lab1).cmp(&hue_group(lab2)).then(lab1.l.partial_cmp(&lab2.l).unwrap())
+    });
+    order
+}
+
+// Renders the palette as a grid of swatches. Each swatch is optionally labeled with the given index number and
+// marked along the bottom with a bar showing how often the color was used, relative to the most common color.
+pub fn render_swatches(palette: &[Srgb8], labels: Option<&[usize]>, counts: Option<&[usize]>) -> image::RgbImage {
+    let columns = (palette.len() as f64).sqrt().ceil().max(1.0) as u32;
+    let rows = (palette.len() as u32).div_ceil(columns).max(1);
+    let mut img = image::RgbImage::from_pixel(columns * SWATCH_SIZE, rows * SWATCH_SIZE, image::Rgb([0x80, 0x80, 0x80]));
+
+    let max_count = counts.and_then(|counts| counts.iter().cloned().max()).unwrap_or(0);
+
+    for (position, index) in display_order(palette).into_iter().enumerate() {
+        let x = position as u32 % columns * SWATCH_SIZE;
+        let y = position as u32 / columns * SWATCH_SIZE;
+        let color = palette[index];
+        let foreground = contrasting(color);
+        fill_rect(&mut img, x, y, SWATCH_SIZE, SWATCH_SIZE, color);
+
+        if let Some(labels) = labels {
+            draw_number(&mut img, x + 3, y + 3, labels[index], 2, foreground);
+        }
+
+        if let Some(counts) = counts {
+            if max_count > 0 && counts[index] > 0 {
+                let bar_width = ((counts[index] as f64 / max_count as f64) * (SWATCH_SIZE - 6) as f64).ceil() as u32;
+                fill_rect(&mut img, x + 3, y + SWATCH_SIZE - 8, bar_width, 5, foreground);
+            }
+        }
+    }
+
+    img
+}
+
+//////// Gamut coverage ////////
+
+// Shows which parts of an image are outside the palette's gamut, given the per-pixel errors from
+// `dither::gamut_errors`. Pixels inside the gamut are shown as a dim grayscale copy of the image, and those outside are
+// red, brighter for larger errors.
+pub fn render_gamut_mask(img: &LinearImage, errors: &[f64]) -> image::RgbImage {
+    let worst = errors.iter().cloned().fold(0.0, f64::max);
+    image::RgbImage::from_fn(img.width, img.height, |x, y| {
+        let error = errors[(y * img.width + x) as usize];
+        if error > dither::GAMUT_TOLERANCE {
+            let strength = 0.25 + 0.75 * error / worst;
+            Srgb8::from(LinearRgb { data: [strength, 0.0, 0.0] })
+        } else {
+            let gray = 0.25 * Lab::from(img.get(x, y)).l / 100.0;
+            Srgb8::from(LinearRgb { data: [gray, gray, gray] })
+        }
+    })
+}
+
+//////// Gamut projection ////////
+
+const PANEL_SIZE: f64 = 400.0;
+
+enum Shape {
+    Line([f64; 2], [f64; 2], Srgb8),
+    Dot([f64; 2], f64, Srgb8),
+    Cross([f64; 2], f64, Srgb8),
+}
+
+// A 2D drawing of the palette's convex hull in linear RGB, from two directions: looking down the gray axis (showing
+// the range of hues and saturations) and from the side (with lightness increasing upwards). Palette colors are drawn
+// as dots, and image colors that fall outside the hull as crosses.
+pub struct GamutPlot {
+    shapes: Vec<Shape>,
+    pub outside_colors: usize,
+}
+
+impl GamutPlot {
+    pub fn new(palette: &[Srgb8], pixels: &[Srgb8], distance2: fn(Lab, Lab) -> f64) -> GamutPlot {
+        // An orthonormal basis with the gray axis as the third vector
+        let project = |rgb: LinearRgb| -> [[f64; 2]; 2] {
+            let [r, g, b] = rgb.into_coords();
+            let u = (r - g) / 2.0f64.sqrt();
+            let v = (r + g - 2.0 * b) / 6.0f64.sqrt();
+            let gray = (r + g + b) / 3.0f64.sqrt();
+            [
+                [PANEL_SIZE * 0.5 + u * 220.0, PANEL_SIZE * 0.5 - v * 220.0],
+                [PANEL_SIZE * 1.5 + u * 200.0, PANEL_SIZE - 20.0 - gray * 200.0],
+            ]
+        };
+
+        let linear_palette: Vec<LinearRgb> = palette.iter().cloned().map(LinearRgb::from).collect();
+        let hull = ConvexHull::new(&linear_palette);
+
+        let mut shapes = vec![Shape::Line([PANEL_SIZE, 0.0], [PANEL_SIZE, PANEL_SIZE], image::Rgb([0x60, 0x60, 0x60]))];
+
+        if let Some(ref hull) = hull {
+            for [a, b] in hull.edges() {
+                let (pa, pb) = (project(hull.points[a]), project(hull.points[b]));
+                for view in 0..2 {
+                    shapes.push(Shape::Line(pa[view], pb[view], image::Rgb([0xa0, 0xa0, 0xa0])));
+                }
+            }
+        }
+
+        let mut unique_pixels = pixels.to_vec();
+        unique_pixels.sort_unstable_by_key(|pixel| pixel.0);
+        unique_pixels.dedup();
+        // A palette whose colors all lie on a plane (or a line) has no hull, but the colors in between can still be mixed,
+        // so each pixel is checked the way simplex dithering would project it instead
+        let lab_palette: Vec<Lab> = linear_palette.iter().cloned().map(Lab::from).collect();
+        let outside: Vec<Srgb8> = unique_pixels.into_iter().filter(|pixel| {
+            match hull {
+                Some(ref hull) => hull.distance_outside(LinearRgb::from(*pixel)) > 1e-9,
+                None => dither::tight_simplex_projected(LinearRgb::from(*pixel), palette, &linear_palette, &lab_palette, 0.5, distance2).1.sqrt() > dither::GAMUT_TOLERANCE,
+            }
+        }).collect();
+        // Drawing every color of a photograph would just be a blur, so only draw a representative sample
+        let stride = (outside.len() / 4096).max(1);
+        for &pixel in outside.iter().step_by(stride) {
+            for &point in &project(LinearRgb::from(pixel)) {
+                shapes.push(Shape::Cross(point, 3.0, pixel));
+            }
+        }
+
+        for (&color, &linear) in palette.iter().zip(&linear_palette) {
+            for &point in &project(linear) {
+                shapes.push(Shape::Dot(point, 4.0, color));
+            }
+        }
+
+        GamutPlot { shapes, outside_colors: outside.len() }
+    }
+
+    pub fn to_image(&self) -> image::RgbImage {
+        let mut img = image::RgbImage::from_pixel(2 * PANEL_SIZE as u32, PANEL_SIZE as u32, image::Rgb([0x30, 0x30, 0x30]));
+        for shape in &self.shapes {
+            match *shape {
+                Shape::Line(from, to, color) => draw_line(&mut img, from, to, color),
+                Shape::Cross([x, y], size, color) => {
+                    draw_line(&mut img, [x - size, y - size], [x + size, y + size], color);
+                    draw_line(&mut img, [x - size, y + size], [x + size, y - size], color);
+                },
+                Shape::Dot([x, y], radius, color) => {
+                    let outline = contrasting(color);
+                    for dy in -radius as i32 - 1..=radius as i32 + 1 {
+                        for dx in -radius as i32 - 1..=radius as i32 + 1 {
+                            let dist = ((dx * dx + dy * dy) as f64).sqrt();
+                            if dist <= radius {
+                                plot(&mut img, x + dx as f64, y + dy as f64, color);
+                            } else if dist <= radius + 1.0 {
+                                plot(&mut img, x + dx as f64, y + dy as f64, outline);
+                            }
+                        }
+                    }
+                },
+            }
+        }
+
+        img
+    }
+
+    pub fn to_svg(&self) -> String {
+        let hex = |color: Srgb8| format!("#{:02x}{:02x}{:02x}", color.0[0], color.0[1], color.0[2]);
+
+        let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{1}\" viewBox=\"0 0 {0} {1}\">\n", 2.0 * PANEL_SIZE, PANEL_SIZE);
+        svg += &format!("<rect width=\"100%\" height=\"100%\" fill=\"{}\"/>\n", hex(image::Rgb([0x30, 0x30, 0x30])));
+        for shape in &self.shapes {
+            svg += &match *shape {
+                Shape::Line(from, to, color) => {
+                    format!("<line x1=\"{:.2}\" y1=\"{:.2}\" x2=\"{:.2}\" y2=\"{:.2}\" stroke=\"{}\"/>\n", from[0], from[1], to[0], to[1], hex(color))
+                },
+                Shape::Cross([x, y], size, color) => {
+                    format!("<path d=\"M{:.2} {:.2}L{:.2} {:.2}M{:.2} {:.2}L{:.2} {:.2}\" stroke=\"{}\"/>\n",
+                            x - size, y - size, x + size, y + size, x - size, y + size, x + size, y - size, hex(color))
+                },
+                Shape::Dot([x, y], radius, color) => {
+                    format!("<circle cx=\"{:.2}\" cy=\"{:.2}\" r=\"{}\" fill=\"{}\" stroke=\"{}\"/>\n", x, y, radius, hex(color), hex(contrasting(color)))
+                },
+            };
+        }
+        svg += "</svg>\n";
+        svg
+    }
+}
+
+//////// Contact sheets ////////
+
+const SHEET_MARGIN: u32 = 8;
+
+// Lays out images in a grid with a few lines of text under each one, filling the rows from left to right. Text that
+// doesn't fit under its image is cut off.
+pub fn render_contact_sheet(tiles: &[(image::RgbImage, Vec<String>)], columns: usize) -> image::RgbImage {
+    let tile_width = tiles.iter().map(|(tile, _)| tile.width()).max().unwrap_or(0);
+    let tile_height = tiles.iter().map(|(tile, _)| tile.height()).max().unwrap_or(0);
+    let line_count = tiles.iter().map(|(_, lines)| lines.len()).max().unwrap_or(0) as u32;
+    let scale = if tile_width >= 160 { 2 } else { 1 };
+    let line_height = 6 * scale;
+    let max_chars = (tile_width / (4 * scale)) as usize;
+
+    let cell_width = tile_width + SHEET_MARGIN;
+    let cell_height = tile_height + SHEET_MARGIN / 2 + line_count * line_height + SHEET_MARGIN;
+    let rows = tiles.len().div_ceil(columns) as u32;
+    let mut img = image::RgbImage::from_pixel(columns as u32 * cell_width + SHEET_MARGIN, rows * cell_height + SHEET_MARGIN, image::Rgb([0x30, 0x30, 0x30]));
+
+    for (i, (tile, lines)) in tiles.iter().enumerate() {
+        let x = SHEET_MARGIN + (i % columns) as u32 * cell_width;
+        let y = SHEET_MARGIN + (i / columns) as u32 * cell_height;
+        image::imageops::replace(&mut img, tile, x as i64, y as i64);
+        for (j, line) in lines.iter().enumerate() {
+            let line: String = line.chars().take(max_chars).collect();
+            draw_text(&mut img, x, y + tile_height + SHEET_MARGIN / 2 + j as u32 * line_height, &line, scale, image::Rgb([0xe0, 0xe0, 0xe0]));
+        }
+    }
+
+    img
+}
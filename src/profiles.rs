@@ -0,0 +1,181 @@
+use crate::color::{self, ColorSpace, RgbPrimaries, Transfer, WhitePoint};
+use crate::geom::{self, Matrix3};
+use std::convert::TryInto;
+
+// Working out which color space an image file's values are in, and recording the working space in the files we write.
+// Only PNGs are supported, through their cICP, iCCP, sRGB, gAMA and cHRM chunks. Everything else is taken to be sRGB.
+
+// The code points that a cICP chunk (or a video stream) uses for the primaries and transfer functions we support
+const CICP_PRIMARIES: &[(u8, RgbPrimaries)] = &[(1, RgbPrimaries::SRGB), (9, RgbPrimaries::REC2020), (12, RgbPrimaries::DISPLAY_P3)];
+const CICP_TRANSFERS: &[(u8, Transfer)] = &[
+    (13, Transfer::Srgb),
+    (1, Transfer::Rec709), (6, Transfer::Rec709), (14, Transfer::Rec709), (15, Transfer::Rec709),
+    (4, Transfer::Gamma(2.2)), (5, Transfer::Gamma(2.8)), (8, Transfer::Gamma(1.0)),
+];
+
+// Finds a PNG's color space, following the precedence the PNG specification gives the chunks that describe it
+pub fn detect_png(data: &[u8]) -> ColorSpace {
+    if let Some(cicp) = find_chunk(data, b"cICP") {
+        match from_cicp(cicp) {
+            Some(space) => return space,
+            None => eprintln!("Warning: unsupported cICP color space {:?}, assuming sRGB", cicp),
+        }
+    }
+
+    // The png crate's own iCCP decoding loses the end of the profile, so we decompress it ourselves
+    if let Some(iccp) = find_chunk(data, b"iCCP") {
+        let compressed = iccp.iter().position(|&byte| byte == 0).and_then(|end| iccp.get(end + 2..));
+        match compressed.and_then(|compressed| miniz_oxide::inflate::decompress_to_vec_zlib(compressed).ok()).and_then(|profile| from_icc(&profile)) {
+            Some(space) => return space,
+            None => eprintln!("Warning: unsupported ICC profile, assuming sRGB"),
+        }
+    }
+
+    let reader = match png::Decoder::new(data).read_info() {
+        Ok(reader) => reader,
+        Err(_) => return ColorSpace::SRGB,
+    };
+    let info = reader.info();
+    if info.srgb.is_some() {
+        return ColorSpace::SRGB;
+    }
+
+    let gamma = info.source_gamma.map(|gamma| 1.0 / gamma.into_value() as f64);
+    let primaries = info.source_chromaticities.map(|chromaticities| {
+        let xy = |(x, y): (png::ScaledFloat, png::ScaledFloat)| [x.into_value() as f64, y.into_value() as f64];
+        let [white_x, white_y] = xy(chromaticities.white);
+        RgbPrimaries {
+            red: xy(chromaticities.red),
+            green: xy(chromaticities.green),
+            blue: xy(chromaticities.blue),
+            white: WhitePoint { x: white_x, y: white_y },
+        }
+    });
+    // cHRM values only have five decimal places, so snap them to the standard primaries they were meant to be
+    let primaries = primaries.map_or(RgbPrimaries::SRGB, |primaries| {
+        color::COLOR_SPACES.iter().map(|named| named.space.primaries).find(|known| known.approx_eq(&primaries)).unwrap_or(primaries)
+    });
+    match gamma {
+        None => ColorSpace { primaries, transfer: Transfer::Srgb },
+        // A gAMA of 1/2.2 is what many programs write to mean the sRGB curve
+        Some(gamma) if (gamma - 2.2).abs() < 0.02 && primaries == RgbPrimaries::SRGB => ColorSpace::SRGB,
+        Some(gamma) => ColorSpace { primaries, transfer: Transfer::Gamma(gamma) },
+    }
+}
+
+// The contents of the first chunk of the given type before the image data, if there is one
+fn find_chunk<'a>(data: &'a [u8], chunk_type: &[u8; 4]) -> Option<&'a [u8]> {
+    let mut rest = data.get(8..)?;
+    while rest.len() >= 12 {
+        let length = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let (current_type, contents) = (&rest[4..8], rest.get(8..8 + length)?);
+        if current_type == chunk_type {
+            return Some(contents);
+        } else if current_type == b"IDAT" {
+            return None;
+        }
+        rest = rest.get(12 + length..)?;
+    }
+    None
+}
+
+fn from_cicp(cicp: &[u8]) -> Option<ColorSpace> {
+    // We only handle full range RGB, not YCbCr
+    if cicp.len() != 4 || cicp[2] != 0 || cicp[3] != 1 {
+        return None;
+    }
+    let primaries = CICP_PRIMARIES.iter().find(|&&(code, _)| code == cicp[0])?.1;
+    let transfer = CICP_TRANSFERS.iter().find(|&&(code, _)| code == cicp[1])?.1;
+    Some(ColorSpace { primaries, transfer })
+}
+
+// Reads a matrix/TRC ICC profile, which is what RGB images embed. If the colorants match one of the standard spaces, we
+// use that exact space, since profiles only store them to about four decimal places. Otherwise, we rebuild the space
+// from the colorants and the red channel's tone curve.
+fn from_icc(profile: &[u8]) -> Option<ColorSpace> {
+    let read_u32 = |offset: usize| -> Option<u32> {
+        Some(u32::from_be_bytes(profile.get(offset..offset + 4)?.try_into().ok()?))
+    };
+    let fixed = |offset: usize| -> Option<f64> { Some(read_u32(offset)? as i32 as f64 / 65536.0) };
+    if profile.get(16..20)? != b"RGB " {
+        return None;
+    }
+
+    let tag_count = read_u32(128)? as usize;
+    let tag = |signature: &[u8; 4]| -> Option<usize> {
+        (0..tag_count).map(|i| 132 + 12 * i).find(|&entry| profile.get(entry..entry + 4) == Some(&signature[..]))
+            .and_then(|entry| read_u32(entry + 4)).map(|offset| offset as usize)
+    };
+    let colorant = |signature: &[u8; 4]| -> Option<[f64; 3]> {
+        let offset = tag(signature)?;
+        Some([fixed(offset + 8)?, fixed(offset + 12)?, fixed(offset + 16)?])
+    };
+    let (r, g, b) = (colorant(b"rXYZ")?, colorant(b"gXYZ")?, colorant(b"bXYZ")?);
+    // The colorants are relative to the profile connection space's D50 white
+    let to_pcs: Matrix3 = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+
+    for named in color::COLOR_SPACES {
+        let expected = geom::multiply(&color::bradford(named.space.primaries.white, WhitePoint::D50), &named.space.primaries.to_xyz_matrix());
+        if (0..3).all(|i| (0..3).all(|j| (expected[i][j] - to_pcs[i][j]).abs() < 0.005)) {
+            return Some(named.space);
+        }
+    }
+
+    // Profiles have already adapted their colorants to D50, so this assumes the common case of a D65 display
+    let to_xyz = geom::multiply(&color::bradford(WhitePoint::D50, WhitePoint::D65), &to_pcs);
+    let chromaticity = |column: usize| {
+        let sum = to_xyz[0][column] + to_xyz[1][column] + to_xyz[2][column];
+        [to_xyz[0][column] / sum, to_xyz[1][column] / sum]
+    };
+    let transfer = tag(b"rTRC").and_then(|offset| match profile.get(offset..offset + 4)? {
+        b"curv" => match read_u32(offset + 8)? {
+            0 => Some(Transfer::Gamma(1.0)),
+            1 => Some(Transfer::Gamma(u16::from_be_bytes(profile.get(offset + 12..offset + 14)?.try_into().ok()?) as f64 / 256.0)),
+            _ => None,
+        },
+        b"para" if profile.get(offset + 8..offset + 10)? == [0, 0] => Some(Transfer::Gamma(fixed(offset + 12)?)),
+        _ => None,
+    }).unwrap_or(Transfer::Srgb);
+    Some(ColorSpace {
+        primaries: RgbPrimaries { red: chromaticity(0), green: chromaticity(1), blue: chromaticity(2), white: WhitePoint::D65 },
+        transfer,
+    })
+}
+
+// Describes a color space with the gAMA and cHRM chunks that every PNG reader understands, although only approximately
+// for spaces that aren't a pure power law. This has to happen before the header is written.
+pub fn tag_png_header<W: std::io::Write>(encoder: &mut png::Encoder<W>, space: &ColorSpace) {
+    let gamma = match space.transfer {
+        Transfer::Gamma(gamma) => gamma,
+        Transfer::Srgb | Transfer::Rec709 => 2.2,
+    };
+    encoder.set_source_gamma(png::ScaledFloat::new((1.0 / gamma) as f32));
+    let xy = |[x, y]: [f64; 2]| (x as f32, y as f32);
+    let primaries = &space.primaries;
+    encoder.set_source_chromaticities(png::SourceChromaticities::new(
+        xy([primaries.white.x, primaries.white.y]), xy(primaries.red), xy(primaries.green), xy(primaries.blue)));
+}
+
+// Adds a cICP chunk, which describes the color space exactly, where it has code points for it
+pub fn write_cicp<W: std::io::Write>(writer: &mut png::Writer<W>, space: &ColorSpace) {
+    let primaries = CICP_PRIMARIES.iter().find(|&&(_, primaries)| primaries == space.primaries);
+    let transfer = CICP_TRANSFERS.iter().find(|&&(_, transfer)| transfer == space.transfer);
+    if let (Some(&(primaries, _)), Some(&(transfer, _))) = (primaries, transfer) {
+        writer.write_chunk(png::chunk::ChunkType(*b"cICP"), &[primaries, transfer, 0, 1]).unwrap();
+    }
+}
+
+// Encodes an image as a PNG tagged with the working space
+pub fn encode_png(img: &image::RgbImage) -> Vec<u8> {
+    let space = color::working_space();
+    let mut data = Vec::new();
+    let mut encoder = png::Encoder::new(&mut data, img.width(), img.height());
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    tag_png_header(&mut encoder, &space);
+    let mut writer = encoder.write_header().unwrap();
+    write_cicp(&mut writer, &space);
+    writer.write_image_data(img).unwrap();
+    writer.finish().unwrap();
+    data
+}
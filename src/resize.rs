@@ -0,0 +1,95 @@
+use crate::color::{LinearRgb, LinearImage};
+
+use image::imageops::FilterType;
+
+pub const FILTERS: [&str; 5] = ["nearest", "triangle", "catmullrom", "gaussian", "lanczos3"];
+
+pub fn parse_filter(name: &str) -> FilterType {
+    match name {
+        "nearest" => FilterType::Nearest,
+        "triangle" => FilterType::Triangle,
+        "catmullrom" => FilterType::CatmullRom,
+        "gaussian" => FilterType::Gaussian,
+        "lanczos3" => FilterType::Lanczos3,
+        _ => panic!("Unrecognized resampling filter {:?}!", name),
+    }
+}
+
+// Works out the size to resize an image to from either a --resize value like `320x240` or a --scale factor. Either
+// dimension of --resize can be left out (as in `320x` or `x240`) to keep the aspect ratio.
+pub fn requested_size(resize: Option<&str>, scale: Option<f64>, width: u32, height: u32) -> Option<(u32, u32)> {
+    let scaled = |length: u32, factor: f64| ((length as f64 * factor).round() as u32).max(1);
+
+    if let Some(spec) = resize {
+        let parse = |value: &str| -> Option<u32> {
+            if value.is_empty() {
+                None
+            } else {
+                match value.parse() {
+                    Ok(length) if length > 0 => Some(length),
+                    _ => panic!("Invalid size {:?}! Use something like 320x240, 320x or x240.", spec),
+                }
+            }
+        };
+        let (new_width, new_height) = match spec.split_once('x') {
+            Some((new_width, new_height)) => (parse(new_width), parse(new_height)),
+            None => panic!("Invalid size {:?}! Use something like 320x240, 320x or x240.", spec),
+        };
+        Some(match (new_width, new_height) {
+            (Some(new_width), Some(new_height)) => (new_width, new_height),
+            (Some(new_width), None) => (new_width, scaled(height, new_width as f64 / width as f64)),
+            (None, Some(new_height)) => (scaled(width, new_height as f64 / height as f64), new_height),
+            (None, None) => panic!("Invalid size {:?}! At least one of the width and height is needed.", spec),
+        })
+    } else {
+        scale.map(|factor| {
+            if factor <= 0.0 || factor.is_nan() {
+                panic!("The scale factor must be positive!");
+            }
+            (scaled(width, factor), scaled(height, factor))
+        })
+    }
+}
+
+// Resamples an image in linear light. Doing this directly on sRGB values (as most tools do) darkens fine detail and
+// shifts the average color, which the dither would then faithfully reproduce.
+//
+// `image` clips the result to the range from 0 to 1, so floating point images with highlights brighter than white are
+// scaled down to fit first and back up afterwards.
+pub fn resize(img: &LinearImage, width: u32, height: u32, filter: FilterType) -> LinearImage {
+    let peak = img.pixels.iter().flat_map(|pixel| pixel.data).fold(1.0, f64::max);
+    let linear: image::Rgb32FImage = image::ImageBuffer::from_fn(img.width, img.height, |x, y| {
+        let rgb = img.get(x, y);
+        image::Rgb([(rgb.data[0] / peak) as f32, (rgb.data[1] / peak) as f32, (rgb.data[2] / peak) as f32])
+    });
+    let resized = image::imageops::resize(&linear, width, height, filter);
+    LinearImage {
+        width,
+        height,
+        pixels: resized.pixels().map(|pixel| LinearRgb { data: [pixel.0[0] as f64 * peak, pixel.0[1] as f64 * peak, pixel.0[2] as f64 * peak] }).collect(),
+    }
+}
+
+// Resizes a per-pixel mask (such as which pixels are transparent) by picking the nearest source pixel
+pub fn resize_mask(mask: &[bool], width: u32, height: u32, new_width: u32, new_height: u32) -> Vec<bool> {
+    let source = |position: u32, length: u32, new_length: u32| {
+        (((position as f64 + 0.5) * length as f64 / new_length as f64) as u32).min(length - 1)
+    };
+    (0..new_height).flat_map(|y| (0..new_width).map(move |x| (x, y))).map(|(x, y)| {
+        mask[(source(y, height, new_height) * width + source(x, width, new_width)) as usize]
+    }).collect()
+}
+
+// Scales up by a whole number, turning each pixel into a block, for previewing pixel art at a visible size
+pub fn upscale(img: &image::RgbImage, factor: u32) -> image::RgbImage {
+    image::RgbImage::from_fn(img.width() * factor, img.height() * factor, |x, y| *img.get_pixel(x / factor, y / factor))
+}
+
+pub fn upscale_mask(mask: &[bool], width: u32, height: u32, factor: u32) -> Vec<bool> {
+    resize_mask(mask, width, height, width * factor, height * factor)
+}
+
+// Repeats each of a row-major grid of values in a `factor` by `factor` square, like `upscale`
+pub fn upscale_values<T: Copy>(values: &[T], width: u32, height: u32, factor: u32) -> Vec<T> {
+    (0..height * factor).flat_map(|y| (0..width * factor).map(move |x| values[(y / factor * width + x / factor) as usize])).collect()
+}
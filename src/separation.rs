@@ -0,0 +1,159 @@
+use crate::bias::{self, ScreenGeometry};
+use crate::color::{LinearRgb, Srgb8};
+use crate::files;
+use crate::find_bias;
+
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+// Four-color printing: splitting an image into cyan, magenta, yellow and black separations and halftoning each one with
+// its own screen. The screens are rotated away from each other so that they don't beat against each other in a visible
+// moire, and black goes at 45 degrees, where the eye notices the pattern least.
+//
+// Inks are treated as ideal filters, with cyan absorbing only red light and so on, which makes an ink's coverage one
+// minus the linear reflectance it leaves in its channel. Halftone dots of that coverage then average out to the same
+// reflectance, so the separations are done in linear light, like everything else.
+
+pub const INK_NAMES: [&str; 4] = ["c", "m", "y", "k"];
+
+// Where each ink reads a bias pattern that isn't a screen from, relative to the pixel. Patterns can't be rotated like
+// screens, but shifting them keeps the inks from all printing in the same places, dot on dot.
+const INK_OFFSETS: [(u32, u32); 4] = [(0, 0), (3, 5), (5, 2), (2, 7)];
+
+// How black replaces the gray component that cyan, magenta and yellow have in common
+pub enum BlackGeneration {
+    // Gray component replacement: black takes over some fraction of the gray component everywhere
+    Gcr,
+    // Undercolor removal: black only replaces the colored inks in the shadows, where they pile up the most
+    Ucr,
+}
+
+pub struct Separation {
+    pub black_generation: BlackGeneration,
+    // The fraction of the gray component given to black, from 0 to 1
+    pub black_amount: f64,
+    // The most ink allowed on any one spot, as a total coverage out of 4
+    pub ink_limit: f64,
+}
+
+impl Separation {
+    pub fn from_matches(arg_matches: &clap::ArgMatches) -> Separation {
+        let separation = Separation {
+            black_generation: match arg_matches.value_of("BLACK_GENERATION").unwrap() {
+                "gcr" => BlackGeneration::Gcr,
+                "ucr" => BlackGeneration::Ucr,
+                other => panic!("Unrecognized black generation {:?}!", other),
+            },
+            black_amount: arg_matches.value_of_t_or_exit("BLACK_AMOUNT"),
+            ink_limit: arg_matches.value_of_t_or_exit::<f64>("INK_LIMIT") / 100.0,
+        };
+        if !(0.0..=1.0).contains(&separation.black_amount) || separation.ink_limit <= 0.0 {
+            panic!("The black amount must be between 0 and 1, and the ink limit must be positive!");
+        }
+        separation
+    }
+
+    // The coverage of each ink, from 0 to 1
+    pub fn separate(&self, rgb: LinearRgb) -> [f64; 4] {
+        let rgb = rgb.clamp();
+        let [c, m, y] = [1.0 - rgb.data[0], 1.0 - rgb.data[1], 1.0 - rgb.data[2]];
+        let gray = c.min(m).min(y);
+        // Black alone can't go over the ink limit either, in which case it gets all of it
+        let k = (self.black_amount * match self.black_generation {
+            BlackGeneration::Gcr => gray,
+            BlackGeneration::Ucr => (2.0 * gray - 1.0).max(0.0),
+        }).min(self.ink_limit);
+        if k >= 1.0 {
+            return [0.0, 0.0, 0.0, 1.0];
+        }
+
+        // Black darkens every channel, so the colored inks only need to make up the rest of the absorption
+        let under = |ink: f64| (ink - k) / (1.0 - k);
+        let mut inks = [under(c), under(m), under(y), k];
+        let colored: f64 = inks[..3].iter().sum();
+        if colored + k > self.ink_limit && colored > 0.0 {
+            let scale = ((self.ink_limit - k) / colored).max(0.0);
+            for ink in &mut inks[..3] {
+                *ink *= scale;
+            }
+        }
+        inks
+    }
+}
+
+// What the printed inks look like together, on white paper
+pub fn composite(inks: [bool; 4]) -> Srgb8 {
+    let channel = |ink: bool| if ink || inks[3] { 0.0 } else { 1.0 };
+    Srgb8::from(LinearRgb { data: [channel(inks[0]), channel(inks[1]), channel(inks[2])] })
+}
+
+pub fn run(arg_matches: &clap::ArgMatches) {
+    let separation = Separation::from_matches(arg_matches);
+    let named_bias = find_bias(arg_matches.value_of("BIAS").unwrap());
+    let base_geometry = ScreenGeometry::from_matches(arg_matches);
+    let angles: Vec<f64> = arg_matches.value_of("ANGLES").unwrap().split(',').map(|angle| match angle.trim().parse() {
+        Ok(angle) => angle,
+        Err(_) => panic!("Invalid screen angle {:?}!", angle),
+    }).collect();
+    if angles.len() != 4 {
+        panic!("Give four screen angles, for cyan, magenta, yellow and black in that order!");
+    }
+    let screens: Vec<bias::BiasFunc> = match named_bias.source {
+        bias::BiasSource::Screen(_) => angles.iter().map(|&angle| named_bias.build(&ScreenGeometry { angle, ..base_geometry })).collect(),
+        bias::BiasSource::Pattern(_) => {
+            if arg_matches.occurrences_of("ANGLES") > 0 {
+                panic!("Only halftone screens can be rotated! Leave out --angles to shift the pattern for each ink instead.");
+            }
+            INK_OFFSETS.iter().map(|&(dx, dy)| {
+                let pattern = named_bias.build(&base_geometry);
+                Box::new(move |x, y| pattern(x + dx, y + dy)) as bias::BiasFunc
+            }).collect()
+        },
+    };
+
+    let img = files::InputFile::read(arg_matches.value_of_os("IMAGE").unwrap()).image();
+    let (width, height) = (img.width, img.height);
+
+    // A dot prints wherever the ink's coverage exceeds the screen's bias, so that each dot grows from the screen's low
+    // points as the tone darkens
+    let plates: Vec<Vec<bool>> = (0..4).into_par_iter().map(|ink| {
+        (0..height).flat_map(|y| (0..width).map(move |x| (x, y))).map(|(x, y)| {
+            separation.separate(img.get(x, y))[ink] > screens[ink](x, y)
+        }).collect()
+    }).collect();
+
+    for (name, plate) in INK_NAMES.iter().zip(&plates) {
+        let coverage = plate.iter().filter(|&&inked| inked).count() as f64 / plate.len() as f64;
+        eprintln!("{} coverage: {:.1}%", name.to_uppercase(), 100.0 * coverage);
+    }
+
+    if let Some(prefix) = arg_matches.value_of("PLATES") {
+        for (name, plate) in INK_NAMES.iter().zip(&plates) {
+            save_plate(plate, width, height, &format!("{}_{}.png", prefix, name));
+        }
+    }
+
+    let preview = image::RgbImage::from_fn(width, height, |x, y| {
+        let i = (y * width + x) as usize;
+        composite([plates[0][i], plates[1][i], plates[2][i], plates[3][i]])
+    });
+    let format = arg_matches.value_of("FORMAT").map(files::parse_format);
+    files::save_image(&preview, arg_matches.value_of_os("OUTPUT").unwrap(), format);
+}
+
+// Writes a plate as a 1-bit PNG, with black where the ink goes
+fn save_plate(plate: &[bool], width: u32, height: u32, file_name: &str) {
+    let row_bytes = (width as usize).div_ceil(8);
+    let mut data = vec![0u8; row_bytes * height as usize];
+    for (i, &inked) in plate.iter().enumerate() {
+        let (x, y) = (i % width as usize, i / width as usize);
+        if !inked {
+            data[y * row_bytes + x / 8] |= 0x80 >> (x % 8);
+        }
+    }
+
+    let file = std::io::BufWriter::new(std::fs::File::create(file_name).unwrap());
+    let mut encoder = png::Encoder::new(file, width, height);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::One);
+    encoder.write_header().unwrap().write_image_data(&data).unwrap();
+}
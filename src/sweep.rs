@@ -0,0 +1,127 @@
+use crate::{find_algorithm, find_bias, find_color_space, find_distance, palette_choice, PaletteChoice};
+use crate::bias;
+use crate::color::{Srgb8, LinearRgb, LinearImage, Lab};
+use crate::dither::{self, Algorithm};
+use crate::files;
+use crate::metrics::{self, Metrics};
+use crate::palettes;
+use crate::preview;
+
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+
+// Dithers one image with every combination of the given algorithms, palettes and biases, and lays the results out
+// side by side in a contact sheet. Each row uses one palette, with the algorithm and bias varying along it.
+pub fn run(arg_matches: &clap::ArgMatches) {
+    let list = |name: &str| -> Vec<&str> {
+        let values: Vec<&str> = arg_matches.value_of(name).unwrap().split(',').map(str::trim).filter(|value| !value.is_empty()).collect();
+        if values.is_empty() {
+            panic!("Nothing to compare! Give at least one value for each option.");
+        }
+        values
+    };
+    if arg_matches.is_present("CHANNELS") {
+        panic!("Sweeps can't use --channels! Compare grid palettes like websafe with the per-channel algorithm instead.");
+    }
+    if arg_matches.is_present("BOUNDING_PALETTE") {
+        panic!("Sweeps can't use --bounding-palette! Dither with each bounding palette on its own instead.");
+    }
+    if arg_matches.is_present("BLOCK") {
+        panic!("Sweeps can't use --block! Dither with each setting on its own instead.");
+    }
+    let algorithm_names = list("ALGORITHM");
+    let palette_names = list("PALETTE");
+    let bias_names = list("BIAS");
+    let palette_size: usize = arg_matches.value_of_t_or_exit("PALETTE_SIZE");
+    let distance2_func = find_distance(arg_matches.value_of("DISTANCE").unwrap());
+    let palette_space = find_color_space(arg_matches.value_of("PALETTE_SPACE").unwrap());
+    let show_metrics = arg_matches.is_present("metrics");
+
+    // Look everything up before starting, so that typos are caught before any of the slow parts
+    let algorithms: Vec<&Algorithm> = algorithm_names.iter().map(|&name| find_algorithm(name)).collect();
+    let palette_choices: Vec<PaletteChoice> = palette_names.iter().map(|&name| palette_choice(name)).collect();
+    let screen_geometry = bias::ScreenGeometry::from_matches(arg_matches);
+    let biases: Vec<_> = bias_names.iter().map(|&name| find_bias(name).build(&screen_geometry)).collect();
+
+    let out_file_name = arg_matches.value_of_os("OUTPUT").unwrap();
+    if show_metrics && files::is_stdio(out_file_name) {
+        panic!("Metrics can't be printed while writing the contact sheet to stdout!");
+    }
+
+    let img = files::InputFile::read(arg_matches.value_of_os("IMAGE").unwrap()).image();
+
+    let palettes: Vec<(Vec<Srgb8>, Vec<LinearRgb>, Vec<Lab>)> = palette_choices.iter().map(|choice| {
+        let palette = match *choice {
+            PaletteChoice::Entries(ref entries) => palettes::shown_colors(entries, &palette_space),
+            PaletteChoice::Procedural(build) => build(palette_size, &img.pixels, distance2_func),
+            _ => unreachable!(),
+        };
+        let linear_palette: Vec<_> = palette.iter().cloned().map(LinearRgb::from).collect();
+        let lab_palette: Vec<_> = linear_palette.iter().cloned().map(Lab::from).collect();
+        (palette, linear_palette, lab_palette)
+    }).collect();
+    eprintln!("Generated palettes. Dithering...");
+
+    // Error diffusion ignores the bias, so those algorithms are only run once per palette and shown under every bias. The
+    // per-channel algorithm only works with grid palettes, so its tiles for other palettes are left empty.
+    let is_grid: Vec<bool> = palettes.iter().map(|(palette, _, _)| dither::grid_levels(palette).is_some()).collect();
+    let mut jobs = Vec::new();
+    let mut tile_jobs = Vec::new();
+    for (p, &grid) in is_grid.iter().enumerate() {
+        for (a, algorithm) in algorithms.iter().enumerate() {
+            for b in 0..biases.len() {
+                if matches!(**algorithm, Algorithm::PerChannel) && !grid {
+                    tile_jobs.push((p, a, None));
+                    continue;
+                }
+                if b == 0 || matches!(**algorithm, Algorithm::Ordered(_) | Algorithm::PerChannel) {
+                    jobs.push((p, a, b));
+                }
+                tile_jobs.push((p, a, Some(jobs.len() - 1)));
+            }
+        }
+    }
+    if jobs.is_empty() {
+        panic!("The per-channel algorithm needs a palette with every combination of its channel values, like websafe or one from --channels!");
+    }
+
+    let results: Vec<(image::RgbImage, Option<Metrics>)> = jobs.par_iter().map(|&(p, a, b)| {
+        let (ref palette, ref linear_palette, ref lab_palette) = palettes[p];
+        let dithered = dither::dither_image(&img, algorithms[a], palette, linear_palette, lab_palette, &biases[b], distance2_func);
+        let metrics = if show_metrics { Some(Metrics::compute(&img, &LinearImage::from_rgb8(&dithered))) } else { None };
+        (dithered, metrics)
+    }).collect();
+
+    // The label for the bias used with a given algorithm
+    let bias_label = |a: usize, b: usize| match *algorithms[a] {
+        Algorithm::Ordered(_) | Algorithm::PerChannel => bias_names[b].to_string(),
+        Algorithm::FloydSteinberg { .. } => "(no bias)".to_string(),
+    };
+
+    if show_metrics {
+        for (&(p, a, b), (_, metrics)) in jobs.iter().zip(&results) {
+            println!("{}@{} {}:", algorithm_names[a], palette_names[p], bias_label(a, b));
+            print!("{}", metrics.as_ref().unwrap().to_text());
+        }
+    }
+
+    let tiles: Vec<(image::RgbImage, Vec<String>)> = tile_jobs.iter().enumerate().map(|(i, &(p, a, job))| {
+        let mut lines = vec![algorithm_names[a].to_string(), palette_names[p].to_string()];
+        let job = match job {
+            Some(job) => job,
+            None => {
+                lines.push("(not a grid palette)".to_string());
+                return (image::RgbImage::new(0, 0), lines);
+            },
+        };
+        lines.push(bias_label(a, i % biases.len()));
+        let (ref dithered, ref metrics) = results[job];
+        if let Some(ref metrics) = *metrics {
+            lines.push(format!("PSNR {:.2} SSIM {:.3}", metrics.psnr, metrics.ssim));
+            lines.push(format!("DE {:.2} at sigma {}", metrics.low_pass_delta_e[1], metrics::LOW_PASS_SIGMAS[1]));
+        }
+        (dithered.clone(), lines)
+    }).collect();
+
+    let columns = algorithms.len() * biases.len();
+    files::save_image(&preview::render_contact_sheet(&tiles, columns), out_file_name, None);
+}
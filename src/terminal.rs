@@ -0,0 +1,133 @@
+use crate::color::{LinearImage, Srgb8};
+use crate::resize;
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+// Showing dithered images right in the terminal, for machines where there's nothing to open a file with. Images wider
+// than the terminal are shrunk to fit, averaging in linear light for half-blocks (which can show any color) and
+// sampling the nearest pixel for Sixel (which has a limited number of color registers).
+
+pub const PREVIEW_KINDS: [&str; 2] = ["terminal", "sixel"];
+
+// Most terminals can't say how wide their cells are in pixels without a round trip through the terminal itself, so
+// Sixel images are sized assuming this many pixels per column
+const SIXEL_CELL_WIDTH: u32 = 10;
+
+// The terminal's width in columns, from $COLUMNS, asking stty, or guessing 80
+fn columns() -> u32 {
+    if let Some(columns) = std::env::var("COLUMNS").ok().and_then(|columns| columns.parse().ok()) {
+        return columns;
+    }
+    let stty = std::fs::File::open("/dev/tty").ok().and_then(|tty| {
+        std::process::Command::new("stty").arg("size").stdin(tty).stderr(std::process::Stdio::null()).output().ok()
+    });
+    stty.and_then(|output| String::from_utf8(output.stdout).ok())
+        .and_then(|size| size.split_whitespace().nth(1).and_then(|columns| columns.parse().ok()))
+        .filter(|&columns| columns > 0)
+        .unwrap_or(80)
+}
+
+// The size to show an image at, no wider than `max_width`
+fn fit(img: &image::RgbImage, max_width: u32) -> (u32, u32) {
+    if img.width() <= max_width {
+        (img.width(), img.height())
+    } else {
+        (max_width, ((img.height() as f64 * max_width as f64 / img.width() as f64).round() as u32).max(1))
+    }
+}
+
+// Two pixels per character cell: the top one as the foreground of an upper half block, and the bottom one as its
+// background, both as 24-bit ANSI colors
+pub fn half_blocks(img: &image::RgbImage) -> String {
+    let (width, height) = fit(img, columns());
+    let img = if (width, height) == img.dimensions() {
+        img.clone()
+    } else {
+        resize::resize(&LinearImage::from_rgb8(img), width, height, image::imageops::FilterType::Triangle).to_srgb8()
+    };
+
+    let mut text = String::new();
+    for y in (0..height).step_by(2) {
+        for x in 0..width {
+            let [r, g, b] = img.get_pixel(x, y).0;
+            write!(text, "\x1b[38;2;{};{};{}m", r, g, b).unwrap();
+            if y + 1 < height {
+                let [r, g, b] = img.get_pixel(x, y + 1).0;
+                write!(text, "\x1b[48;2;{};{};{}m", r, g, b).unwrap();
+            }
+            text.push('\u{2580}');
+        }
+        text.push_str("\x1b[0m\n");
+    }
+    text
+}
+
+// A DEC Sixel image, which terminals like xterm, mlterm, foot and WezTerm draw at full resolution. Each band of six
+// rows is drawn once per color in it, with each character giving which of the six pixels in its column are that color.
+pub fn sixel(img: &image::RgbImage) -> String {
+    let (width, height) = fit(img, columns() * SIXEL_CELL_WIDTH);
+    let mut img = image::imageops::resize(img, width, height, image::imageops::FilterType::Nearest);
+
+    // Terminals usually have 256 color registers, so anything with more colors is rounded to an 8x8x4 grid
+    let distinct = |img: &image::RgbImage| -> Vec<Srgb8> {
+        let mut colors: Vec<Srgb8> = img.pixels().cloned().collect();
+        colors.sort_unstable_by_key(|color| color.0);
+        colors.dedup();
+        colors
+    };
+    let mut colors = distinct(&img);
+    if colors.len() > 256 {
+        let round = |value: u8, levels: u32| ((value as u32 * (levels - 1) + 127) / 255 * 255 / (levels - 1)) as u8;
+        for pixel in img.pixels_mut() {
+            let [r, g, b] = pixel.0;
+            *pixel = image::Rgb([round(r, 8), round(g, 8), round(b, 4)]);
+        }
+        colors = distinct(&img);
+    }
+    let registers: HashMap<Srgb8, usize> = colors.iter().enumerate().map(|(i, &color)| (color, i)).collect();
+
+    let mut text = String::new();
+    write!(text, "\x1bP0;1;0q\"1;1;{};{}", width, height).unwrap();
+    for (i, color) in colors.iter().enumerate() {
+        let percent = |value: u8| (value as u32 * 100 + 127) / 255;
+        write!(text, "#{};2;{};{};{}", i, percent(color.0[0]), percent(color.0[1]), percent(color.0[2])).unwrap();
+    }
+
+    for band in (0..height).step_by(6) {
+        let rows = band..(band + 6).min(height);
+        let mut band_colors: Vec<usize> = rows.clone().flat_map(|y| (0..width).map(move |x| (x, y))).map(|(x, y)| registers[img.get_pixel(x, y)]).collect();
+        band_colors.sort_unstable();
+        band_colors.dedup();
+
+        for (n, &register) in band_colors.iter().enumerate() {
+            if n > 0 {
+                text.push('$');
+            }
+            write!(text, "#{}", register).unwrap();
+            let columns: Vec<u8> = (0..width).map(|x| {
+                rows.clone().filter(|&y| registers[img.get_pixel(x, y)] == register).fold(0, |bits, y| bits | 1 << (y - band))
+            }).collect();
+            // Runs of the same column are written as !COUNT followed by the column once
+            for run in columns.chunk_by(|a, b| a == b) {
+                let symbol = (63 + run[0]) as char;
+                if run.len() > 3 {
+                    write!(text, "!{}{}", run.len(), symbol).unwrap();
+                } else {
+                    text.extend(std::iter::repeat_n(symbol, run.len()));
+                }
+            }
+        }
+        text.push('-');
+    }
+    text.push_str("\x1b\\\n");
+    text
+}
+
+pub fn show(kind: &str, img: &image::RgbImage) {
+    match kind {
+        "terminal" => print!("{}", half_blocks(img)),
+        "sixel" => print!("{}", sixel(img)),
+        _ => panic!("Unsupported preview {:?}! The options are {}.", kind, PREVIEW_KINDS.join(", ")),
+    }
+}
@@ -0,0 +1,189 @@
+use crate::Settings;
+use crate::PaletteChoice;
+use crate::color::{Srgb8, LinearRgb, Lab};
+use crate::dither::{self, Algorithm, PixelAlgorithm};
+use crate::files;
+use crate::palettes;
+use crate::resize;
+
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+
+use std::ffi::OsStr;
+use std::fmt::Write as _;
+
+// Text mode: dithering an image into character cells, each showing a shade glyph in one color over another. The shade
+// glyphs cover a fixed quarter, half or three quarters of their cell, so a cell can mix its two colors in those ratios,
+// and every cell together can mix more, the same way ordered dithering mixes pixels.
+//
+// Each cell takes four samples of its average color with the ordered algorithm, with biases spread evenly a quarter
+// apart. For the simplex algorithm, the fraction of samples that come out as each color then matches that color's
+// barycentric weight to within a quarter, and which way it rounds varies from cell to cell with the bias pattern.
+
+// Character cells are about twice as tall as they are wide
+const CELL_ASPECT: f64 = 2.0;
+// The size of each cell in rendered images
+const CELL_WIDTH: u32 = 8;
+const CELL_HEIGHT: u32 = 16;
+
+// Indexed by how many of the cell's four quarters are in the foreground color
+const SHADES: [char; 5] = [' ', '\u{2591}', '\u{2592}', '\u{2593}', '\u{2588}'];
+
+// The ANSI color for each color of the rgbi palette, which has its full intensity colors first and lists white and black
+// among them, where terminals have the dim colors first, light gray as color 7 and dark gray as color 8
+const RGBI_ANSI: [usize; 16] = [0, 9, 10, 11, 12, 13, 14, 15, 8, 1, 2, 3, 4, 5, 6, 7];
+
+// How rendered shade glyphs are filled in: a pixel is in the foreground if its entry is less than the shade
+const SHADE_PATTERN: [[usize; 2]; 2] = [[0, 2], [3, 1]];
+
+#[derive(Copy, Clone)]
+struct Cell {
+    // Both colors are positions in the palette
+    foreground: usize,
+    background: usize,
+    shade: usize,
+}
+
+fn choose_cell(target: LinearRgb, bias: f64, pixel_algorithm: PixelAlgorithm, palettes: (&[Srgb8], &[LinearRgb], &[Lab]), distance2: fn(Lab, Lab) -> f64) -> (usize, usize, usize) {
+    let (palette, linear_palette, lab_palette) = palettes;
+    // Pairs of a palette position and how many samples came out as it, in the order they were first seen
+    let mut counts: Vec<(usize, usize)> = Vec::new();
+    for quarter in 0..4 {
+        let color = pixel_algorithm(target, palette, linear_palette, lab_palette, (bias + quarter as f64 / 4.0).fract(), distance2);
+        let position = palette.iter().position(|&pal_color| pal_color == color).unwrap();
+        match counts.iter_mut().find(|&&mut (seen, _)| seen == position) {
+            Some(count) => count.1 += 1,
+            None => counts.push((position, 1)),
+        }
+    }
+    counts.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+
+    // Only two colors fit in a cell, so the samples of any others go to whichever of the two is closer
+    if counts.len() > 2 {
+        for i in 2..counts.len() {
+            let (position, count) = counts[i];
+            let nearer = if distance2(lab_palette[position], lab_palette[counts[0].0]) <= distance2(lab_palette[position], lab_palette[counts[1].0]) { 0 } else { 1 };
+            counts[nearer].1 += count;
+        }
+        counts.truncate(2);
+    }
+    match counts[..] {
+        [(only, _)] => (only, only, 0),
+        [(background, _), (foreground, shade)] => (foreground, background, shade),
+        _ => unreachable!(),
+    }
+}
+
+// The SGR parameter selecting a palette index as the foreground or background color
+fn indexed_sgr(index: usize, background: bool) -> String {
+    let base = if background { 40 } else { 30 };
+    match index {
+        0..=7 => (base + index).to_string(),
+        8..=15 => (base + 60 + index - 8).to_string(),
+        _ => format!("{};5;{}", base + 8, index),
+    }
+}
+
+fn truecolor_sgr(color: Srgb8, background: bool) -> String {
+    format!("{};2;{};{};{}", if background { 48 } else { 38 }, color.0[0], color.0[1], color.0[2])
+}
+
+fn to_ansi(cells: &[Cell], columns: usize, sgr: impl Fn(usize, bool) -> String) -> String {
+    let mut text = String::new();
+    for row in cells.chunks(columns) {
+        let (mut foreground, mut background) = (None, None);
+        for cell in row {
+            // Blank and full cells only show one of their colors, so the other is left as it was
+            if cell.shade > 0 && foreground != Some(cell.foreground) {
+                write!(text, "\x1b[{}m", sgr(cell.foreground, false)).unwrap();
+                foreground = Some(cell.foreground);
+            }
+            if cell.shade < 4 && background != Some(cell.background) {
+                write!(text, "\x1b[{}m", sgr(cell.background, true)).unwrap();
+                background = Some(cell.background);
+            }
+            text.push(SHADES[cell.shade]);
+        }
+        text.push_str("\x1b[0m\n");
+    }
+    text
+}
+
+fn render(cells: &[Cell], columns: u32, rows: u32, palette: &[Srgb8]) -> image::RgbImage {
+    image::RgbImage::from_fn(columns * CELL_WIDTH, rows * CELL_HEIGHT, |x, y| {
+        let cell = cells[((y / CELL_HEIGHT) * columns + x / CELL_WIDTH) as usize];
+        if SHADE_PATTERN[y as usize % 2][x as usize % 2] < cell.shade {
+            palette[cell.foreground]
+        } else {
+            palette[cell.background]
+        }
+    })
+}
+
+// The terminal's color index for each palette position, if the palette is one of the terminal's own. Other palettes'
+// indices mean nothing to a terminal, so they're written as 24-bit colors.
+fn terminal_indices(palette_name: &str, palette_output: &palettes::PaletteOutput) -> Option<Vec<usize>> {
+    match palettes::lookup(palette_name)?.names[0] {
+        "xterm256" => Some(palette_output.indices.clone()),
+        "rgbi" => Some(palette_output.indices.iter().map(|&index| RGBI_ANSI[index]).collect()),
+        _ => None,
+    }
+}
+
+fn is_text_file(name: &OsStr) -> bool {
+    files::is_stdio(name) || std::path::Path::new(name).extension().is_some_and(|ext| ext.eq_ignore_ascii_case("ans") || ext.eq_ignore_ascii_case("txt"))
+}
+
+pub fn run(arg_matches: &clap::ArgMatches) {
+    let settings = Settings::from_matches(arg_matches);
+    let pixel_algorithm = match *settings.algorithm {
+        Algorithm::Ordered(pixel_algorithm) => pixel_algorithm,
+        // Only picked by default, for grid palettes
+        Algorithm::PerChannel if arg_matches.occurrences_of("ALGORITHM") == 0 => dither::tight_simplex,
+        _ => panic!("Text mode needs an ordered algorithm, since every cell is dithered on its own!"),
+    };
+    if settings.blocks.is_some() {
+        panic!("Text mode can't use --block, since each cell already has only two colors!");
+    }
+    let columns: u32 = arg_matches.value_of_t_or_exit("COLUMNS");
+    if columns == 0 {
+        panic!("There must be at least one column!");
+    }
+
+    let source = files::InputFile::read(arg_matches.value_of_os("IMAGE").unwrap()).image();
+    let rows = ((source.height as f64 * columns as f64 / source.width as f64 / CELL_ASPECT).round() as u32).max(1);
+    let targets = resize::resize(&source, columns, rows, image::imageops::FilterType::Triangle);
+
+    let palette = settings.build_palette(&targets.pixels);
+    let palette_output = settings.palette_output(&palette);
+    let linear_palette: Vec<_> = palette.iter().cloned().map(LinearRgb::from).collect();
+    let lab_palette: Vec<_> = linear_palette.iter().cloned().map(Lab::from).collect();
+    let terminal_indices = match settings.palette {
+        PaletteChoice::Entries(_) => terminal_indices(arg_matches.value_of("PALETTE").unwrap(), &palette_output),
+        _ => None,
+    };
+    let bright = |position: usize| terminal_indices.as_ref().is_some_and(|indices| (8..16).contains(&indices[position]));
+
+    let cells: Vec<Cell> = (0..rows * columns).into_par_iter().map(|i| {
+        let (x, y) = (i % columns, i / columns);
+        let palettes = (&palette[..], &linear_palette[..], &lab_palette[..]);
+        let (foreground, background, shade) = choose_cell(targets.get(x, y), (settings.bias_func)(x, y), pixel_algorithm, palettes, settings.distance2_func);
+        // Not every terminal can show the bright colors as backgrounds, so those go in the foreground where possible
+        if bright(background) && (!bright(foreground) || foreground == background) {
+            Cell { foreground: background, background: foreground, shade: 4 - shade }
+        } else {
+            Cell { foreground, background, shade }
+        }
+    }).collect();
+
+    let out_file_name = arg_matches.value_of_os("OUTPUT").unwrap();
+    if is_text_file(out_file_name) {
+        let text = if let Some(ref indices) = terminal_indices {
+            to_ansi(&cells, columns as usize, |position, background| indexed_sgr(indices[position], background))
+        } else {
+            to_ansi(&cells, columns as usize, |position, background| truecolor_sgr(palette_output.colors[position], background))
+        };
+        files::write(out_file_name, text.as_bytes());
+    } else {
+        files::save_image(&render(&cells, columns, rows, &palette), out_file_name, None);
+    }
+}